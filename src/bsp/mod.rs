@@ -1,6 +1,32 @@
-
 #[cfg(feature = "raspberry_pi_5")]
 pub mod raspberry_pi_5;
 
 #[cfg(feature = "raspberry_pi_5")]
 pub use raspberry_pi_5::init as init;
+
+#[cfg(feature = "raspberry_pi_5")]
+pub use raspberry_pi_5::handle_irq as handle_irq;
+
+#[cfg(feature = "raspberry_pi_5")]
+pub use raspberry_pi_5::enable_timer_interrupt as enable_timer_interrupt;
+
+#[cfg(feature = "raspberry_pi_5")]
+pub use raspberry_pi_5::CORE_COUNT as CORE_COUNT;
+
+#[cfg(feature = "raspberry_pi_5")]
+pub use raspberry_pi_5::init_secondary as init_secondary;
+
+#[cfg(feature = "raspberry_pi_5")]
+pub use raspberry_pi_5::start_core as start_core;
+
+#[cfg(feature = "raspberry_pi_5")]
+pub use raspberry_pi_5::enable_software_interrupt as enable_software_interrupt;
+
+#[cfg(feature = "raspberry_pi_5")]
+pub use raspberry_pi_5::send_software_interrupt as send_software_interrupt;
+
+#[cfg(feature = "raspberry_pi_5")]
+pub use raspberry_pi_5::FIRMWARE_RESERVED as FIRMWARE_RESERVED;
+
+#[cfg(feature = "raspberry_pi_5")]
+pub use raspberry_pi_5::firmware_memory as firmware_memory;
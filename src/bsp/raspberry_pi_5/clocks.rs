@@ -0,0 +1,341 @@
+use super::rp1;
+
+/// The RP1 clock manager. Every peripheral clock is generated here from the crystal or one of three PLLs.
+const CLOCKS_BASE: usize = rp1::RP1_PERIPHERAL_BASE + 0x018000;
+
+/// The PLLs live in the same block, after the clock generators.
+const PLL_SYS_BASE: usize = CLOCKS_BASE + 0x08000;
+const PLL_AUDIO_BASE: usize = CLOCKS_BASE + 0x0C000;
+const PLL_VIDEO_BASE: usize = CLOCKS_BASE + 0x10000;
+
+/// RP1's reference crystal
+const XOSC_FREQUENCY: usize = 50_000_000;
+
+//Clock generator registers, relative to the generator's CTRL register
+const CLOCK_CTRL: usize = 0x0;
+const CLOCK_DIV_INT: usize = 0x4;
+const CLOCK_DIV_FRAC: usize = 0x8;
+
+//CTRL bits
+const CLOCK_CTRL_SRC_MASK: u32 = 0b11;
+const CLOCK_CTRL_SRC_AUX: u32 = 1;
+const CLOCK_CTRL_AUXSRC_SHIFT: u32 = 5;
+const CLOCK_CTRL_AUXSRC_MASK: u32 = 0x1F << CLOCK_CTRL_AUXSRC_SHIFT;
+const CLOCK_CTRL_ENABLE: u32 = 1 << 11;
+
+//PLL registers
+const PLL_CS: usize = 0x00;
+const PLL_FBDIV_INT: usize = 0x08;
+const PLL_FBDIV_FRAC: usize = 0x0C;
+const PLL_PRIM: usize = 0x10;
+const PLL_SEC: usize = 0x14;
+
+const PLL_CS_LOCK: u32 = 1 << 31;
+const PLL_CS_REFDIV_MASK: u32 = 0x3F;
+
+/// Where a clock generator can take its input from.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ClockSource{
+    /// The 50MHz crystal
+    Xosc,
+    /// The system PLL's primary output
+    PllSys,
+    /// The system PLL's primary output after its phase shifter, which halves it
+    PllSysPrimaryPhase,
+    /// The system PLL's secondary output
+    PllSysSecondary,
+    PllAudio,
+    PllAudioSecondary,
+    PllVideo,
+    PllVideoSecondary,
+    /// The output of the clk_sys generator
+    ClkSys
+}
+
+/// The clock generators. SPI and I2C have no generator of their own, they run from Sys.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Clock{
+    Sys,
+    SlowSys,
+    Dma,
+    Uart,
+    Ethernet,
+    Pwm0,
+    Pwm1,
+    AudioIn,
+    AudioOut,
+    I2s,
+    Adc,
+    GeneralPurpose0
+}
+
+/// Which peripheral blocks a clock feeds, for drivers that don't know the clock tree.
+#[derive(Clone, Copy)]
+pub enum Peripheral{
+    Uart,
+    Spi,
+    I2c,
+    Pwm0,
+    Pwm1,
+    Dma
+}
+
+/// Where a clock generator's registers are, and what its auxiliary mux can select.
+struct ClockDefinition{
+    /// Offset of the CTRL register from CLOCKS_BASE
+    offset: usize,
+    /// Whether the divider has a fractional part
+    fractional_divider: bool,
+    /// Whether the clock has a glitchless mux in front of the auxiliary one. Those can't be gated.
+    glitchless: bool,
+    /// The sources of the auxiliary mux, by AUXSRC value
+    auxiliary_sources: &'static [ClockSource]
+}
+
+const SYS_SOURCES: &[ClockSource] = &[ClockSource::PllSys, ClockSource::PllVideo, ClockSource::PllAudio];
+const PERIPHERAL_SOURCES: &[ClockSource] = &[ClockSource::PllSysPrimaryPhase, ClockSource::PllVideo, ClockSource::Xosc, ClockSource::ClkSys];
+const AUDIO_SOURCES: &[ClockSource] = &[ClockSource::PllAudio, ClockSource::PllAudioSecondary, ClockSource::PllVideoSecondary, ClockSource::Xosc];
+const GENERAL_PURPOSE_SOURCES: &[ClockSource] = &[ClockSource::Xosc, ClockSource::PllSys, ClockSource::PllSysSecondary, ClockSource::PllAudio, ClockSource::PllVideo, ClockSource::ClkSys];
+
+impl Clock{
+    fn definition(&self) -> ClockDefinition{
+        match self{
+            Clock::Sys => ClockDefinition{ offset: 0x014, fractional_divider: false, glitchless: true, auxiliary_sources: SYS_SOURCES },
+            Clock::SlowSys => ClockDefinition{ offset: 0x024, fractional_divider: false, glitchless: true, auxiliary_sources: SYS_SOURCES },
+            Clock::Dma => ClockDefinition{ offset: 0x044, fractional_divider: false, glitchless: false, auxiliary_sources: PERIPHERAL_SOURCES },
+            Clock::Uart => ClockDefinition{ offset: 0x054, fractional_divider: false, glitchless: false, auxiliary_sources: PERIPHERAL_SOURCES },
+            Clock::Ethernet => ClockDefinition{ offset: 0x064, fractional_divider: false, glitchless: false, auxiliary_sources: PERIPHERAL_SOURCES },
+            Clock::Pwm0 => ClockDefinition{ offset: 0x074, fractional_divider: true, glitchless: false, auxiliary_sources: PERIPHERAL_SOURCES },
+            Clock::Pwm1 => ClockDefinition{ offset: 0x084, fractional_divider: true, glitchless: false, auxiliary_sources: PERIPHERAL_SOURCES },
+            Clock::AudioIn => ClockDefinition{ offset: 0x094, fractional_divider: false, glitchless: false, auxiliary_sources: AUDIO_SOURCES },
+            Clock::AudioOut => ClockDefinition{ offset: 0x0A4, fractional_divider: false, glitchless: false, auxiliary_sources: AUDIO_SOURCES },
+            Clock::I2s => ClockDefinition{ offset: 0x0B4, fractional_divider: false, glitchless: false, auxiliary_sources: AUDIO_SOURCES },
+            Clock::Adc => ClockDefinition{ offset: 0x144, fractional_divider: false, glitchless: false, auxiliary_sources: PERIPHERAL_SOURCES },
+            Clock::GeneralPurpose0 => ClockDefinition{ offset: 0x174, fractional_divider: true, glitchless: false, auxiliary_sources: GENERAL_PURPOSE_SOURCES },
+        }
+    }
+}
+
+impl Peripheral{
+    /// The clock generator that feeds this peripheral.
+    pub fn clock(&self) -> Clock{
+        match self{
+            Peripheral::Uart => Clock::Uart,
+            Peripheral::Spi => Clock::Sys,
+            Peripheral::I2c => Clock::Sys,
+            Peripheral::Pwm0 => Clock::Pwm0,
+            Peripheral::Pwm1 => Clock::Pwm1,
+            Peripheral::Dma => Clock::Dma,
+        }
+    }
+}
+
+fn read_register(address: usize) -> u32{
+    unsafe{
+        core::ptr::read_volatile(address as *const u32)
+    }
+}
+
+fn write_register(address: usize, value: u32){
+    unsafe{
+        core::ptr::write_volatile(address as *mut u32, value)
+    }
+}
+
+/// The frequency of a PLL's VCO, read back from its dividers.
+fn pll_vco_frequency(pll_base: usize) -> Result<usize, &'static str>{
+    let cs = read_register(pll_base + PLL_CS);
+
+    if cs & PLL_CS_LOCK == 0{
+        return Err("The PLL is not locked.");
+    }
+
+    let reference_divider = (cs & PLL_CS_REFDIV_MASK) as usize;
+    if reference_divider == 0{
+        return Err("The PLL reference divider is zero.");
+    }
+
+    //The feedback divider has a 24 bit fraction.
+    let feedback_integer = read_register(pll_base + PLL_FBDIV_INT) as usize & 0xFFF;
+    let feedback_fraction = read_register(pll_base + PLL_FBDIV_FRAC) as usize & 0xFF_FFFF;
+    let feedback = (feedback_integer << 24) | feedback_fraction;
+
+    Ok(((XOSC_FREQUENCY / reference_divider) * feedback) >> 24)
+}
+
+/// The frequency of a PLL's primary output, after its two post dividers.
+fn pll_primary_frequency(pll_base: usize) -> Result<usize, &'static str>{
+    let vco = pll_vco_frequency(pll_base)?;
+    let prim = read_register(pll_base + PLL_PRIM);
+    let post_divider_1 = ((prim >> 16) & 0x7) as usize;
+    let post_divider_2 = ((prim >> 12) & 0x7) as usize;
+
+    if post_divider_1 == 0 || post_divider_2 == 0{
+        return Err("The PLL post dividers are not configured.");
+    }
+
+    Ok(vco / (post_divider_1 * post_divider_2))
+}
+
+/// The frequency of a PLL's secondary output.
+fn pll_secondary_frequency(pll_base: usize) -> Result<usize, &'static str>{
+    let vco = pll_vco_frequency(pll_base)?;
+    let divider = ((read_register(pll_base + PLL_SEC) >> 8) & 0x1F) as usize;
+
+    if divider == 0{
+        return Err("The PLL secondary divider is not configured.");
+    }
+
+    Ok(vco / divider)
+}
+
+/// The frequency of a clock source, in Hz.
+pub fn source_frequency(source: ClockSource) -> Result<usize, &'static str>{
+    match source{
+        ClockSource::Xosc => Ok(XOSC_FREQUENCY),
+        ClockSource::PllSys => pll_primary_frequency(PLL_SYS_BASE),
+        ClockSource::PllSysPrimaryPhase => Ok(pll_primary_frequency(PLL_SYS_BASE)? / 2),
+        ClockSource::PllSysSecondary => pll_secondary_frequency(PLL_SYS_BASE),
+        ClockSource::PllAudio => pll_primary_frequency(PLL_AUDIO_BASE),
+        ClockSource::PllAudioSecondary => pll_secondary_frequency(PLL_AUDIO_BASE),
+        ClockSource::PllVideo => pll_primary_frequency(PLL_VIDEO_BASE),
+        ClockSource::PllVideoSecondary => pll_secondary_frequency(PLL_VIDEO_BASE),
+        ClockSource::ClkSys => frequency(Clock::Sys),
+    }
+}
+
+/// The source a clock generator is currently running from.
+pub fn source(clock: Clock) -> Result<ClockSource, &'static str>{
+    let definition = clock.definition();
+    let ctrl = read_register(CLOCKS_BASE + definition.offset + CLOCK_CTRL);
+
+    //Glitchless clocks can bypass the auxiliary mux and run straight from the crystal.
+    if definition.glitchless && ctrl & CLOCK_CTRL_SRC_MASK != CLOCK_CTRL_SRC_AUX{
+        return Ok(ClockSource::Xosc);
+    }
+
+    let auxiliary_source = ((ctrl & CLOCK_CTRL_AUXSRC_MASK) >> CLOCK_CTRL_AUXSRC_SHIFT) as usize;
+
+    match definition.auxiliary_sources.get(auxiliary_source){
+        Some(source) => Ok(*source),
+        None => Err("The clock is running from an unknown source."),
+    }
+}
+
+/// The divider a clock generator applies, as a 16.16 fixed point value.
+fn divider(definition: &ClockDefinition) -> usize{
+    let integer = read_register(CLOCKS_BASE + definition.offset + CLOCK_DIV_INT) as usize & 0xFFFF;
+
+    //An integer divider of zero means the largest divider, 2^16.
+    let integer = if integer == 0 { 1 << 16 } else { integer };
+
+    let fraction = if definition.fractional_divider{
+        (read_register(CLOCKS_BASE + definition.offset + CLOCK_DIV_FRAC) >> 16) as usize
+    } else{
+        0
+    };
+
+    (integer << 16) | fraction
+}
+
+/// The frequency a clock generator is currently producing, in Hz. A gated clock reports zero.
+pub fn frequency(clock: Clock) -> Result<usize, &'static str>{
+    if !is_enabled(clock){
+        return Ok(0);
+    }
+
+    let definition = clock.definition();
+    let source_hz = source_frequency(source(clock)?)?;
+
+    Ok((source_hz << 16) / divider(&definition))
+}
+
+/// The frequency of the clock a peripheral runs from, in Hz.
+pub fn peripheral_frequency(peripheral: Peripheral) -> Result<usize, &'static str>{
+    frequency(peripheral.clock())
+}
+
+pub fn is_enabled(clock: Clock) -> bool{
+    let definition = clock.definition();
+
+    //Glitchless clocks have no enable bit, they always run.
+    definition.glitchless || read_register(CLOCKS_BASE + definition.offset + CLOCK_CTRL) & CLOCK_CTRL_ENABLE > 0
+}
+
+/// Ungate a clock.
+pub fn enable(clock: Clock){
+    let definition = clock.definition();
+
+    if definition.glitchless{
+        return;
+    }
+
+    let address = CLOCKS_BASE + definition.offset + CLOCK_CTRL;
+    write_register(address, read_register(address) | CLOCK_CTRL_ENABLE);
+}
+
+/// Gate a clock. Clocks that everything depends on (Sys and SlowSys) can't be gated.
+pub fn disable(clock: Clock) -> Result<(), &'static str>{
+    let definition = clock.definition();
+
+    if definition.glitchless{
+        return Err("This clock can't be gated.");
+    }
+
+    let address = CLOCKS_BASE + definition.offset + CLOCK_CTRL;
+    write_register(address, read_register(address) & !CLOCK_CTRL_ENABLE);
+
+    Ok(())
+}
+
+/// Run a clock from a source at (as close as possible to, but not above) a target frequency.
+/// The clock is gated while it is switched, and left enabled. Returns the frequency achieved.
+pub fn configure(clock: Clock, source: ClockSource, target_frequency: usize) -> Result<usize, &'static str>{
+    let definition = clock.definition();
+
+    if definition.glitchless{
+        return Err("System clocks are owned by the firmware and can't be reconfigured.");
+    }
+
+    if target_frequency == 0{
+        return Err("A clock can't be configured to run at 0Hz. Disable it instead.");
+    }
+
+    let auxiliary_source = match definition.auxiliary_sources.iter().position(|candidate| *candidate == source){
+        Some(index) => index,
+        None => return Err("The clock can't be driven from that source."),
+    };
+
+    let source_hz = source_frequency(source)?;
+
+    //Work out the 16.16 divider, rounding up so we never run faster than asked.
+    let mut divider = (source_hz << 16).div_ceil(target_frequency);
+
+    if !definition.fractional_divider{
+        divider = (divider + 0xFFFF) & !0xFFFF;
+    }
+
+    if divider < (1 << 16){
+        return Err("The clock source is slower than the requested frequency.");
+    }
+
+    if divider > (0xFFFF << 16) | 0xFFFF{
+        return Err("The requested frequency is too low to reach from this source.");
+    }
+
+    //Switching the auxiliary mux while the clock runs can glitch it, so gate it first.
+    let _ = disable(clock);
+
+    let ctrl_address = CLOCKS_BASE + definition.offset + CLOCK_CTRL;
+    let ctrl = read_register(ctrl_address) & !CLOCK_CTRL_AUXSRC_MASK;
+    write_register(ctrl_address, ctrl | ((auxiliary_source as u32) << CLOCK_CTRL_AUXSRC_SHIFT));
+
+    write_register(CLOCKS_BASE + definition.offset + CLOCK_DIV_INT, (divider >> 16) as u32);
+    if definition.fractional_divider{
+        write_register(CLOCKS_BASE + definition.offset + CLOCK_DIV_FRAC, ((divider & 0xFFFF) << 16) as u32);
+    }
+
+    enable(clock);
+
+    Ok((source_hz << 16) / divider)
+}
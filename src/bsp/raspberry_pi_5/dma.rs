@@ -0,0 +1,359 @@
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::kernel::wait_queue::WaitQueue;
+use crate::memory::cache;
+
+use super::rp1;
+
+/// RP1 has a Synopsys DesignWare AXI DMA controller with 8 channels.
+const DMA_BASE: usize = rp1::RP1_PERIPHERAL_BASE + 0x188000;
+pub const DMA_CHANNEL_COUNT: usize = 8;
+
+/// The most items a single block (and so a single descriptor) can move.
+const MAX_BLOCK_SIZE: usize = 0x10000;
+
+/// The number of descriptors each channel has. This bounds the size of a single transfer.
+const DESCRIPTORS_PER_CHANNEL: usize = 16;
+
+/// The largest transfer a channel can be started with.
+pub const MAX_TRANSFER_SIZE: usize = MAX_BLOCK_SIZE * DESCRIPTORS_PER_CHANNEL;
+
+//Common registers
+const DMAC_CFG: usize = 0x010;
+const DMAC_CHEN: usize = 0x018;
+const DMAC_CHABORTREG: usize = 0x028;
+const DMAC_INTSTATUS: usize = 0x030;
+const DMAC_RESET: usize = 0x058;
+
+//Per-channel registers, relative to the channel's register block
+const CH_CFG: usize = 0x020;
+const CH_LLP: usize = 0x028;
+const CH_INTSTATUS_ENA: usize = 0x080;
+const CH_INTSTATUS: usize = 0x088;
+const CH_INTSIGNAL_ENA: usize = 0x090;
+const CH_INTCLEAR: usize = 0x098;
+
+//DMAC_CFG bits
+const DMAC_CFG_DMAC_EN: u32 = 1 << 0;
+const DMAC_CFG_INT_EN: u32 = 1 << 1;
+
+//CH_CTL bits
+const CH_CTL_SRC_FIXED: u32 = 1 << 4;
+const CH_CTL_DST_FIXED: u32 = 1 << 6;
+const CH_CTL_SRC_MSIZE_POS: u32 = 14;
+const CH_CTL_DST_MSIZE_POS: u32 = 18;
+/// Bursts of 4 items
+const CH_CTL_MSIZE_4: u32 = 1;
+const CH_CTL_H_IOC_BLKTFR: u32 = 1 << 26;
+const CH_CTL_H_LLI_LAST: u32 = 1 << 30;
+const CH_CTL_H_LLI_VALID: u32 = 1 << 31;
+
+//CH_CFG bits
+const CH_CFG_L_MULTBLK_LINKED_LIST: u32 = 0b11 | (0b11 << 2);
+const CH_CFG_H_TT_FC_POS: u32 = 0;
+const CH_CFG_H_HS_SEL_SRC: u32 = 1 << 3;
+const CH_CFG_H_HS_SEL_DST: u32 = 1 << 4;
+const CH_CFG_H_SRC_PER_POS: u32 = 7;
+const CH_CFG_H_DST_PER_POS: u32 = 12;
+
+//CH_INTSTATUS bits
+const CH_INT_DMA_TFR_DONE: u32 = 1 << 1;
+/// Every decode, slave and descriptor error the channel can report
+const CH_INT_ERRORS: u32 = 0x003F_FFE0;
+
+//Channel states
+const CHANNEL_IDLE: usize = 0;
+const CHANNEL_BUSY: usize = 1;
+const CHANNEL_DONE: usize = 2;
+const CHANNEL_ERROR: usize = 3;
+
+/// A linked list item, as read by the controller. The layout is fixed by the hardware.
+#[repr(C, align(64))]
+#[derive(Clone, Copy)]
+struct DmaDescriptor{
+    source: u64,
+    destination: u64,
+    /// The number of items in the block, minus one
+    block_transfer_size: u32,
+    reserved_0: u32,
+    /// The DMA address of the next descriptor
+    next: u64,
+    control_low: u32,
+    control_high: u32,
+    source_status: u32,
+    destination_status: u32,
+    status_low: u32,
+    status_high: u32,
+    reserved_1: u32,
+    reserved_2: u32
+}
+
+impl DmaDescriptor{
+    const fn empty() -> DmaDescriptor{
+        DmaDescriptor{
+            source: 0,
+            destination: 0,
+            block_transfer_size: 0,
+            reserved_0: 0,
+            next: 0,
+            control_low: 0,
+            control_high: 0,
+            source_status: 0,
+            destination_status: 0,
+            status_low: 0,
+            status_high: 0,
+            reserved_1: 0,
+            reserved_2: 0
+        }
+    }
+}
+
+/// Each channel's descriptor list. A channel's list is only touched by its owner, and only while the channel is stopped.
+struct DescriptorTable{
+    descriptors: UnsafeCell<[[DmaDescriptor; DESCRIPTORS_PER_CHANNEL]; DMA_CHANNEL_COUNT]>
+}
+
+unsafe impl Sync for DescriptorTable{}
+
+static DESCRIPTORS: DescriptorTable = DescriptorTable{
+    descriptors: UnsafeCell::new([[DmaDescriptor::empty(); DESCRIPTORS_PER_CHANNEL]; DMA_CHANNEL_COUNT])
+};
+
+/// A bit per channel, set while the channel is owned.
+static CHANNELS_IN_USE: AtomicUsize = AtomicUsize::new(0);
+static CHANNEL_STATES: [AtomicUsize; DMA_CHANNEL_COUNT] = [const { AtomicUsize::new(CHANNEL_IDLE) }; DMA_CHANNEL_COUNT];
+static CHANNEL_WAIT_QUEUES: [WaitQueue; DMA_CHANNEL_COUNT] = [const { WaitQueue::new() }; DMA_CHANNEL_COUNT];
+
+/// Where a transfer reads from and writes to.
+#[derive(Clone, Copy)]
+pub enum DmaDirection{
+    /// Memory to memory. Both addresses increment.
+    MemoryToMemory,
+    /// Memory to a peripheral register, paced by the peripheral's DREQ line.
+    MemoryToPeripheral(usize),
+    /// A peripheral register to memory, paced by the peripheral's DREQ line.
+    PeripheralToMemory(usize)
+}
+
+/// A single transfer of bytes. Addresses are the CPU's physical addresses; they are translated for RP1 here.
+pub struct DmaTransfer{
+    pub source: usize,
+    pub destination: usize,
+    pub length: usize,
+    pub direction: DmaDirection
+}
+
+/// An owned DMA channel. The channel is released when this is dropped, aborting any transfer in flight.
+pub struct DmaChannel{
+    channel: usize
+}
+
+fn read_register(address: usize) -> u32{
+    unsafe{
+        core::ptr::read_volatile(address as *const u32)
+    }
+}
+
+fn write_register(address: usize, value: u32){
+    unsafe{
+        core::ptr::write_volatile(address as *mut u32, value)
+    }
+}
+
+/// 64-bit registers are written as two 32-bit halves, low half first.
+fn write_register_64(address: usize, value: u64){
+    write_register(address, value as u32);
+    write_register(address + 4, (value >> 32) as u32);
+}
+
+fn channel_register(channel: usize, offset: usize) -> usize{
+    DMA_BASE + 0x100 + channel * 0x100 + offset
+}
+
+/// Reset and enable the controller, and hook up its interrupt.
+pub fn init() -> Result<(), &'static str>{
+    write_register(DMA_BASE + DMAC_RESET, 1);
+    while read_register(DMA_BASE + DMAC_RESET) & 1 > 0{}
+
+    write_register(DMA_BASE + DMAC_CFG, DMAC_CFG_DMAC_EN | DMAC_CFG_INT_EN);
+
+    rp1::enable_interrupt(rp1::RP1_INT_DMA, handle_interrupt, 0)
+}
+
+/// Claim a free channel.
+pub fn request_channel() -> Result<DmaChannel, &'static str>{
+    let mut in_use = CHANNELS_IN_USE.load(Ordering::Relaxed);
+
+    loop{
+        let channel = (!in_use).trailing_zeros() as usize;
+
+        if channel >= DMA_CHANNEL_COUNT{
+            return Err("No free DMA channels.");
+        }
+
+        match CHANNELS_IN_USE.compare_exchange_weak(in_use, in_use | (1 << channel), Ordering::Acquire, Ordering::Relaxed){
+            Ok(_) => {
+                CHANNEL_STATES[channel].store(CHANNEL_IDLE, Ordering::Release);
+                return Ok(DmaChannel{ channel });
+            },
+            Err(current) => in_use = current,
+        }
+    }
+}
+
+/// The controller interrupt. One line is shared by every channel.
+fn handle_interrupt(_context: usize){
+    let pending = read_register(DMA_BASE + DMAC_INTSTATUS);
+
+    for channel in 0..DMA_CHANNEL_COUNT{
+        if pending & (1 << channel) == 0{
+            continue;
+        }
+
+        let status = read_register(channel_register(channel, CH_INTSTATUS));
+        write_register(channel_register(channel, CH_INTCLEAR), status);
+
+        if status & CH_INT_ERRORS > 0{
+            CHANNEL_STATES[channel].store(CHANNEL_ERROR, Ordering::Release);
+        } else if status & CH_INT_DMA_TFR_DONE > 0{
+            CHANNEL_STATES[channel].store(CHANNEL_DONE, Ordering::Release);
+        }
+
+        CHANNEL_WAIT_QUEUES[channel].notify_all();
+    }
+
+    rp1::acknowledge_interrupt(rp1::RP1_INT_DMA);
+}
+
+impl DmaChannel{
+    pub fn index(&self) -> usize{
+        self.channel
+    }
+
+    fn is_enabled(&self) -> bool{
+        read_register(DMA_BASE + DMAC_CHEN) & (1 << self.channel) > 0
+    }
+
+    /// Build the descriptor chain for a transfer, and start the channel. Returns as soon as the channel is running.
+    /// The memory side of the transfer must stay valid, and must have had any needed cache maintenance done, until it completes.
+    pub fn start(&mut self, transfer: &DmaTransfer) -> Result<(), &'static str>{
+        if transfer.length == 0{
+            return Err("A DMA transfer can't be empty.");
+        }
+
+        if transfer.length > MAX_TRANSFER_SIZE{
+            return Err("The DMA transfer is larger than a channel's descriptors can describe.");
+        }
+
+        if self.is_enabled(){
+            return Err("The DMA channel is already running a transfer.");
+        }
+
+        //Work out the addresses RP1 uses, and which side is fixed.
+        let (source, destination, control, config) = match transfer.direction{
+            DmaDirection::MemoryToMemory => (
+                rp1::host_dma_address(transfer.source),
+                rp1::host_dma_address(transfer.destination),
+                0,
+                CH_CFG_H_HS_SEL_SRC | CH_CFG_H_HS_SEL_DST
+            ),
+            DmaDirection::MemoryToPeripheral(dreq) => (
+                rp1::host_dma_address(transfer.source),
+                rp1::peripheral_dma_address(transfer.destination),
+                CH_CTL_DST_FIXED,
+                (1 << CH_CFG_H_TT_FC_POS) | CH_CFG_H_HS_SEL_SRC | ((dreq as u32) << CH_CFG_H_DST_PER_POS)
+            ),
+            DmaDirection::PeripheralToMemory(dreq) => (
+                rp1::peripheral_dma_address(transfer.source),
+                rp1::host_dma_address(transfer.destination),
+                CH_CTL_SRC_FIXED,
+                (2 << CH_CFG_H_TT_FC_POS) | CH_CFG_H_HS_SEL_DST | ((dreq as u32) << CH_CFG_H_SRC_PER_POS)
+            ),
+        };
+
+        let control = control | (CH_CTL_MSIZE_4 << CH_CTL_SRC_MSIZE_POS) | (CH_CTL_MSIZE_4 << CH_CTL_DST_MSIZE_POS);
+
+        //Split the transfer into blocks, one per descriptor. Item widths are left at 8 bits.
+        let descriptors = unsafe{ &mut (*DESCRIPTORS.descriptors.get())[self.channel] };
+        let block_count = transfer.length.div_ceil(MAX_BLOCK_SIZE);
+
+        for (block, descriptor) in descriptors.iter_mut().take(block_count).enumerate(){
+            let offset = block * MAX_BLOCK_SIZE;
+            let length = core::cmp::min(MAX_BLOCK_SIZE, transfer.length - offset);
+            let last = block + 1 == block_count;
+
+            *descriptor = DmaDescriptor::empty();
+            descriptor.source = (if control & CH_CTL_SRC_FIXED > 0 { source } else { source + offset }) as u64;
+            descriptor.destination = (if control & CH_CTL_DST_FIXED > 0 { destination } else { destination + offset }) as u64;
+            descriptor.block_transfer_size = (length - 1) as u32;
+            descriptor.control_low = control;
+            descriptor.control_high = CH_CTL_H_LLI_VALID;
+
+            if last{
+                descriptor.control_high |= CH_CTL_H_LLI_LAST | CH_CTL_H_IOC_BLKTFR;
+            }
+        }
+
+        //Chain the descriptors together. This needs their addresses, so it is a second pass.
+        for block in 0..block_count - 1{
+            let next = &descriptors[block + 1] as *const DmaDescriptor as usize;
+            descriptors[block].next = rp1::host_dma_address(next) as u64;
+        }
+
+        //The controller reads the descriptors from memory, not from our cache.
+        let first = &descriptors[0] as *const DmaDescriptor as usize;
+        cache::clean_range(first, block_count * core::mem::size_of::<DmaDescriptor>());
+
+        CHANNEL_STATES[self.channel].store(CHANNEL_BUSY, Ordering::Release);
+
+        write_register(channel_register(self.channel, CH_CFG), CH_CFG_L_MULTBLK_LINKED_LIST);
+        write_register(channel_register(self.channel, CH_CFG) + 4, config);
+        write_register_64(channel_register(self.channel, CH_LLP), rp1::host_dma_address(first) as u64);
+
+        write_register(channel_register(self.channel, CH_INTCLEAR), 0xFFFF_FFFF);
+        write_register(channel_register(self.channel, CH_INTSTATUS_ENA), CH_INT_DMA_TFR_DONE | CH_INT_ERRORS);
+        write_register(channel_register(self.channel, CH_INTSIGNAL_ENA), CH_INT_DMA_TFR_DONE | CH_INT_ERRORS);
+
+        //Set the channel's enable bit along with its write enable bit.
+        write_register(DMA_BASE + DMAC_CHEN, (1 << self.channel) | (1 << (self.channel + 8)));
+
+        Ok(())
+    }
+
+    /// True once the last transfer has finished, successfully or not.
+    pub fn is_complete(&self) -> bool{
+        let state = CHANNEL_STATES[self.channel].load(Ordering::Acquire);
+        state == CHANNEL_DONE || state == CHANNEL_ERROR || state == CHANNEL_IDLE
+    }
+
+    /// Block until the last transfer finishes.
+    pub fn wait(&self) -> Result<(), &'static str>{
+        CHANNEL_WAIT_QUEUES[self.channel].wait_until(|| self.is_complete());
+
+        match CHANNEL_STATES[self.channel].load(Ordering::Acquire){
+            CHANNEL_ERROR => Err("The DMA transfer failed."),
+            _ => Ok(()),
+        }
+    }
+
+    /// Stop the channel, abandoning whatever is left of the transfer.
+    pub fn abort(&mut self){
+        if !self.is_enabled(){
+            return;
+        }
+
+        write_register(DMA_BASE + DMAC_CHABORTREG, (1 << self.channel) | (1 << (self.channel + 8)));
+        while self.is_enabled(){}
+
+        write_register(channel_register(self.channel, CH_INTCLEAR), 0xFFFF_FFFF);
+        CHANNEL_STATES[self.channel].store(CHANNEL_ERROR, Ordering::Release);
+    }
+}
+
+impl Drop for DmaChannel{
+    fn drop(&mut self){
+        self.abort();
+        CHANNELS_IN_USE.fetch_and(!(1 << self.channel), Ordering::Release);
+    }
+}
@@ -0,0 +1,205 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// The BCM2712 uses a GIC-400 (GICv2) as its interrupt controller.
+const GICD_BASE: usize = 0x107FFF9000;
+const GICC_BASE: usize = 0x107FFFA000;
+
+/// The number of interrupt IDs we support. SGIs (0-15) and PPIs (16-31) are included.
+pub const MAX_INTERRUPTS: usize = 320;
+
+/// Interrupt IDs 0-15 are software generated, sent from one core to others.
+pub const SGI_COUNT: usize = 16;
+
+/// Interrupt IDs 1020-1023 are special. 1023 means there is no pending interrupt.
+const SPURIOUS_INTERRUPT: usize = 1023;
+
+//Distributor registers
+const GICD_CTLR: usize = 0x000;
+const GICD_ISENABLER: usize = 0x100;
+const GICD_ICENABLER: usize = 0x180;
+const GICD_ICPENDR: usize = 0x280;
+const GICD_IPRIORITYR: usize = 0x400;
+const GICD_ITARGETSR: usize = 0x800;
+const GICD_ICFGR: usize = 0xC00;
+const GICD_SGIR: usize = 0xF00;
+
+//CPU interface registers
+const GICC_CTLR: usize = 0x000;
+const GICC_PMR: usize = 0x004;
+const GICC_BPR: usize = 0x008;
+const GICC_IAR: usize = 0x00C;
+const GICC_EOIR: usize = 0x010;
+
+/// The default priority given to every interrupt. Lower values are more urgent.
+const DEFAULT_PRIORITY: u8 = 0xA0;
+
+/// A handler for an interrupt. It is passed the context value it was registered with.
+pub type InterruptHandler = fn(usize);
+
+fn unhandled_interrupt(_context: usize){}
+
+/// Registered handlers and their contexts, indexed by interrupt ID.
+static HANDLERS: [AtomicUsize; MAX_INTERRUPTS] = [const { AtomicUsize::new(0) }; MAX_INTERRUPTS];
+static CONTEXTS: [AtomicUsize; MAX_INTERRUPTS] = [const { AtomicUsize::new(0) }; MAX_INTERRUPTS];
+
+fn read_register(address: usize) -> u32{
+    unsafe{
+        core::ptr::read_volatile(address as *const u32)
+    }
+}
+
+fn write_register(address: usize, value: u32){
+    unsafe{
+        core::ptr::write_volatile(address as *mut u32, value)
+    }
+}
+
+fn validate_interrupt(interrupt_id: usize) -> Result<(), &'static str>{
+    if interrupt_id >= MAX_INTERRUPTS{
+        return Err("Invalid interrupt ID passed to the GIC.");
+    }
+
+    Ok(())
+}
+
+/// Initialize the distributor and the CPU interface of the calling core.
+pub fn init(){
+    //Disable the distributor while we program it.
+    write_register(GICD_BASE + GICD_CTLR, 0);
+
+    //Disable and clear every shared peripheral interrupt, then give each one the default
+    //priority and route it to core 0.
+    for interrupt_id in (32..MAX_INTERRUPTS).step_by(32){
+        write_register(GICD_BASE + GICD_ICENABLER + (interrupt_id / 32) * 4, 0xFFFF_FFFF);
+        write_register(GICD_BASE + GICD_ICPENDR + (interrupt_id / 32) * 4, 0xFFFF_FFFF);
+    }
+
+    for interrupt_id in (32..MAX_INTERRUPTS).step_by(4){
+        let priority = DEFAULT_PRIORITY as u32;
+        write_register(GICD_BASE + GICD_IPRIORITYR + interrupt_id, priority | priority << 8 | priority << 16 | priority << 24);
+        write_register(GICD_BASE + GICD_ITARGETSR + interrupt_id, 0x01010101);
+    }
+
+    //Every SPI is level sensitive by default.
+    for interrupt_id in (32..MAX_INTERRUPTS).step_by(16){
+        write_register(GICD_BASE + GICD_ICFGR + (interrupt_id / 16) * 4, 0);
+    }
+
+    //Re-enable the distributor.
+    write_register(GICD_BASE + GICD_CTLR, 1);
+
+    init_cpu_interface();
+}
+
+/// Initialize the CPU interface of the calling core. Banked SGI and PPI state is set up here too.
+pub fn init_cpu_interface(){
+    for interrupt_id in (0..32).step_by(4){
+        let priority = DEFAULT_PRIORITY as u32;
+        write_register(GICD_BASE + GICD_IPRIORITYR + interrupt_id, priority | priority << 8 | priority << 16 | priority << 24);
+    }
+
+    //Accept every priority, and don't split priorities into groups.
+    write_register(GICC_BASE + GICC_PMR, 0xFF);
+    write_register(GICC_BASE + GICC_BPR, 0);
+    write_register(GICC_BASE + GICC_CTLR, 1);
+}
+
+/// Register a handler for an interrupt ID. The interrupt is not enabled until enable_interrupt is called.
+pub fn register_handler(interrupt_id: usize, handler: InterruptHandler, context: usize) -> Result<(), &'static str>{
+    match validate_interrupt(interrupt_id){
+        Ok(_) => {},
+        Err(error) => return Err(error),
+    }
+
+    CONTEXTS[interrupt_id].store(context, Ordering::Relaxed);
+    HANDLERS[interrupt_id].store(handler as usize, Ordering::Release);
+
+    Ok(())
+}
+
+/// Make a shared peripheral interrupt edge triggered, rather than level sensitive.
+pub fn set_edge_triggered(interrupt_id: usize, edge_triggered: bool) -> Result<(), &'static str>{
+    match validate_interrupt(interrupt_id){
+        Ok(_) => {},
+        Err(error) => return Err(error),
+    }
+
+    if interrupt_id < 32{
+        return Err("Only shared peripheral interrupts can have their trigger changed.");
+    }
+
+    //Each interrupt has two bits in ICFGR. The upper one selects edge triggering.
+    let address = GICD_BASE + GICD_ICFGR + (interrupt_id / 16) * 4;
+    let bit = 1u32 << ((interrupt_id % 16) * 2 + 1);
+    let value = read_register(address);
+
+    write_register(address, if edge_triggered { value | bit } else { value & !bit });
+
+    Ok(())
+}
+
+pub fn enable_interrupt(interrupt_id: usize) -> Result<(), &'static str>{
+    match validate_interrupt(interrupt_id){
+        Ok(_) => {},
+        Err(error) => return Err(error),
+    }
+
+    write_register(GICD_BASE + GICD_ISENABLER + (interrupt_id / 32) * 4, 1 << (interrupt_id % 32));
+
+    Ok(())
+}
+
+pub fn disable_interrupt(interrupt_id: usize) -> Result<(), &'static str>{
+    match validate_interrupt(interrupt_id){
+        Ok(_) => {},
+        Err(error) => return Err(error),
+    }
+
+    write_register(GICD_BASE + GICD_ICENABLER + (interrupt_id / 32) * 4, 1 << (interrupt_id % 32));
+
+    Ok(())
+}
+
+/// Send a software generated interrupt to every core whose bit is set in the target mask.
+pub fn send_sgi(interrupt_id: usize, target_mask: u8) -> Result<(), &'static str>{
+    if interrupt_id >= SGI_COUNT{
+        return Err("Software generated interrupt IDs run from 0 to 15.");
+    }
+
+    //Make our writes visible to the targets before they take the interrupt.
+    unsafe{
+        core::arch::asm!("dsb ishst", options(nostack));
+    }
+
+    write_register(GICD_BASE + GICD_SGIR, (target_mask as u32) << 16 | interrupt_id as u32);
+
+    Ok(())
+}
+
+/// Acknowledge and dispatch every pending interrupt. Called from the IRQ exception vector.
+pub fn handle_irq(){
+    loop{
+        let iar = read_register(GICC_BASE + GICC_IAR);
+        let interrupt_id = (iar & 0x3FF) as usize;
+
+        if interrupt_id == SPURIOUS_INTERRUPT{
+            break;
+        }
+
+        if interrupt_id < MAX_INTERRUPTS{
+            let handler = HANDLERS[interrupt_id].load(Ordering::Acquire);
+            let context = CONTEXTS[interrupt_id].load(Ordering::Relaxed);
+
+            let handler: InterruptHandler = if handler == 0{
+                unhandled_interrupt
+            } else{
+                unsafe{ core::mem::transmute::<usize, InterruptHandler>(handler) }
+            };
+
+            handler(context);
+        }
+
+        //The whole IAR value, including the source CPU for SGIs, must be written back.
+        write_register(GICC_BASE + GICC_EOIR, iar);
+    }
+}
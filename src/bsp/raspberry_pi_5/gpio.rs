@@ -1,36 +1,34 @@
-const GPIO_BASE: usize = 0x1F000d0000;
-const GPIO_COUNT: usize = 28;                         
-
-const GPIO_ADDRESSES: [usize; GPIO_COUNT] = [
-    GPIO_BASE,        //GPIO0
-    GPIO_BASE + 0x08, //GPIO1
-    GPIO_BASE + 0x10, //GPIO2
-    GPIO_BASE + 0x18, //GPIO3
-    GPIO_BASE + 0x20, //GPIO4
-    GPIO_BASE + 0x28, //GPIO5
-    GPIO_BASE + 0x30, //GPIO6
-    GPIO_BASE + 0x38, //GPIO7
-    GPIO_BASE + 0x40, //GPIO8
-    GPIO_BASE + 0x48, //GPIO9
-    GPIO_BASE + 0x50, //GPIO10
-    GPIO_BASE + 0x58, //GPIO11
-    GPIO_BASE + 0x60, //GPIO12
-    GPIO_BASE + 0x68, //GPIO13
-    GPIO_BASE + 0x70, //GPIO14
-    GPIO_BASE + 0x78, //GPIO15
-    GPIO_BASE + 0x80, //GPIO16
-    GPIO_BASE + 0x88, //GPIO17
-    GPIO_BASE + 0x90, //GPIO18
-    GPIO_BASE + 0x98, //GPIO19
-    GPIO_BASE + 0xA0, //GPIO20
-    GPIO_BASE + 0xA8, //GPIO21
-    GPIO_BASE + 0xB0, //GPIO22
-    GPIO_BASE + 0xB8, //GPIO23
-    GPIO_BASE + 0xC0, //GPIO24
-    GPIO_BASE + 0xC8, //GPIO25
-    GPIO_BASE + 0xD0, //GPIO26
-    GPIO_BASE + 0xD8  //GPIO27
-];
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::kernel::driver::{driver, Device};
+
+/// The RP1 GPIO bank 0 registers, from the device tree. 0 until the driver is probed.
+static GPIO_BASE: AtomicUsize = AtomicUsize::new(0);
+const GPIO_COUNT: usize = 28;
+
+driver!(RP1_GPIO, compatible = ["raspberrypi,rp1-gpio"], probe = probe);
+
+fn probe(device: &Device) -> Result<(), &'static str>{
+    match device.reg(0){
+        Some(region) => {
+            GPIO_BASE.store(region.start, Ordering::Release);
+            Ok(())
+        },
+        None => Err("The GPIO controller has no registers."),
+    }
+}
+
+/// The address of a pin's status and control registers. Each pin has 8 bytes.
+fn gpio_address(pin: usize) -> Result<usize, &'static str>{
+    if pin >= GPIO_COUNT{
+        return Err("Invalid GPIO pin. Values must be between 0 and 27.");
+    }
+
+    match GPIO_BASE.load(Ordering::Acquire){
+        0 => Err("The GPIO controller wasn't found in the device tree."),
+        base => Ok(base + pin * 0x08),
+    }
+}
 
 struct GpioRegisterDefinition{
     offset: usize,
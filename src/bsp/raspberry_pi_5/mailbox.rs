@@ -0,0 +1,119 @@
+use core::cell::UnsafeCell;
+
+use crate::memory::cache;
+use crate::sync::SpinLock;
+
+/// The VideoCore mailbox the firmware answers property requests on.
+const MAILBOX_BASE: usize = 0x10_7C01_3880;
+
+//Mailbox registers. We read from mailbox 0 and write to mailbox 1.
+const MAILBOX_READ: usize = 0x00;
+const MAILBOX_STATUS: usize = 0x18;
+const MAILBOX_WRITE: usize = 0x20;
+
+//MAILBOX_STATUS bits
+const MAILBOX_FULL: u32 = 1 << 31;
+const MAILBOX_EMPTY: u32 = 1 << 30;
+
+/// Property requests from the ARM to the VideoCore.
+const PROPERTY_CHANNEL: u32 = 8;
+
+const REQUEST_CODE: u32 = 0;
+const RESPONSE_SUCCESS: u32 = 0x8000_0000;
+
+/// Set in a tag's value length once the firmware has answered it.
+const TAG_RESPONSE: u32 = 1 << 31;
+
+//Property tags
+const TAG_GET_ARM_MEMORY: u32 = 0x0001_0005;
+const TAG_END: u32 = 0;
+
+/// The property buffer. The firmware reads and writes it in memory, so it must be 16 byte aligned and cache line aligned.
+#[repr(C, align(64))]
+struct PropertyBuffer(UnsafeCell<[u32; 16]>);
+
+// Only used while holding MAILBOX_LOCK.
+unsafe impl Sync for PropertyBuffer{}
+
+static BUFFER: PropertyBuffer = PropertyBuffer(UnsafeCell::new([0; 16]));
+static MAILBOX_LOCK: SpinLock<()> = SpinLock::new(());
+
+fn read_register(offset: usize) -> u32{
+    unsafe{
+        core::ptr::read_volatile((MAILBOX_BASE + offset) as *const u32)
+    }
+}
+
+fn write_register(offset: usize, value: u32){
+    unsafe{
+        core::ptr::write_volatile((MAILBOX_BASE + offset) as *mut u32, value)
+    }
+}
+
+/// Send a single tag request and return its response values. The request values go in the
+/// buffer, and the response must fit in the same number of words.
+fn property_call(tag: u32, values: &[u32], response_words: usize) -> Result<[u32; 4], &'static str>{
+    let words = core::cmp::max(values.len(), response_words);
+
+    if words > 4{
+        return Err("The property request is too long.");
+    }
+
+    let _lock = MAILBOX_LOCK.lock();
+    let buffer = unsafe{ &mut *BUFFER.0.get() };
+
+    buffer.fill(0);
+    buffer[0] = ((6 + words) * 4) as u32;
+    buffer[1] = REQUEST_CODE;
+    buffer[2] = tag;
+    buffer[3] = (words * 4) as u32;
+    buffer[4] = 0;
+    buffer[5..5 + values.len()].copy_from_slice(values);
+    buffer[5 + words] = TAG_END;
+
+    let address = buffer.as_ptr() as usize;
+    let length = core::mem::size_of_val(buffer);
+
+    //The firmware only takes 32 bit buffer addresses. Ours is in the first gigabyte, which it
+    //sees at the same address.
+    if address > u32::MAX as usize{
+        return Err("The property buffer isn't addressable by the firmware.");
+    }
+
+    cache::clean_and_invalidate_range(address, length);
+
+    while read_register(MAILBOX_STATUS) & MAILBOX_FULL != 0{
+        core::hint::spin_loop();
+    }
+
+    write_register(MAILBOX_WRITE, address as u32 | PROPERTY_CHANNEL);
+
+    loop{
+        while read_register(MAILBOX_STATUS) & MAILBOX_EMPTY != 0{
+            core::hint::spin_loop();
+        }
+
+        if read_register(MAILBOX_READ) == address as u32 | PROPERTY_CHANNEL{
+            break;
+        }
+    }
+
+    cache::invalidate_range(address, length);
+
+    if buffer[1] != RESPONSE_SUCCESS || buffer[4] & TAG_RESPONSE == 0{
+        return Err("The firmware rejected the property request.");
+    }
+
+    let mut response = [0; 4];
+    response[..response_words].copy_from_slice(&buffer[5..5 + response_words]);
+
+    Ok(response)
+}
+
+/// The base and size of the memory the firmware has given the ARM cores. This is only the first
+/// region, below the VideoCore's share. The device tree describes the rest.
+pub fn arm_memory() -> Result<(usize, usize), &'static str>{
+    let response = property_call(TAG_GET_ARM_MEMORY, &[], 2)?;
+
+    Ok((response[0] as usize, response[1] as usize))
+}
@@ -1,7 +1,98 @@
-
 pub mod uart;
 pub mod gpio;
+pub mod gic;
+pub mod rp1;
+pub mod dma;
+pub mod clocks;
+pub mod pcie;
+pub mod mailbox;
+
+use crate::memory::cache;
+use crate::memory::frame_allocator::MemoryRegion;
+
+/// The generic timer's non-secure EL1 physical timer interrupt, a PPI.
+const TIMER_INTERRUPT: usize = 30;
+
+/// The BCM2712 has four Cortex-A76 cores.
+pub const CORE_COUNT: usize = 4;
+
+/// The firmware parks the secondary cores polling a spin table. Core n waits for a non-zero
+/// entry address at SPIN_TABLE_BASE + 8n.
+const SPIN_TABLE_BASE: usize = 0xd8;
+
+/// The firmware's ARM stub and the spin table sit below the kernel, which is loaded at 0x80000.
+pub const FIRMWARE_RESERVED: [MemoryRegion; 1] = [MemoryRegion::new(0, 0x80000)];
 
 pub fn init(){
+    gic::init();
+
+    //Every other peripheral we drive lives in RP1, so nothing works without the PCIe link.
+    match pcie::init(){
+        Ok(_) => {},
+        Err(error) => panic!("RP1 is unreachable: {}", error),
+    }
+
+    dma::init().expect("Failed to initialize the RP1 DMA controller");
+}
+
+/// Set up the calling secondary core's side of the interrupt controller.
+pub fn init_secondary(){
+    gic::init_cpu_interface();
+}
+
+/// Release a secondary core from the spin table. It starts at entry, at EL2 or EL1, with the MMU off.
+pub fn start_core(core: usize, entry: usize) -> Result<(), &'static str>{
+    if core == 0 || core >= CORE_COUNT{
+        return Err("Only cores 1 to 3 can be started.");
+    }
+
+    let address = SPIN_TABLE_BASE + core * 8;
+
+    unsafe{
+        core::ptr::write_volatile(address as *mut u64, entry as u64);
+    }
+
+    //The core is polling with its caches off, so push the entry out to memory before waking it.
+    cache::clean_range(address, 8);
+
+    unsafe{
+        core::arch::asm!("sev", options(nostack));
+    }
+
+    Ok(())
+}
+
+/// The memory the firmware has given the ARM cores, as reported by the firmware itself.
+/// Only the first region, in the lowest gigabyte, is reported.
+pub fn firmware_memory() -> Result<MemoryRegion, &'static str>{
+    let (start, size) = mailbox::arm_memory()?;
+
+    Ok(MemoryRegion::new(start, size))
+}
+
+/// Dispatch pending interrupts. Called from the IRQ exception vector.
+pub fn handle_irq(){
+    gic::handle_irq();
+}
+
+/// Route the calling core's EL1 physical timer interrupt to a handler.
+pub fn enable_timer_interrupt(handler: gic::InterruptHandler) -> Result<(), &'static str>{
+    gic::register_handler(TIMER_INTERRUPT, handler, 0)?;
+    gic::enable_interrupt(TIMER_INTERRUPT)
+}
+
+/// Route a software generated interrupt to a handler on the calling core. SGI enables are banked,
+/// so every core that should take it must call this.
+pub fn enable_software_interrupt(interrupt_id: usize, handler: gic::InterruptHandler) -> Result<(), &'static str>{
+    if interrupt_id >= gic::SGI_COUNT{
+        return Err("Software generated interrupt IDs run from 0 to 15.");
+    }
+
+    gic::register_handler(interrupt_id, handler, 0)?;
+    gic::enable_interrupt(interrupt_id)
+}
 
-}
\ No newline at end of file
+/// Send a software generated interrupt to every core in the mask.
+pub fn send_software_interrupt(interrupt_id: usize, core_mask: u8) -> Result<(), &'static str>{
+    gic::send_sgi(interrupt_id, core_mask)
+}
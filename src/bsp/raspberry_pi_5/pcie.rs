@@ -0,0 +1,376 @@
+use core::arch::asm;
+
+use super::rp1;
+
+/// The BCM2712 PCIe root complex that RP1 hangs off (pcie2). It is a Broadcom STB controller.
+const PCIE_BASE: usize = 0x1000120000;
+
+/// The BCM2712 MSI interrupt peripheral (MIP) that turns RP1's MSI-X writes into GIC SPIs.
+const MIP_BASE: usize = 0x1000130000;
+
+/// The PCIe bus address MSI writes are sent to. The root complex forwards it to the MIP.
+const MIP_DOORBELL_BUS_ADDRESS: u64 = 0xFF_FFFF_F000;
+const MIP_DOORBELL_SIZE: u64 = 0x1000;
+
+/// The CPU window RP1 is reached through. PCIe bus address 0 appears at rp1::RP1_PERIPHERAL_BASE.
+const OUTBOUND_WINDOW_CPU_BASE: u64 = rp1::RP1_PERIPHERAL_BASE as u64;
+const OUTBOUND_WINDOW_SIZE: u64 = 0x1_0000_0000;
+
+/// Where RP1 sees host memory. This must match rp1::host_dma_address.
+const INBOUND_MEMORY_BUS_ADDRESS: u64 = 0x10_0000_0000;
+const INBOUND_MEMORY_SIZE: u64 = 0x10_0000_0000;
+
+/// Where each RP1 BAR is placed on the bus. BAR1 (the peripherals) must be at 0, since every driver's
+/// register addresses assume it. BAR2 is RP1's shared SRAM, and BAR0 holds the MSI-X table.
+const RP1_BAR1_BUS_ADDRESS: u32 = 0x0000_0000;
+const RP1_BAR2_BUS_ADDRESS: u32 = 0x0040_0000;
+const RP1_BAR0_BUS_ADDRESS: u32 = 0x0041_0000;
+
+const RP1_VENDOR_ID: u16 = 0x1DE4;
+const RP1_DEVICE_ID: u16 = 0x0001;
+
+//Root complex registers
+const PCIE_RC_CAP_REGS: usize = 0x00AC;
+const PCIE_MISC_MISC_CTRL: usize = 0x4008;
+const PCIE_MISC_CPU_2_PCIE_MEM_WIN0_LO: usize = 0x400C;
+const PCIE_MISC_CPU_2_PCIE_MEM_WIN0_HI: usize = 0x4010;
+const PCIE_MISC_RC_BAR1_CONFIG_LO: usize = 0x402C;
+const PCIE_MISC_RC_BAR1_CONFIG_HI: usize = 0x4030;
+const PCIE_MISC_RC_BAR2_CONFIG_LO: usize = 0x4034;
+const PCIE_MISC_RC_BAR2_CONFIG_HI: usize = 0x4038;
+const PCIE_MISC_PCIE_CTRL: usize = 0x4064;
+const PCIE_MISC_PCIE_STATUS: usize = 0x4068;
+const PCIE_MISC_CPU_2_PCIE_MEM_WIN0_BASE_LIMIT: usize = 0x4070;
+const PCIE_MISC_CPU_2_PCIE_MEM_WIN0_BASE_HI: usize = 0x4080;
+const PCIE_MISC_CPU_2_PCIE_MEM_WIN0_LIMIT_HI: usize = 0x4084;
+const PCIE_MISC_UBUS_BAR1_CONFIG_REMAP: usize = 0x40AC;
+const PCIE_MISC_UBUS_BAR1_CONFIG_REMAP_HI: usize = 0x40B0;
+const PCIE_MISC_UBUS_BAR2_CONFIG_REMAP: usize = 0x40B4;
+const PCIE_MISC_UBUS_BAR2_CONFIG_REMAP_HI: usize = 0x40B8;
+const PCIE_EXT_CFG_DATA: usize = 0x8000;
+const PCIE_EXT_CFG_INDEX: usize = 0x9000;
+
+const MISC_CTRL_SCB_ACCESS_EN: u32 = 1 << 12;
+const MISC_CTRL_CFG_READ_UR_MODE: u32 = 1 << 13;
+const PCIE_CTRL_PERSTB: u32 = 1 << 2;
+const PCIE_STATUS_PHYLINKUP: u32 = 1 << 4;
+const PCIE_STATUS_DL_ACTIVE: u32 = 1 << 5;
+const UBUS_REMAP_ACCESS_EN: u32 = 1 << 0;
+
+//MIP registers
+const MIP_INT_CLEAR: usize = 0x10;
+const MIP_INT_CFGL_HOST: usize = 0x20;
+const MIP_INT_CFGH_HOST: usize = 0x30;
+const MIP_INT_MASKL_HOST: usize = 0x40;
+const MIP_INT_MASKH_HOST: usize = 0x50;
+const MIP_INT_MASKL_VPU: usize = 0x60;
+const MIP_INT_MASKH_VPU: usize = 0x70;
+
+//Standard configuration space registers
+const PCI_VENDOR_ID: usize = 0x00;
+const PCI_COMMAND: usize = 0x04;
+const PCI_BASE_ADDRESS_0: usize = 0x10;
+const PCI_PRIMARY_BUS: usize = 0x18;
+const PCI_MEMORY_BASE: usize = 0x20;
+const PCI_CAPABILITY_LIST: usize = 0x34;
+const PCI_EXP_LNKSTA: usize = 0x12;
+
+const PCI_COMMAND_MEMORY: u32 = 1 << 1;
+const PCI_COMMAND_MASTER: u32 = 1 << 2;
+const PCI_CAP_ID_MSIX: u32 = 0x11;
+const PCI_MSIX_FLAGS_ENABLE: u32 = 1 << 31;
+
+/// How long to wait for the link to train after releasing PERST#
+const LINK_UP_TIMEOUT_MICROSECONDS: u64 = 100_000;
+
+/// The state of the PCIe link, as found by init.
+pub struct LinkStatus{
+    /// The negotiated speed, as a PCIe generation (1 = 2.5GT/s, 2 = 5GT/s, 3 = 8GT/s)
+    pub generation: u32,
+    /// The negotiated number of lanes
+    pub width: u32,
+    /// True if the link was already up and RP1 configured when we got here
+    pub configured_by_firmware: bool
+}
+
+fn read_register(address: usize) -> u32{
+    unsafe{
+        core::ptr::read_volatile(address as *const u32)
+    }
+}
+
+fn write_register(address: usize, value: u32){
+    unsafe{
+        core::ptr::write_volatile(address as *mut u32, value)
+    }
+}
+
+/// Busy wait using the generic timer's counter. There is no timer service this early in boot.
+fn delay_microseconds(microseconds: u64){
+    let frequency: u64;
+    let start: u64;
+
+    unsafe{
+        asm!("mrs {}, cntfrq_el0", out(reg) frequency, options(nomem, nostack));
+        asm!("isb", "mrs {}, cntpct_el0", out(reg) start, options(nomem, nostack));
+    }
+
+    let ticks = frequency * microseconds / 1_000_000;
+
+    loop{
+        let now: u64;
+        unsafe{
+            asm!("isb", "mrs {}, cntpct_el0", out(reg) now, options(nomem, nostack));
+        }
+
+        if now - start >= ticks{
+            break;
+        }
+    }
+}
+
+fn is_link_up() -> bool{
+    let status = read_register(PCIE_BASE + PCIE_MISC_PCIE_STATUS);
+    status & PCIE_STATUS_PHYLINKUP > 0 && status & PCIE_STATUS_DL_ACTIVE > 0
+}
+
+/// Read a 32-bit configuration register. Bus 0 is the root complex itself; anything else goes out over the link.
+fn config_read(bus: u32, device: u32, function: u32, offset: usize) -> u32{
+    if bus == 0{
+        return read_register(PCIE_BASE + offset);
+    }
+
+    write_register(PCIE_BASE + PCIE_EXT_CFG_INDEX, (bus << 20) | (device << 15) | (function << 12));
+    read_register(PCIE_BASE + PCIE_EXT_CFG_DATA + offset)
+}
+
+fn config_write(bus: u32, device: u32, function: u32, offset: usize, value: u32){
+    if bus == 0{
+        write_register(PCIE_BASE + offset, value);
+        return;
+    }
+
+    write_register(PCIE_BASE + PCIE_EXT_CFG_INDEX, (bus << 20) | (device << 15) | (function << 12));
+    write_register(PCIE_BASE + PCIE_EXT_CFG_DATA + offset, value);
+}
+
+/// Encode the size of an inbound (RC_BAR) window, as the controller expects it.
+fn encode_inbound_size(size: u64) -> Result<u32, &'static str>{
+    let log2 = 63 - size.leading_zeros();
+
+    if !size.is_power_of_two(){
+        return Err("Inbound window sizes must be a power of two.");
+    }
+
+    match log2{
+        12..=15 => Ok(log2 - 12 + 0x1C),
+        16..=35 => Ok(log2 - 15),
+        _ => Err("Unsupported inbound window size."),
+    }
+}
+
+/// Program an inbound window, letting the device reach `cpu_address` at `bus_address`.
+fn configure_inbound_window(config_lo: usize, config_hi: usize, remap_lo: usize, remap_hi: usize, bus_address: u64, cpu_address: u64, size: u64) -> Result<(), &'static str>{
+    let encoded_size = encode_inbound_size(size)?;
+
+    write_register(PCIE_BASE + config_lo, (bus_address as u32 & !0xFFF) | encoded_size);
+    write_register(PCIE_BASE + config_hi, (bus_address >> 32) as u32);
+
+    write_register(PCIE_BASE + remap_lo, (cpu_address as u32 & !0xFFF) | UBUS_REMAP_ACCESS_EN);
+    write_register(PCIE_BASE + remap_hi, (cpu_address >> 32) as u32);
+
+    Ok(())
+}
+
+/// Program the outbound window, so CPU accesses to RP1_PERIPHERAL_BASE reach PCIe bus address 0.
+fn configure_outbound_window(){
+    let cpu_base_mb = OUTBOUND_WINDOW_CPU_BASE >> 20;
+    let cpu_limit_mb = (OUTBOUND_WINDOW_CPU_BASE + OUTBOUND_WINDOW_SIZE - 1) >> 20;
+
+    //The bus address the window starts at
+    write_register(PCIE_BASE + PCIE_MISC_CPU_2_PCIE_MEM_WIN0_LO, 0);
+    write_register(PCIE_BASE + PCIE_MISC_CPU_2_PCIE_MEM_WIN0_HI, 0);
+
+    //The CPU range, in megabytes. The low 12 bits live in BASE_LIMIT, the rest in the HI registers.
+    write_register(
+        PCIE_BASE + PCIE_MISC_CPU_2_PCIE_MEM_WIN0_BASE_LIMIT,
+        (((cpu_base_mb & 0xFFF) as u32) << 4) | (((cpu_limit_mb & 0xFFF) as u32) << 20)
+    );
+    write_register(PCIE_BASE + PCIE_MISC_CPU_2_PCIE_MEM_WIN0_BASE_HI, (cpu_base_mb >> 12) as u32);
+    write_register(PCIE_BASE + PCIE_MISC_CPU_2_PCIE_MEM_WIN0_LIMIT_HI, (cpu_limit_mb >> 12) as u32);
+}
+
+/// Reset the endpoint and wait for the link to train.
+fn train_link() -> Result<(), &'static str>{
+    let ctrl_address = PCIE_BASE + PCIE_MISC_PCIE_CTRL;
+
+    //Assert PERST#, give the endpoint time to notice, then release it.
+    write_register(ctrl_address, read_register(ctrl_address) & !PCIE_CTRL_PERSTB);
+    delay_microseconds(100);
+    write_register(ctrl_address, read_register(ctrl_address) | PCIE_CTRL_PERSTB);
+
+    let mut waited = 0;
+    while !is_link_up(){
+        if waited >= LINK_UP_TIMEOUT_MICROSECONDS{
+            return Err("The PCIe link to RP1 did not come up.");
+        }
+
+        delay_microseconds(1000);
+        waited += 1000;
+    }
+
+    Ok(())
+}
+
+/// Route every MSI the MIP receives to the host's GIC as an edge, rather than to the VPU.
+fn configure_mip(){
+    write_register(MIP_BASE + MIP_INT_MASKL_VPU, 0xFFFF_FFFF);
+    write_register(MIP_BASE + MIP_INT_MASKH_VPU, 0xFFFF_FFFF);
+    write_register(MIP_BASE + MIP_INT_CFGL_HOST, 0xFFFF_FFFF);
+    write_register(MIP_BASE + MIP_INT_CFGH_HOST, 0xFFFF_FFFF);
+    write_register(MIP_BASE + MIP_INT_CLEAR, 0xFFFF_FFFF);
+    write_register(MIP_BASE + MIP_INT_CLEAR + 4, 0xFFFF_FFFF);
+    write_register(MIP_BASE + MIP_INT_MASKL_HOST, 0);
+    write_register(MIP_BASE + MIP_INT_MASKH_HOST, 0);
+}
+
+/// Give the bridge and RP1 their bus numbers and BARs, and let RP1 master the bus.
+fn configure_rp1() -> Result<(), &'static str>{
+    //Root port: primary bus 0, secondary and subordinate bus 1.
+    config_write(0, 0, 0, PCI_PRIMARY_BUS, 0x0001_0100);
+
+    //Forward the whole outbound window to the secondary bus. Base and limit are in 1MB units.
+    let limit = ((OUTBOUND_WINDOW_SIZE - 1) >> 16) as u32 & 0xFFF0;
+    config_write(0, 0, 0, PCI_MEMORY_BASE, limit << 16);
+    config_write(0, 0, 0, PCI_COMMAND, config_read(0, 0, 0, PCI_COMMAND) | PCI_COMMAND_MEMORY | PCI_COMMAND_MASTER);
+
+    let id = config_read(1, 0, 0, PCI_VENDOR_ID);
+    if id as u16 != RP1_VENDOR_ID || (id >> 16) as u16 != RP1_DEVICE_ID{
+        return Err("The device on the PCIe link is not RP1.");
+    }
+
+    config_write(1, 0, 0, PCI_BASE_ADDRESS_0, RP1_BAR0_BUS_ADDRESS);
+    config_write(1, 0, 0, PCI_BASE_ADDRESS_0 + 4, RP1_BAR1_BUS_ADDRESS);
+    config_write(1, 0, 0, PCI_BASE_ADDRESS_0 + 8, RP1_BAR2_BUS_ADDRESS);
+    config_write(1, 0, 0, PCI_COMMAND, config_read(1, 0, 0, PCI_COMMAND) | PCI_COMMAND_MEMORY | PCI_COMMAND_MASTER);
+
+    Ok(())
+}
+
+/// Find a capability in RP1's configuration space. Returns its offset.
+fn find_rp1_capability(capability_id: u32) -> Option<usize>{
+    let mut offset = (config_read(1, 0, 0, PCI_CAPABILITY_LIST) & 0xFC) as usize;
+
+    while offset != 0{
+        let header = config_read(1, 0, 0, offset);
+
+        if header & 0xFF == capability_id{
+            return Some(offset);
+        }
+
+        offset = ((header >> 8) & 0xFC) as usize;
+    }
+
+    None
+}
+
+/// Point every one of RP1's MSI-X vectors at the MIP doorbell, with the vector number as the data.
+/// The MIP raises SPI (base + data), which is what rp1::gic_interrupt_id expects.
+fn configure_msix() -> Result<(), &'static str>{
+    let capability = match find_rp1_capability(PCI_CAP_ID_MSIX){
+        Some(offset) => offset,
+        None => return Err("RP1 has no MSI-X capability."),
+    };
+
+    let table = config_read(1, 0, 0, capability + 4);
+    let table_bar = (table & 0x7) as usize;
+    let table_offset = (table & !0x7) as usize;
+
+    let bar_bus_address = match table_bar{
+        0 => RP1_BAR0_BUS_ADDRESS,
+        1 => RP1_BAR1_BUS_ADDRESS,
+        2 => RP1_BAR2_BUS_ADDRESS,
+        _ => return Err("RP1's MSI-X table is in a BAR we don't map."),
+    };
+
+    let table_address = rp1::RP1_PERIPHERAL_BASE + bar_bus_address as usize + table_offset;
+
+    for vector in 0..rp1::RP1_INTERRUPT_COUNT{
+        let entry = table_address + vector * 16;
+
+        write_register(entry, MIP_DOORBELL_BUS_ADDRESS as u32);
+        write_register(entry + 4, (MIP_DOORBELL_BUS_ADDRESS >> 32) as u32);
+        write_register(entry + 8, vector as u32);
+        //Unmask the vector. RP1 still only sends it once the interrupt is enabled in its MSIX_CFG.
+        write_register(entry + 12, 0);
+    }
+
+    config_write(1, 0, 0, capability, config_read(1, 0, 0, capability) | PCI_MSIX_FLAGS_ENABLE);
+
+    Ok(())
+}
+
+/// Read the negotiated link speed and width from the root port's PCIe capability.
+fn link_status(configured_by_firmware: bool) -> LinkStatus{
+    let register = PCIE_RC_CAP_REGS + PCI_EXP_LNKSTA;
+    let status = config_read(0, 0, 0, register & !0x3) >> ((register & 0x3) * 8);
+
+    LinkStatus{
+        generation: status & 0xF,
+        width: (status >> 4) & 0x3F,
+        configured_by_firmware
+    }
+}
+
+/// Make sure RP1 is reachable. If the firmware left the link up, its windows are reprogrammed to what
+/// the drivers expect; otherwise the link is brought up from reset. Either way, RP1 is identified and
+/// its MSI-X vectors are routed to the GIC. Returns an error if RP1 can't be reached.
+pub fn init() -> Result<LinkStatus, &'static str>{
+    let configured_by_firmware = is_link_up();
+
+    //Let the CPU reach the controller's configuration space, and return all ones for unsupported requests.
+    let misc_ctrl = read_register(PCIE_BASE + PCIE_MISC_MISC_CTRL);
+    write_register(PCIE_BASE + PCIE_MISC_MISC_CTRL, misc_ctrl | MISC_CTRL_SCB_ACCESS_EN | MISC_CTRL_CFG_READ_UR_MODE);
+
+    configure_outbound_window();
+
+    //BAR1 lets RP1 ring the MIP doorbell, BAR2 lets it reach host memory for DMA.
+    match configure_inbound_window(
+        PCIE_MISC_RC_BAR1_CONFIG_LO, PCIE_MISC_RC_BAR1_CONFIG_HI,
+        PCIE_MISC_UBUS_BAR1_CONFIG_REMAP, PCIE_MISC_UBUS_BAR1_CONFIG_REMAP_HI,
+        MIP_DOORBELL_BUS_ADDRESS, MIP_BASE as u64, MIP_DOORBELL_SIZE
+    ){
+        Ok(_) => {},
+        Err(error) => return Err(error),
+    }
+
+    match configure_inbound_window(
+        PCIE_MISC_RC_BAR2_CONFIG_LO, PCIE_MISC_RC_BAR2_CONFIG_HI,
+        PCIE_MISC_UBUS_BAR2_CONFIG_REMAP, PCIE_MISC_UBUS_BAR2_CONFIG_REMAP_HI,
+        INBOUND_MEMORY_BUS_ADDRESS, 0, INBOUND_MEMORY_SIZE
+    ){
+        Ok(_) => {},
+        Err(error) => return Err(error),
+    }
+
+    if !configured_by_firmware{
+        match train_link(){
+            Ok(_) => {},
+            Err(error) => return Err(error),
+        }
+    }
+
+    match configure_rp1(){
+        Ok(_) => {},
+        Err(error) => return Err(error),
+    }
+
+    configure_mip();
+
+    match configure_msix(){
+        Ok(_) => {},
+        Err(error) => return Err(error),
+    }
+
+    Ok(link_status(configured_by_firmware))
+}
@@ -0,0 +1,112 @@
+use super::gic;
+
+/// RP1 peripherals are mapped through the PCIe outbound window set up by pcie::init. RP1 address 0x40000000 appears here.
+pub const RP1_PERIPHERAL_BASE: usize = 0x1F00000000;
+
+/// RP1's own address for its peripheral block. Bus masters inside RP1, like the DMA controller, use this.
+const RP1_INTERNAL_PERIPHERAL_BASE: usize = 0x40000000;
+
+/// The host's memory appears at this offset in RP1's address space, through the PCIe inbound window set up by pcie::init.
+const RP1_HOST_MEMORY_BASE: usize = 0x1000000000;
+
+/// The PCIe endpoint configuration block inside RP1. It holds the per-interrupt MSI-X controls.
+const RP1_PCIE_APBS_BASE: usize = RP1_PERIPHERAL_BASE + 0x108000;
+
+/// MSI-X configuration register for RP1 interrupt n.
+const fn msix_cfg(rp1_interrupt: usize) -> usize{
+    RP1_PCIE_APBS_BASE + 0x008 + rp1_interrupt * 4
+}
+
+/// RP1 registers have atomic set and clear aliases at these offsets.
+const REG_SET: usize = 0x800;
+const REG_CLR: usize = 0xC00;
+
+const MSIX_CFG_ENABLE: u32 = 1 << 0;
+const MSIX_CFG_IACK: u32 = 1 << 2;
+const MSIX_CFG_IACK_EN: u32 = 1 << 3;
+
+/// RP1 raises its interrupts as MSI-X vectors. The BCM2712 MSI interrupt peripheral (MIP) turns
+/// vector n into SPI (RP1_MSI_SPI_BASE + n). The path is set up by pcie::init.
+const RP1_MSI_SPI_BASE: usize = 128;
+
+/// The number of interrupt sources RP1 has.
+pub const RP1_INTERRUPT_COUNT: usize = 61;
+
+/// RP1 interrupt numbers
+pub const RP1_INT_DMA: usize = 40;
+
+/// Translate the address of an RP1 register, as the CPU sees it, to the address an RP1 bus master uses.
+pub const fn peripheral_dma_address(address: usize) -> usize{
+    address - RP1_PERIPHERAL_BASE + RP1_INTERNAL_PERIPHERAL_BASE
+}
+
+/// Translate a physical address in host memory to the address an RP1 bus master uses.
+pub const fn host_dma_address(address: usize) -> usize{
+    address + RP1_HOST_MEMORY_BASE
+}
+
+fn validate_interrupt(rp1_interrupt: usize) -> Result<(), &'static str>{
+    if rp1_interrupt >= RP1_INTERRUPT_COUNT{
+        return Err("Invalid RP1 interrupt number.");
+    }
+
+    Ok(())
+}
+
+/// The GIC interrupt ID that an RP1 interrupt is delivered on.
+pub const fn gic_interrupt_id(rp1_interrupt: usize) -> usize{
+    32 + RP1_MSI_SPI_BASE + rp1_interrupt
+}
+
+/// Register a handler for an RP1 interrupt and enable it, both in RP1 and in the GIC.
+/// The MSI reaches the GIC as an edge, and RP1 won't send another until it's acknowledged,
+/// so the handler must clear the source and call acknowledge_interrupt before returning.
+pub fn enable_interrupt(rp1_interrupt: usize, handler: gic::InterruptHandler, context: usize) -> Result<(), &'static str>{
+    match validate_interrupt(rp1_interrupt){
+        Ok(_) => {},
+        Err(error) => return Err(error),
+    }
+
+    let interrupt_id = gic_interrupt_id(rp1_interrupt);
+
+    match gic::register_handler(interrupt_id, handler, context){
+        Ok(_) => {},
+        Err(error) => return Err(error),
+    }
+
+    match gic::set_edge_triggered(interrupt_id, true){
+        Ok(_) => {},
+        Err(error) => return Err(error),
+    }
+
+    //Level interrupts need an explicit acknowledge in RP1 before they can be raised again.
+    unsafe{
+        core::ptr::write_volatile((msix_cfg(rp1_interrupt) + REG_SET) as *mut u32, MSIX_CFG_ENABLE | MSIX_CFG_IACK_EN);
+    }
+
+    gic::enable_interrupt(interrupt_id)
+}
+
+pub fn disable_interrupt(rp1_interrupt: usize) -> Result<(), &'static str>{
+    match validate_interrupt(rp1_interrupt){
+        Ok(_) => {},
+        Err(error) => return Err(error),
+    }
+
+    unsafe{
+        core::ptr::write_volatile((msix_cfg(rp1_interrupt) + REG_CLR) as *mut u32, MSIX_CFG_ENABLE);
+    }
+
+    gic::disable_interrupt(gic_interrupt_id(rp1_interrupt))
+}
+
+/// Acknowledge a level sensitive RP1 interrupt so that RP1 can send its MSI again.
+pub fn acknowledge_interrupt(rp1_interrupt: usize){
+    if rp1_interrupt >= RP1_INTERRUPT_COUNT{
+        return;
+    }
+
+    unsafe{
+        core::ptr::write_volatile((msix_cfg(rp1_interrupt) + REG_SET) as *mut u32, MSIX_CFG_IACK);
+    }
+}
@@ -1,15 +1,158 @@
-pub const UARK_CLK: usize = 48000000usize;
-
-/// These are the base addresses for UARTS 0-5 on the RP1 SoC.
-/// The UART controller is extremely similar to the PL1011
-const UART_ADDRESSES: [usize; 6] = [
-    0x1F00030000, // UART0
-    0x1F00034000, // UART1
-    0x1F00038000, // UART2
-    0x1F0003C000, // UART3
-    0x1F00040000, // UART4
-    0x1F00044000  // UART5
-];
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
+
+use crate::kernel::driver::{driver, Device};
+use crate::kernel::peripherals::{PeripheralClaim, PeripheralRegistry};
+use crate::kernel::wait_queue::WaitQueue;
+use crate::memory::cache;
+use crate::memory::frame_allocator::MemoryRegion;
+use crate::sync::{IrqSpinLock, Once};
+
+use super::clocks;
+use super::dma;
+use super::gic;
+use super::rp1;
+
+/// The number of UARTs the driver can handle. RP1 has six, which the controller is extremely similar to the PL011.
+pub const UART_COUNT: usize = 6;
+
+/// Which UARTs currently have a UartInstance. Each UART can only have one at a time.
+static UART_OWNERSHIP: PeripheralRegistry<UART_COUNT> = PeripheralRegistry::new("The UART is already owned by another instance. Share it with kernel::peripherals::Shared instead.");
+
+/// The default largest error allowed between the requested and achieved baud rate, in hundredths of a percent.
+const DEFAULT_BAUD_RATE_TOLERANCE: usize = 200;
+
+/// The DMA controller the RP1 UARTs' request lines go to.
+const RP1_DMA_COMPATIBLE: &str = "snps,axi-dma-1.01a";
+
+/// Where a UART's interrupt is delivered.
+#[derive(Clone, Copy)]
+enum UartInterrupt{
+    /// An RP1 interrupt, forwarded to the GIC over PCIe
+    Rp1(usize),
+    /// A GIC interrupt ID, for a UART in the BCM2712 itself
+    Gic(usize)
+}
+
+/// A UART found in the device tree.
+#[derive(Clone, Copy)]
+struct UartDevice{
+    registers: MemoryRegion,
+    interrupt: UartInterrupt,
+    /// The RP1 DMA request lines for the transmit and receive FIFOs, if the UART has them
+    dma_requests: Option<(usize, usize)>,
+    /// The reference clock, if the device tree gives it a fixed rate. Otherwise the RP1 clock manager has it.
+    clock_frequency: Option<usize>
+}
+
+/// The UARTs probed from the device tree, by index. A UART with a serialN alias gets index N.
+static UART_DEVICES: [Once<UartDevice>; UART_COUNT] = [const { Once::new() }; UART_COUNT];
+
+/// RP1's UART0, on the GPIO header, where the Raspberry Pi 5 firmware always puts it. Used as UART 0
+/// when there's no device tree, so there's still a console.
+const FALLBACK_CONSOLE: UartDevice = UartDevice{
+    registers: MemoryRegion::new(0x1F_0003_0000, 0x4000),
+    interrupt: UartInterrupt::Rp1(25),
+    dma_requests: Some((10, 11)),
+    clock_frequency: None
+};
+
+driver!(PL011_UART, compatible = ["arm,pl011-axi", "arm,pl011"], probe = probe);
+
+/// The transmit and receive DMA request lines from the dmas and dma-names properties, if they go to RP1's DMA controller.
+fn rp1_dma_requests(device: &Device) -> Option<(usize, usize)>{
+    let tree = device.node.tree();
+    let names = device.node.property("dma-names")?;
+    let mut cells = device.node.property("dmas")?.cells();
+    let (mut transmit, mut receive) = (None, None);
+
+    //Each entry is the controller's phandle and one cell, the request line.
+    for name in names.strings(){
+        let (controller, request) = (cells.next()?, cells.next()? as usize);
+
+        if !tree.find_phandle(controller)?.is_compatible(RP1_DMA_COMPATIBLE){
+            return None;
+        }
+
+        match name{
+            "tx" => transmit = Some(request),
+            "rx" => receive = Some(request),
+            _ => {},
+        }
+    }
+
+    Some((transmit?, receive?))
+}
+
+/// Record a UART from the device tree. The hardware isn't touched until an instance is built.
+fn probe(device: &Device) -> Result<(), &'static str>{
+    let registers = match device.reg(0){
+        Some(region) => region,
+        None => return Err("The UART has no registers."),
+    };
+
+    let interrupt = match (device.interrupt(0), device.interrupt_controller(0)){
+        (Some(interrupt), Some(controller)) if controller.compatible().any(|compatible| compatible.contains("gic")) => {
+            UartInterrupt::Gic(interrupt.gic_id().ok_or("The UART's GIC interrupt is malformed.")?)
+        },
+        (Some(interrupt), _) => UartInterrupt::Rp1(interrupt.cells[0] as usize),
+        (None, _) => return Err("The UART has no interrupt."),
+    };
+
+    //A UART without an alias takes a free slot no alias asks for, so the order nodes come in
+    //never changes which UART an index means.
+    let index = match device.alias_index("serial"){
+        Some(index) if index >= UART_COUNT => return Err("The UART's serial alias is beyond the UARTs the driver handles."),
+        Some(index) => Some(index).filter(|index| !UART_DEVICES[*index].is_completed()),
+        None => (0..UART_COUNT).find(|index| !UART_DEVICES[*index].is_completed() && !is_aliased_slot(device, *index)),
+    }.ok_or("Every UART slot is taken.")?;
+
+    UART_DEVICES[index].call_once(|| UartDevice{
+        registers,
+        interrupt,
+        dma_requests: rp1_dma_requests(device),
+        clock_frequency: device.fixed_clock_frequency(0).map(|frequency| frequency as usize)
+    });
+
+    Ok(())
+}
+
+/// Whether any node has the serialN alias for a slot.
+fn is_aliased_slot(device: &Device, index: usize) -> bool{
+    let aliases = match device.node.tree().find_node("/aliases"){
+        Some(aliases) => aliases,
+        None => return false,
+    };
+
+    aliases.properties().any(|alias| alias.name.strip_prefix("serial").and_then(|number| number.parse::<usize>().ok()) == Some(index))
+}
+
+/// Use RP1's UART0 as UART 0, for booting without a device tree.
+pub fn register_fallback_console(){
+    UART_DEVICES[0].call_once(|| FALLBACK_CONSOLE);
+}
+
+fn uart_device(uart_index: usize) -> Result<&'static UartDevice, &'static str>{
+    match UART_DEVICES.get(uart_index).and_then(|device| device.get()){
+        Some(device) => Ok(device),
+        None => Err("There is no such UART in the device tree."),
+    }
+}
+
+/// UARTDMACR bits
+const UARTDMACR_RXDMAE: usize = 1usize << 0;
+const UARTDMACR_TXDMAE: usize = 1usize << 1;
+
+/// The size of each UART's software transmit and receive buffers. Must be a power of two.
+const RING_BUFFER_SIZE: usize = 1024;
+
+/// Interrupt bits shared by UARTIMSC, UARTRIS, UARTMIS and UARTICR
+const UART_INTERRUPT_RX: usize = 1usize << 4;
+const UART_INTERRUPT_TX: usize = 1usize << 5;
+const UART_INTERRUPT_RX_TIMEOUT: usize = 1usize << 6;
+const UART_INTERRUPT_ALL: usize = 0x7FF;
 
 enum UartRegisterAccessType{
     ReadOnly,
@@ -141,7 +284,7 @@ const UARTMIS: UartRegisterDefinition = UartRegisterDefinition{
 
 /// Interrupt Clear Register
 const UARTICR: UartRegisterDefinition = UartRegisterDefinition{
-    offset: 0x040,
+    offset: 0x044,
     bit_width: 11,
     data_width: 11,
     access_type: UartRegisterAccessType::WriteOnly
@@ -155,18 +298,126 @@ const UARTDMACR: UartRegisterDefinition = UartRegisterDefinition{
     access_type: UartRegisterAccessType::ReadWrite
 };
 
+/// A single producer, single consumer ring of bytes.
+/// For transmit, the writer pushes bytes and the TX interrupt handler pops them into the hardware FIFO.
+/// For receive, the RX interrupt handler pushes bytes from the hardware FIFO and the reader pops them.
+struct RingBuffer{
+    buffer: UnsafeCell<[u8; RING_BUFFER_SIZE]>,
+    /// The total number of bytes ever pushed. Only the producer moves this: write for transmit,
+    /// and the RX interrupt for receive.
+    head: AtomicUsize,
+    /// The total number of bytes ever popped. The consumer moves this: the FIFO refill for transmit
+    /// and read for receive. clear moves it too, while the UART is stopped.
+    tail: AtomicUsize
+}
+
+// The buffer is only written between tail and head by the producer, and read by the consumer
+// after the head has been published, so sharing it is sound.
+unsafe impl Sync for RingBuffer{}
+
+impl RingBuffer{
+    const fn new() -> RingBuffer{
+        RingBuffer{
+            buffer: UnsafeCell::new([0; RING_BUFFER_SIZE]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0)
+        }
+    }
+
+    fn len(&self) -> usize{
+        self.head.load(Ordering::Acquire).wrapping_sub(self.tail.load(Ordering::Acquire))
+    }
+
+    fn is_empty(&self) -> bool{
+        self.len() == 0
+    }
+
+    fn is_full(&self) -> bool{
+        self.len() == RING_BUFFER_SIZE
+    }
+
+    /// Drop everything in the buffer. Only safe while the producer is stopped.
+    fn clear(&self){
+        self.tail.store(self.head.load(Ordering::Acquire), Ordering::Release);
+    }
+
+    /// Copy as much of data as fits into the buffer. Returns the number of bytes copied.
+    fn push(&self, data: &[u8]) -> usize{
+        let head = self.head.load(Ordering::Relaxed);
+        let free = RING_BUFFER_SIZE - head.wrapping_sub(self.tail.load(Ordering::Acquire));
+        let count = core::cmp::min(free, data.len());
+
+        for (offset, byte) in data[..count].iter().enumerate(){
+            unsafe{
+                (*self.buffer.get())[head.wrapping_add(offset) & (RING_BUFFER_SIZE - 1)] = *byte;
+            }
+        }
+
+        //Publish the bytes to the consumer.
+        self.head.store(head.wrapping_add(count), Ordering::Release);
+
+        count
+    }
+
+    fn pop(&self) -> Option<u8>{
+        let tail = self.tail.load(Ordering::Relaxed);
+
+        if tail == self.head.load(Ordering::Acquire){
+            return None;
+        }
+
+        let byte = unsafe{
+            (*self.buffer.get())[tail & (RING_BUFFER_SIZE - 1)]
+        };
+
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+
+        Some(byte)
+    }
+}
+
+static TX_BUFFERS: [RingBuffer; UART_COUNT] = [const { RingBuffer::new() }; UART_COUNT];
+static RX_BUFFERS: [RingBuffer; UART_COUNT] = [const { RingBuffer::new() }; UART_COUNT];
+
+/// Writers waiting for space in, or for the draining of, each UART's transmit buffer.
+static TX_WAIT_QUEUES: [WaitQueue; UART_COUNT] = [const { WaitQueue::new() }; UART_COUNT];
+
+/// Readers waiting for each UART to receive data.
+static RX_WAIT_QUEUES: [WaitQueue; UART_COUNT] = [const { WaitQueue::new() }; UART_COUNT];
+
+/// Serializes pushing into each UART's transmit buffer, refilling its transmit FIFO and updating
+/// its interrupt mask, between tasks on any core and the UART interrupt.
+static UART_LOCKS: [IrqSpinLock<()>; UART_COUNT] = [const { IrqSpinLock::new(()) }; UART_COUNT];
+
 pub struct UartRegReadResult{
     pub value: usize,
     pub bit_width: usize
 }
 
 fn get_uart_address(uart_index: usize) -> Result<usize, &'static str>{
-    if uart_index >= UART_ADDRESSES.len()
+    if uart_index >= UART_COUNT
     {
         return Err("Invalid UART index passed to write function. Values must be between 0 and 6.");
     }
 
-    Ok(UART_ADDRESSES[uart_index])
+    Ok(uart_device(uart_index)?.registers.start)
+}
+
+/// The UART's registers, from the device tree. Give it to an EL0 task as a region to let it drive the UART itself.
+pub fn register_region(uart_index: usize) -> Result<MemoryRegion, &'static str>{
+    Ok(uart_device(uart_index)?.registers)
+}
+
+/// Write straight to the transmit FIFO, with no instance and no locks, for when nothing else
+/// can run, like a panic. The UART must already have been set up by an instance.
+pub fn emergency_write(uart_index: usize, array: &[u8]) -> Result<(), &'static str>{
+    for byte in array{
+        while Flags::read(uart_index)?.transmit_fifo_full(){}
+
+        UARTDR.write(uart_index, *byte as usize, 8)?;
+    }
+
+    Ok(())
 }
 
 
@@ -269,6 +520,111 @@ impl UartRegisterDefinition{
     }
 }
 
+/// The baud rate divisor for a requested baud rate, and the rate it actually achieves.
+/// The UART divides its reference clock by 16 * (integer + fractional / 64).
+#[derive(Clone, Copy)]
+struct BaudRateDivisor{
+    integer: u16,
+    fractional: u8,
+    achieved: usize,
+    /// (achieved - requested) / requested, in hundredths of a percent
+    error: isize
+}
+
+impl BaudRateDivisor{
+    /// Work out the divisor for a baud rate, using integer math only.
+    /// Fails if the divisor doesn't fit the registers, or misses the requested rate by more than the tolerance.
+    fn calculate(clock: usize, baud_rate: usize, tolerance: usize) -> Result<BaudRateDivisor, &'static str>{
+        if baud_rate == 0{
+            return Err("A baud rate of zero is invalid");
+        }
+
+        //The divisor is clock / (16 * baud_rate), with a 6 bit fraction. Scaling by 64 gives
+        //clock * 4 / baud_rate. We add half the baud rate to round to the nearest value.
+        let scaled = (clock * 4 + baud_rate / 2) / baud_rate;
+        let integer = scaled >> 6;
+        let fractional = scaled & 0x3F;
+
+        //The integer part must be non-zero, and the whole divisor can't be larger than 0xFFFF.
+        if integer == 0{
+            return Err("The baud rate is too high for the UART reference clock");
+        }
+
+        if integer > 0xFFFF || (integer == 0xFFFF && fractional > 0){
+            return Err("The baud rate is too low for the UART reference clock");
+        }
+
+        //Work backwards from the divisor to the rate it actually gives us.
+        let achieved = (clock * 4 + scaled / 2) / scaled;
+        let error = ((achieved as isize - baud_rate as isize) * 10000) / baud_rate as isize;
+
+        if error.unsigned_abs() > tolerance{
+            return Err("The achievable baud rate is outside the allowed tolerance");
+        }
+
+        Ok(BaudRateDivisor{
+            integer: integer as u16,
+            fractional: fractional as u8,
+            achieved,
+            error
+        })
+    }
+}
+
+/// A DMA transfer to or from a UART, started by UartInstance::write_dma or UartInstance::read_dma.
+/// The buffer is handed back by wait. Dropping the transfer early aborts it.
+/// The transfer borrows the instance, so the UART can't be reconfigured or released underneath it.
+pub struct UartDmaTransfer<'a, B>{
+    uart_index: usize,
+    instance: PhantomData<&'a UartInstance>,
+    channel: dma::DmaChannel,
+    /// The UARTDMACR bit that lets the UART make DMA requests for this transfer
+    dma_enable_bit: usize,
+    buffer: Option<B>,
+    address: usize,
+    length: usize
+}
+
+impl<B> UartDmaTransfer<'_, B>{
+    /// True once every byte has been moved, or the transfer has failed.
+    pub fn is_complete(&self) -> bool{
+        self.channel.is_complete()
+    }
+
+    /// Block until the transfer completes, and hand the buffer back.
+    pub fn wait(mut self) -> Result<B, &'static str>{
+        match self.channel.wait(){
+            Ok(_) => {},
+            Err(error) => return Err(error),
+        }
+
+        //The DMA controller wrote received data to memory behind the cache, so drop any stale lines.
+        if self.dma_enable_bit == UARTDMACR_RXDMAE{
+            cache::invalidate_range(self.address, self.length);
+        }
+
+        match self.buffer.take(){
+            Some(buffer) => Ok(buffer),
+            None => Err("The DMA transfer's buffer was already taken."),
+        }
+    }
+}
+
+impl<B> Drop for UartDmaTransfer<'_, B>{
+    fn drop(&mut self){
+        //Drop can't fail, and these only fail for a bad UART index, so errors are ignored.
+        self.channel.abort();
+        let _ = UartInstance::update_dma_control(self.uart_index, self.dma_enable_bit, false);
+
+        //Hand the receive FIFO back to the RX interrupt.
+        if self.dma_enable_bit == UARTDMACR_RXDMAE{
+            let _lock = UART_LOCKS[self.uart_index].lock();
+            let _ = UartInstance::update_interrupt_mask(self.uart_index, UART_INTERRUPT_RX | UART_INTERRUPT_RX_TIMEOUT, true);
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
 pub enum WordLength{
     Bits8,
     Bits7,
@@ -276,45 +632,76 @@ pub enum WordLength{
     Bits5
 }
 
+#[derive(Clone, Copy)]
 pub enum ParitySelect{
     Even,
     Odd
 }
 
+#[derive(Clone, Copy)]
 pub enum StickParityEnableMode{
     Disabled,
     Enabled
 }
 
+#[derive(Clone, Copy)]
 pub enum FIFOEnableMode{
     Enabled,
     Disabled
 }
 
+#[derive(Clone, Copy)]
 pub enum ParityEnableMode{
     Disabled,
     Enabled(ParitySelect)
 }
 
 
+#[derive(Clone, Copy)]
 pub enum StopBitMode{
     OneStopBit,
     TwoStopBits
 }
 
+#[derive(Clone, Copy)]
 pub enum TransmitMode{
     TxOnly,
     RxOnly,
     Bidirectional
 }
 
+/// Why UartInstance::reconfigure failed.
+#[derive(Clone, Copy, Debug)]
+pub struct ReconfigureError{
+    /// Why the new settings couldn't be applied
+    pub error: &'static str,
+    /// Why the old settings couldn't be put back either, if they couldn't. The UART is left disabled then.
+    pub restore_error: Option<&'static str>
+}
+
+impl From<&'static str> for ReconfigureError{
+    fn from(error: &'static str) -> ReconfigureError{
+        ReconfigureError{ error, restore_error: None }
+    }
+}
+
+/// The owner of a UART. Only one can exist per UART; to use a UART from several places,
+/// install its instance in a kernel::peripherals::Shared.
 pub struct UartInstance{
-    uart_index: usize
+    uart_index: usize,
+    /// The settings the UART is currently programmed with
+    configuration: InstanceBuilder,
+    /// The baud rate divisor the UART is programmed with
+    divisor: BaudRateDivisor,
+    /// Our claim on the UART. Dropped after Drop has disabled the hardware.
+    _claim: PeripheralClaim
 }
 
+#[derive(Clone, Copy)]
 pub struct InstanceBuilder{
    pub(in crate::bsp::raspberry_pi_5::uart) uart_index: usize,
    pub(in crate::bsp::raspberry_pi_5::uart) baud_rate: usize,
+   pub(in crate::bsp::raspberry_pi_5::uart) baud_rate_tolerance: usize,
    pub(in crate::bsp::raspberry_pi_5::uart) word_length: WordLength,
    pub(in crate::bsp::raspberry_pi_5::uart) fifo_enable_mode: FIFOEnableMode,
    pub(in crate::bsp::raspberry_pi_5::uart) parity_enable_mode: ParityEnableMode,
@@ -377,6 +764,15 @@ impl InstanceBuilder{
         }
     }
 
+    /// The largest error between the requested and achieved baud rate that build accepts,
+    /// in hundredths of a percent. Defaults to 2%.
+    pub fn with_baud_rate_tolerance(self, baud_rate_tolerance: usize) -> InstanceBuilder{
+        InstanceBuilder{
+            baud_rate_tolerance,
+            ..self
+        }
+    }
+
     pub fn with_transmit_mode(self, transmit_mode: TransmitMode) -> InstanceBuilder{
         InstanceBuilder{
             transmit_mode,
@@ -392,8 +788,9 @@ impl InstanceBuilder{
 
     fn default() -> InstanceBuilder{
         InstanceBuilder{
-            uart_index: UART_ADDRESSES.len() + 1, //By default, set it to the first invalid index. This prevents the default value from being useful, and forces a uart to be selected
+            uart_index: UART_COUNT + 1, //By default, set it to the first invalid index. This prevents the default value from being useful, and forces a uart to be selected
             baud_rate: 115200,
+            baud_rate_tolerance: DEFAULT_BAUD_RATE_TOLERANCE,
             word_length: WordLength::Bits8,
             fifo_enable_mode: FIFOEnableMode::Disabled,
             parity_enable_mode: ParityEnableMode::Disabled,
@@ -717,36 +1114,203 @@ impl UartInstance{
 
     }
 
-    fn set_baud_rate(uart_index: usize, baud_rate: usize) -> Result<(), &'static str>{
+    fn set_baud_rate(uart_index: usize, divisor: &BaudRateDivisor) -> Result<(), &'static str>{
 
+        //We have our bdrf and bdri values. Let's write them.
+        //They only take effect once UARTLCR_H is written, so this must come before configure_line_control.
+        match UARTIBRD.write(uart_index, divisor.integer as usize, UARTIBRD.bit_width){
+            Ok(_) => {},
+            Err(error) => return Err(error),
+        }
 
-        if baud_rate == 0{
-            return Err("A baud rate of zero is invalid");
+        match UARTFBRD.write(uart_index, divisor.fractional as usize, UARTFBRD.bit_width){
+            Ok(_) => Ok(()),
+            Err(error) => Err(error)
+        }
+    }
+
+    /// Mask and clear every UART interrupt, reset the transmit buffer, and register the interrupt handler.
+    /// The TX interrupt itself stays masked until there is something to transmit.
+    fn configure_interrupts(uart_index: usize) -> Result<(), &'static str>{
+        match UARTIMSC.write(uart_index, 0, UARTIMSC.bit_width){
+            Ok(_) => {},
+            Err(error) => return Err(error),
         }
 
-        //First, we need to calculate our divisor
-        let divisor: f64 = (UARK_CLK as f64) / (16 * baud_rate) as f64;
-        let bdri: u16 = divisor as u16;
-        let fractional_value: f64 = divisor - bdri as f64;
+        match UARTICR.write(uart_index, UART_INTERRUPT_ALL, UARTICR.bit_width){
+            Ok(_) => {},
+            Err(error) => return Err(error),
+        }
 
-        let bdrf = ((fractional_value * 64.0) + 0.5) as u8;
+        //Raise the TX interrupt when the FIFO drains to half full (TXIFLSEL = 0b010), and the RX
+        //interrupt when it fills to half full (RXIFLSEL = 0b010). Anything short of that is
+        //picked up by the receive timeout interrupt once the line goes quiet.
+        match UARTIFLS.write(uart_index, 0b010 << 3 | 0b010, UARTIFLS.bit_width){
+            Ok(_) => {},
+            Err(error) => return Err(error),
+        }
 
+        TX_BUFFERS[uart_index].clear();
+        RX_BUFFERS[uart_index].clear();
 
-        //We now have our bdrf and bdri values. Let's write them.
-        match UARTIBRD.write(uart_index, bdri as usize, UARTIBRD.bit_width){
+        match UARTIMSC.write(uart_index, UART_INTERRUPT_RX | UART_INTERRUPT_RX_TIMEOUT, UARTIMSC.bit_width){
             Ok(_) => {},
             Err(error) => return Err(error),
         }
 
-        match UARTFBRD.write(uart_index, bdrf as usize, UARTFBRD.bit_width){
+        match uart_device(uart_index)?.interrupt{
+            UartInterrupt::Rp1(interrupt) => rp1::enable_interrupt(interrupt, Self::handle_interrupt, uart_index),
+            UartInterrupt::Gic(interrupt_id) => {
+                gic::register_handler(interrupt_id, Self::handle_interrupt, uart_index)?;
+                gic::enable_interrupt(interrupt_id)
+            },
+        }
+    }
+
+    /// Set or clear bits in the interrupt mask. Must be called with interrupts masked on this core.
+    fn update_interrupt_mask(uart_index: usize, bits: usize, enabled: bool) -> Result<(), &'static str>{
+        let current_value = UARTIMSC.read(uart_index, UARTIMSC.bit_width)?.value;
+
+        let new_value = if enabled{
+            current_value | bits
+        } else{
+            current_value & !bits
+        };
+
+        match UARTIMSC.write(uart_index, new_value, UARTIMSC.bit_width){
             Ok(_) => Ok(()),
-            Err(error) => Err(error)
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Move bytes from the transmit buffer into the hardware FIFO until either one runs out.
+    /// The TX interrupt is left unmasked only while there are bytes still waiting.
+    /// Must be called with interrupts masked on this core.
+    fn fill_transmit_fifo(uart_index: usize) -> Result<(), &'static str>{
+        let buffer = &TX_BUFFERS[uart_index];
+
+        loop{
+            let flags = Flags::read(uart_index)?;
+
+            if flags.transmit_fifo_full(){
+                break;
+            }
+
+            match buffer.pop(){
+                Some(byte) => {
+                    match UARTDR.write(uart_index, byte as usize, 8){
+                        Ok(_) => {},
+                        Err(error) => return Err(error),
+                    }
+                },
+                None => break,
+            }
+        }
+
+        Self::update_interrupt_mask(uart_index, UART_INTERRUPT_TX, !buffer.is_empty())
+    }
+
+    /// Set or clear bits in the DMA control register.
+    fn update_dma_control(uart_index: usize, bits: usize, enabled: bool) -> Result<(), &'static str>{
+        let current_value = UARTDMACR.read(uart_index, UARTDMACR.bit_width)?.value;
+
+        let new_value = if enabled{
+            current_value | bits
+        } else{
+            current_value & !bits
+        };
+
+        match UARTDMACR.write(uart_index, new_value, UARTDMACR.bit_width){
+            Ok(_) => Ok(()),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Move bytes from the hardware receive FIFO into the receive buffer until the FIFO is empty.
+    /// Bytes received with a framing, parity, break or overrun error are dropped, as are
+    /// bytes that arrive while the buffer is full.
+    fn drain_receive_fifo(uart_index: usize) -> Result<(), &'static str>{
+        let buffer = &RX_BUFFERS[uart_index];
+
+        while !Flags::read(uart_index)?.receive_fifo_empty(){
+            let data = UARTDR.read(uart_index, UARTDR.bit_width)?.value;
+
+            //Bits 8-11 are the error flags for this byte.
+            if data & 0xF00 == 0{
+                buffer.push(&[data as u8]);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The UART interrupt handler. The context is the UART index.
+    /// There's no one to return an error to here, and panicking would take the whole kernel down
+    /// over one UART, so a failed register access just ends the handler.
+    fn handle_interrupt(uart_index: usize){
+        let status = match UARTMIS.read(uart_index, UARTMIS.bit_width){
+            Ok(result) => result.value,
+            Err(_) => return,
+        };
+
+        if status & (UART_INTERRUPT_RX | UART_INTERRUPT_RX_TIMEOUT) > 0{
+            let _ = Self::drain_receive_fifo(uart_index);
+            let _ = UARTICR.write(uart_index, UART_INTERRUPT_RX | UART_INTERRUPT_RX_TIMEOUT, UARTICR.bit_width);
+
+            //Wake any task blocked in read.
+            RX_WAIT_QUEUES[uart_index].notify_all();
+        }
+
+        if status & UART_INTERRUPT_TX > 0{
+            let _ = UARTICR.write(uart_index, UART_INTERRUPT_TX, UARTICR.bit_width);
+            let fifo_lock = UART_LOCKS[uart_index].lock();
+            let _ = Self::fill_transmit_fifo(uart_index);
+            drop(fifo_lock);
+
+            //There's room in the buffer again, or it has drained.
+            TX_WAIT_QUEUES[uart_index].notify_all();
+        }
+
+        //Only RP1 needs telling before it can raise the interrupt again.
+        if let Ok(UartDevice{ interrupt: UartInterrupt::Rp1(interrupt), .. }) = uart_device(uart_index){
+            rp1::acknowledge_interrupt(*interrupt);
+        }
+    }
+
+    /// The UART's reference clock. RP1's UARTs share one, which the firmware may have set to
+    /// anything, so the clock manager is asked what it is.
+    fn reference_clock(uart_index: usize) -> Result<usize, &'static str>{
+        if let Some(frequency) = uart_device(uart_index)?.clock_frequency{
+            return Ok(frequency);
+        }
+
+        clocks::enable(clocks::Clock::Uart);
+        clocks::peripheral_frequency(clocks::Peripheral::Uart)
+    }
+
+    /// The UART's transmit and receive DMA request lines.
+    fn dma_requests(uart_index: usize) -> Result<(usize, usize), &'static str>{
+        match uart_device(uart_index)?.dma_requests{
+            Some(requests) => Ok(requests),
+            None => Err("The UART isn't wired to a DMA controller we drive."),
         }
     }
 
     /// Create a new instance of the Uart for reading or writing
     pub(in crate::bsp::raspberry_pi_5) fn new(builder: InstanceBuilder) -> Result<UartInstance, &'static str>{
 
+        //First, validate that we have a valid index, and take ownership of the UART.
+        //Nothing touches the hardware until we own it, so a second build can't tear down a live UART.
+        if builder.uart_index >= UART_COUNT {
+            return Err("Invalid UART index");
+        }
+
+        //Check the baud rate is achievable before we disturb anything.
+        let reference_clock = Self::reference_clock(builder.uart_index)?;
+        let divisor = BaudRateDivisor::calculate(reference_clock, builder.baud_rate, builder.baud_rate_tolerance)?;
+
+        let claim = UART_OWNERSHIP.take(builder.uart_index)?;
+
         //Disable the UART, which will flush the FIFO, wait for the last transmit (if we're still running)
         //and disable TX and RX
         match Self::disable_uart(builder.uart_index){
@@ -756,11 +1320,32 @@ impl UartInstance{
             },
         };
 
-        //Now we can validate the values stored within our builder, and then program the UART.
+        //Second, program the line control register and baud rate, and enable the UART.
+        match Self::program(&builder, &divisor){
+            Ok(_) => {},
+            Err(error) => {return Err(error)}
+        }
 
-        //First, validate that we have a valid index.
-        if builder.uart_index >= UART_ADDRESSES.len() {
-            return Err("Invalid UART index");
+        //With the UART running, we can hook up the interrupt that drains the transmit buffer.
+        match Self::configure_interrupts(builder.uart_index){
+            Ok(_) => {},
+            Err(error) => {return Err(error)}
+        }
+
+        Ok(UartInstance{
+            uart_index: builder.uart_index,
+            configuration: builder,
+            divisor,
+            _claim: claim
+        })
+    }
+
+    /// Program a disabled UART with the builder's settings, then enable it.
+    fn program(builder: &InstanceBuilder, divisor: &BaudRateDivisor) -> Result<(), &'static str>{
+        //First, we should set our baud rate. The divisor is latched by the line control write that follows.
+        match Self::set_baud_rate(builder.uart_index, divisor){
+            Ok(_) => {},
+            Err(error) => {return Err(error)}
         }
 
         //Second, we should program our line control register.
@@ -778,21 +1363,62 @@ impl UartInstance{
             }
         };
 
-        //Third, we should set our baud rate.
-        match Self::set_baud_rate(builder.uart_index, builder.baud_rate){
+        //Finally, enable the UART
+        Self::enable_uart(builder.uart_index, builder.transmit_mode)
+    }
+
+    /// The baud rate the UART is actually running at. This differs from the requested rate by baud_rate_error.
+    pub fn achieved_baud_rate(&self) -> usize{
+        self.divisor.achieved
+    }
+
+    /// How far the achieved baud rate is from the requested one, in hundredths of a percent.
+    /// Positive values mean the UART runs fast.
+    pub fn baud_rate_error(&self) -> isize{
+        self.divisor.error
+    }
+
+    /// A builder holding this instance's current settings. Modify it and pass it to reconfigure.
+    pub fn configuration(&self) -> InstanceBuilder{
+        self.configuration
+    }
+
+    /// Change the settings of a running UART. Anything already queued is sent with the old settings first.
+    /// If the new settings can't be applied, the old ones are restored and the error is returned,
+    /// along with why restoring failed if it did.
+    pub fn reconfigure(&mut self, builder: InstanceBuilder) -> Result<(), ReconfigureError>{
+        if builder.uart_index != self.uart_index{
+            return Err("A UART instance can't be reconfigured as a different UART.".into());
+        }
+
+        let reference_clock = Self::reference_clock(self.uart_index)?;
+        let divisor = BaudRateDivisor::calculate(reference_clock, builder.baud_rate, builder.baud_rate_tolerance)?;
+
+        //Let queued data drain under the settings it was written with.
+        self.flush();
+
+        //This is the same sequence as building the instance. Disabling waits for the
+        //last character and flushes the FIFOs, so the UART can be safely reprogrammed.
+        match Self::disable_uart(self.uart_index){
             Ok(_) => {},
-            Err(error) => {return Err(error)}
+            Err(error) => {return Err(error.into())}
         }
 
-        //Finally, enable the UART
-        match Self::enable_uart(builder.uart_index, builder.transmit_mode){
+        match Self::program(&builder, &divisor){
             Ok(_) => {},
-            Err(error) => {return Err(error)}
+            Err(error) => {
+                //Put the old settings back so the instance is still usable.
+                let _ = Self::disable_uart(self.uart_index);
+                let restore_error = Self::program(&self.configuration, &self.divisor).err();
+
+                return Err(ReconfigureError{ error, restore_error });
+            }
         }
 
-        Ok(UartInstance{
-            uart_index: builder.uart_index
-        })
+        self.configuration = builder;
+        self.divisor = divisor;
+
+        Ok(())
     }
 
 
@@ -824,6 +1450,207 @@ impl UartInstance{
 
     }
 
+    /// Queue bytes for interrupt-driven transmission and return immediately.
+    /// Returns the number of bytes queued, which is less than array.len() if the transmit buffer filled up.
+    pub fn write(&self, array: &[u8]) -> Result<usize, &'static str>{
+        //The buffer takes one producer at a time, and writers can be on any task or core.
+        let fifo_lock = UART_LOCKS[self.uart_index].lock();
+        let written = TX_BUFFERS[self.uart_index].push(array);
+
+        //Prime the FIFO ourselves. The TX interrupt only fires as the FIFO drains, so it takes over from here.
+        let result = if written > 0{ Self::fill_transmit_fifo(self.uart_index) } else{ Ok(()) };
+        drop(fifo_lock);
+
+        match result{
+            Ok(_) => {},
+            Err(error) => return Err(error),
+        }
+
+        Ok(written)
+    }
+
+    /// Queue every byte for transmission, blocking whenever the transmit buffer is full.
+    /// Returns once the last byte is queued, not once it has been sent. See flush.
+    pub fn write_all(&self, array: &[u8]) -> Result<(), &'static str>{
+        let mut remaining = array;
+
+        while !remaining.is_empty(){
+            let written = self.write(remaining)?;
+
+            remaining = &remaining[written..];
+
+            if !remaining.is_empty(){
+                TX_WAIT_QUEUES[self.uart_index].wait_until(|| !TX_BUFFERS[self.uart_index].is_full());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Block until every queued byte has left the UART, including the shift register.
+    pub fn flush(&self){
+        TX_WAIT_QUEUES[self.uart_index].wait_until(|| TX_BUFFERS[self.uart_index].is_empty());
+
+        //The buffer is empty, but the FIFO and the shift register may still be draining.
+        //That takes at most a FIFO's worth of characters, so we simply poll the BUSY flag.
+        Self::busy_wait_last_transmit(self.uart_index);
+    }
+
+    /// Transmit a buffer with DMA, without copying it. Returns as soon as the transfer has started.
+    /// Anything queued with write is flushed first, so the two don't interleave.
+    pub fn write_dma(&self, buffer: &'static [u8]) -> Result<UartDmaTransfer<'_, &'static [u8]>, &'static str>{
+        self.flush();
+
+        let mut channel = dma::request_channel()?;
+        let address = buffer.as_ptr() as usize;
+
+        //Make sure the controller sees what we wrote, not whatever was in memory before.
+        cache::clean_range(address, buffer.len());
+
+        let transfer = dma::DmaTransfer{
+            source: address,
+            destination: get_uart_address(self.uart_index)? + UARTDR.offset,
+            length: buffer.len(),
+            direction: dma::DmaDirection::MemoryToPeripheral(Self::dma_requests(self.uart_index)?.0)
+        };
+
+        match channel.start(&transfer){
+            Ok(_) => {},
+            Err(error) => return Err(error),
+        }
+
+        //Only once the channel is running do we let the UART start requesting data.
+        match Self::update_dma_control(self.uart_index, UARTDMACR_TXDMAE, true){
+            Ok(_) => {},
+            Err(error) => return Err(error),
+        }
+
+        Ok(UartDmaTransfer{
+            uart_index: self.uart_index,
+            instance: PhantomData,
+            channel,
+            dma_enable_bit: UARTDMACR_TXDMAE,
+            buffer: Some(buffer),
+            address,
+            length: buffer.len()
+        })
+    }
+
+    /// Receive exactly buffer.len() bytes with DMA. Returns as soon as the transfer has started.
+    /// The buffer should be cache line aligned; lines it shares with other data are invalidated on completion.
+    pub fn read_dma(&self, buffer: &'static mut [u8]) -> Result<UartDmaTransfer<'_, &'static mut [u8]>, &'static str>{
+        let channel = dma::request_channel()?;
+        let address = buffer.as_mut_ptr() as usize;
+        let length = buffer.len();
+
+        let transfer = dma::DmaTransfer{
+            source: get_uart_address(self.uart_index)? + UARTDR.offset,
+            destination: address,
+            length,
+            direction: dma::DmaDirection::PeripheralToMemory(Self::dma_requests(self.uart_index)?.1)
+        };
+
+        //Write back anything dirty now, so a later eviction can't overwrite what the controller writes.
+        cache::clean_and_invalidate_range(address, length);
+
+        //Built before anything is changed, so if a step below fails, dropping it puts the UART back as it was.
+        let mut dma_transfer = UartDmaTransfer{
+            uart_index: self.uart_index,
+            instance: PhantomData,
+            channel,
+            dma_enable_bit: UARTDMACR_RXDMAE,
+            buffer: Some(buffer),
+            address,
+            length
+        };
+
+        //The RX interrupt would race the DMA controller for the FIFO. The transfer unmasks it again when dropped.
+        let lock = UART_LOCKS[self.uart_index].lock();
+        let result = Self::update_interrupt_mask(self.uart_index, UART_INTERRUPT_RX | UART_INTERRUPT_RX_TIMEOUT, false);
+        drop(lock);
+
+        match result{
+            Ok(_) => {},
+            Err(error) => return Err(error),
+        }
+
+        match dma_transfer.channel.start(&transfer){
+            Ok(_) => {},
+            Err(error) => return Err(error),
+        }
+
+        match Self::update_dma_control(self.uart_index, UARTDMACR_RXDMAE, true){
+            Ok(_) => {},
+            Err(error) => return Err(error),
+        }
+
+        Ok(dma_transfer)
+    }
+
+    /// Copy received bytes into the array without blocking. Returns the number of bytes copied, which may be zero.
+    pub fn read(&self, array: &mut [u8]) -> usize{
+        let buffer = &RX_BUFFERS[self.uart_index];
+        let mut count = 0;
+
+        while count < array.len(){
+            match buffer.pop(){
+                Some(byte) => {
+                    array[count] = byte;
+                    count += 1;
+                },
+                None => break,
+            }
+        }
+
+        count
+    }
+
+    /// Block until at least one byte has been received, for at most the timeout if one is given,
+    /// then copy as many received bytes as fit into the array. Returns the number of bytes copied.
+    pub fn read_timeout(&self, array: &mut [u8], timeout: Option<Duration>) -> Result<usize, &'static str>{
+        if array.is_empty(){
+            return Ok(0);
+        }
+
+        if !RX_WAIT_QUEUES[self.uart_index].wait_until_timeout(|| !RX_BUFFERS[self.uart_index].is_empty(), timeout){
+            return Err("Timed out waiting for the UART to receive data.");
+        }
+
+        Ok(self.read(array))
+    }
+
+    /// The number of received bytes waiting to be read.
+    pub fn bytes_available(&self) -> usize{
+        RX_BUFFERS[self.uart_index].len()
+    }
+
+    /// True once every queued byte has been transmitted, and the last bit has left the shift register.
+    pub fn transmit_complete(&self) -> bool{
+        if !TX_BUFFERS[self.uart_index].is_empty(){
+            return false;
+        }
+
+        let flags = self.flags();
+
+        //BUSY stays set until the last stop bit has been sent.
+        flags.transmit_fifo_empty() && !flags.transmit_busy()
+    }
+
 
     //Finally, we should enable our UART.
-}
\ No newline at end of file
+}
+
+impl Drop for UartInstance{
+    /// Drain anything still queued, then disable the UART and its interrupt so the port can be acquired again.
+    fn drop(&mut self){
+        self.flush();
+
+        let _ = UARTIMSC.write(self.uart_index, 0, UARTIMSC.bit_width);
+        let _ = match uart_device(self.uart_index).map(|device| device.interrupt){
+            Ok(UartInterrupt::Rp1(interrupt)) => rp1::disable_interrupt(interrupt),
+            Ok(UartInterrupt::Gic(interrupt_id)) => gic::disable_interrupt(interrupt_id),
+            Err(error) => Err(error),
+        };
+        let _ = Self::disable_uart(self.uart_index);
+    }
+}
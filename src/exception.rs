@@ -0,0 +1,179 @@
+use core::arch::{asm, global_asm};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::kernel::cpu::{self, MAX_CPUS};
+use crate::kernel::{fault, scheduler, syscall};
+
+global_asm!(
+    include_str!("asm/aarch64/vectors.S")
+);
+
+/// The register state saved by the exception vectors. The layout must match vectors.S
+#[repr(C)]
+pub struct TrapFrame{
+    /// General purpose registers x0-x30
+    pub regs: [u64; 31],
+    /// The address execution resumes at once the exception returns
+    pub elr: u64,
+    /// The saved processor state
+    pub spsr: u64,
+    /// The stack pointer of EL0
+    pub sp_el0: u64
+}
+
+/// The kind of exception, as passed in by the vector table. The vector table has
+/// four groups of four (synchronous, IRQ, FIQ, SError) entries, so the low two bits are the type.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ExceptionType{
+    Synchronous,
+    Irq,
+    Fiq,
+    SError
+}
+
+impl ExceptionType{
+    fn from_kind(kind: usize) -> ExceptionType{
+        match kind & 0b11{
+            0 => ExceptionType::Synchronous,
+            1 => ExceptionType::Irq,
+            2 => ExceptionType::Fiq,
+            _ => ExceptionType::SError
+        }
+    }
+}
+
+/// Vector kinds 4-7 are exceptions taken from EL1 while using SP_EL1, which is where tasks run.
+const KIND_CURRENT_EL_SPX: usize = 4;
+
+/// Vector kinds 8-11 are exceptions taken from EL0 tasks.
+const KIND_LOWER_EL_AARCH64: usize = 8;
+
+/// The exception class in ESR_EL1 for an SVC instruction executed in AArch64
+const ESR_EC_SVC64: u64 = 0x15;
+
+/// The exception classes for instruction and data aborts taken without a change in exception level
+const ESR_EC_INSTRUCTION_ABORT_SAME_EL: u64 = 0x21;
+const ESR_EC_DATA_ABORT_SAME_EL: u64 = 0x25;
+
+/// The SVC immediate the scheduler uses to yield. See scheduler::yield_now
+pub const SVC_YIELD: u64 = 0;
+
+/// The number of IRQ handlers currently running on each core.
+static IRQ_DEPTH: [AtomicUsize; MAX_CPUS] = [const { AtomicUsize::new(0) }; MAX_CPUS];
+
+/// Handle an exception. Returns the frame to restore, which belongs to a different task if the scheduler switched.
+#[no_mangle]
+extern "C" fn exception_handler(kind: usize, frame: *mut TrapFrame) -> *mut TrapFrame{
+    let elr = unsafe{ (*frame).elr };
+
+    match ExceptionType::from_kind(kind){
+        ExceptionType::Irq => {
+            let depth = &IRQ_DEPTH[cpu::id()];
+
+            depth.fetch_add(1, Ordering::Relaxed);
+            crate::bsp::handle_irq();
+            depth.fetch_sub(1, Ordering::Relaxed);
+
+            scheduler::switch(frame)
+        },
+        ExceptionType::Synchronous => {
+            let esr: u64;
+            let far: u64;
+            unsafe{
+                asm!("mrs {}, esr_el1", out(reg) esr);
+                asm!("mrs {}, far_el1", out(reg) far);
+            }
+
+            if kind & !0b11 == KIND_CURRENT_EL_SPX && esr >> 26 == ESR_EC_SVC64 && esr & 0xFFFF == SVC_YIELD{
+                return scheduler::yield_from_exception(frame);
+            }
+
+            if kind & !0b11 == KIND_LOWER_EL_AARCH64{
+                if esr >> 26 == ESR_EC_SVC64{
+                    syscall::handle_svc(unsafe{ &mut *frame });
+                    return frame;
+                }
+
+                fault::handle_user_fault(esr, far, elr);
+            }
+
+            let class = esr >> 26;
+            if class == ESR_EC_DATA_ABORT_SAME_EL || class == ESR_EC_INSTRUCTION_ABORT_SAME_EL{
+                if let Some(task) = scheduler::stack_overflow_owner(far as usize){
+                    panic!("Stack overflow in task {}. FAR: {:#x}, ELR: {:#x}", task, far, elr);
+                }
+            }
+
+            panic!("Unhandled synchronous exception. ESR: {:#x}, FAR: {:#x}, ELR: {:#x}", esr, far, elr);
+        },
+        ExceptionType::Fiq => {
+            panic!("Unexpected FIQ at {:#x}", elr);
+        },
+        ExceptionType::SError => {
+            panic!("SError at {:#x}", elr);
+        }
+    }
+}
+
+/// Called by the vectors, on an emergency stack, when an exception frame wouldn't fit on the stack
+/// it was taken on. That only happens when a task has run into its guard page.
+#[no_mangle]
+extern "C" fn stack_overflow_handler(sp: usize, elr: u64) -> !{
+    match scheduler::stack_overflow_owner(sp.saturating_sub(core::mem::size_of::<TrapFrame>())){
+        Some(task) => panic!("Stack overflow in task {}. SP: {:#x}, ELR: {:#x}", task, sp, elr),
+        None => panic!("Stack overflow. SP: {:#x}, ELR: {:#x}", sp, elr),
+    }
+}
+
+/// True while an IRQ handler is running on this core.
+pub fn in_interrupt() -> bool{
+    IRQ_DEPTH[cpu::id()].load(Ordering::Relaxed) > 0
+}
+
+/// True if IRQs are masked on the current core.
+pub fn irqs_masked() -> bool{
+    let daif: usize;
+    unsafe{
+        asm!("mrs {}, daif", out(reg) daif, options(nomem, nostack));
+    }
+    daif & (1 << 7) != 0
+}
+
+/// Unmask IRQs on the current core.
+pub fn local_irq_enable(){
+    unsafe{
+        asm!("msr daifclr, #2", options(nostack));
+    }
+}
+
+/// Mask IRQs on the current core.
+pub fn local_irq_disable(){
+    unsafe{
+        asm!("msr daifset, #2", options(nostack));
+    }
+}
+
+/// Mask IRQs on the current core, returning the previous mask state for local_irq_restore
+pub fn local_irq_save() -> usize{
+    let daif: usize;
+    unsafe{
+        asm!("mrs {}, daif", out(reg) daif, options(nomem, nostack));
+    }
+    local_irq_disable();
+    daif
+}
+
+/// Restore the mask state returned by local_irq_save
+pub fn local_irq_restore(daif: usize){
+    unsafe{
+        asm!("msr daif, {}", in(reg) daif, options(nostack));
+    }
+}
+
+/// Run a closure with IRQs masked on the current core.
+pub fn without_interrupts<F, R>(f: F) -> R where F: FnOnce() -> R{
+    let daif = local_irq_save();
+    let result = f();
+    local_irq_restore(daif);
+    result
+}
@@ -0,0 +1,147 @@
+use crate::memory::frame_allocator::MemoryRegion;
+use crate::memory::mmu;
+
+use super::device_tree::DeviceTree;
+
+/// The most memory ranges BootInfo keeps. Pi 5 trees have one or two.
+pub const MAX_MEMORY_REGIONS: usize = 8;
+
+/// The most reserved regions BootInfo keeps, counting the device tree itself.
+pub const MAX_RESERVED_REGIONS: usize = 16;
+
+/// What the firmware told us at boot, read from the device tree it passed in x0.
+pub struct BootInfo{
+    /// The device tree, if the firmware passed a valid one
+    pub device_tree: Option<DeviceTree<'static>>,
+    memory: [Option<MemoryRegion>; MAX_MEMORY_REGIONS],
+    reserved: [Option<MemoryRegion>; MAX_RESERVED_REGIONS],
+    /// The kernel command line, from /chosen/bootargs
+    pub bootargs: Option<&'static str>,
+    /// The console the firmware chose, from /chosen/stdout-path. Often an alias, like "serial10:115200n8".
+    pub stdout_path: Option<&'static str>,
+    /// Where the firmware loaded the initramfs, from /chosen/linux,initrd-start and linux,initrd-end
+    pub initrd: Option<MemoryRegion>
+}
+
+/// Add a region to the first free slot. Fails if every slot is taken.
+fn push_region(regions: &mut [Option<MemoryRegion>], region: MemoryRegion) -> Result<(), &'static str>{
+    if region.size == 0{
+        return Ok(());
+    }
+
+    match regions.iter_mut().find(|slot| slot.is_none()){
+        Some(slot) => {
+            *slot = Some(region);
+            Ok(())
+        },
+        None => Err("There are no free region slots left."),
+    }
+}
+
+/// Record a region that must be left alone. Forgetting one would let the frame allocator
+/// hand it out, so running out of slots stops the boot instead.
+fn reserve_region(info: &mut BootInfo, region: MemoryRegion){
+    if push_region(&mut info.reserved, region).is_err(){
+        panic!("The device tree reserves more than {} regions", MAX_RESERVED_REGIONS);
+    }
+}
+
+impl BootInfo{
+    pub const fn empty() -> BootInfo{
+        BootInfo{
+            device_tree: None,
+            memory: [None; MAX_MEMORY_REGIONS],
+            reserved: [None; MAX_RESERVED_REGIONS],
+            bootargs: None,
+            stdout_path: None,
+            initrd: None
+        }
+    }
+
+    /// Read the device tree the firmware passed. With no valid tree, everything is left empty.
+    ///
+    /// # Safety
+    /// The address must be 0, or identity mapped memory the firmware left a device tree in, which must never be overwritten.
+    pub unsafe fn from_device_tree(address: usize) -> BootInfo{
+        match DeviceTree::from_address(address){
+            Ok(tree) => BootInfo::from_tree(tree),
+            Err(_) => BootInfo::empty(),
+        }
+    }
+
+    fn from_tree(tree: DeviceTree<'static>) -> BootInfo{
+        let mut info = BootInfo::empty();
+        info.device_tree = Some(tree);
+
+        //Memory nodes are children of the root, so their reg is already a CPU address.
+        let memory_nodes = tree.nodes().filter(|node| {
+            node.depth() == 1 && match node.property("device_type").and_then(|property| property.as_str()){
+                Some(device_type) => device_type == "memory",
+                None => node.base_name() == "memory",
+            }
+        });
+
+        for node in memory_nodes.filter(|node| node.is_enabled()){
+            for entry in node.reg(){
+                //RAM that doesn't fit is just never handed out, which is safe.
+                let _ = push_region(&mut info.memory, MemoryRegion::new(entry.address as usize, entry.size as usize));
+            }
+        }
+
+        reserve_region(&mut info, tree.region());
+
+        for region in tree.memory_reservations(){
+            reserve_region(&mut info, region);
+        }
+
+        if let Some(reserved_memory) = tree.find_node("/reserved-memory"){
+            for node in reserved_memory.children(){
+                for entry in node.reg(){
+                    reserve_region(&mut info, MemoryRegion::new(entry.address as usize, entry.size as usize));
+                }
+            }
+        }
+
+        if let Some(chosen) = tree.find_node("/chosen"){
+            info.bootargs = chosen.property("bootargs").and_then(|property| property.as_str());
+            info.stdout_path = chosen.property("stdout-path")
+                .or_else(|| chosen.property("linux,stdout-path"))
+                .and_then(|property| property.as_str());
+
+            let initrd_start = chosen.property("linux,initrd-start").and_then(|property| property.as_u64());
+            let initrd_end = chosen.property("linux,initrd-end").and_then(|property| property.as_u64());
+
+            if let (Some(start), Some(end)) = (initrd_start, initrd_end){
+                if end > start{
+                    let initrd = MemoryRegion::new(start as usize, (end - start) as usize);
+
+                    info.initrd = Some(initrd);
+                    reserve_region(&mut info, initrd);
+                }
+            }
+        }
+
+        info
+    }
+
+    /// The initramfs the firmware loaded, if it loaded one into memory the kernel maps.
+    pub fn initramfs(&self) -> Option<&'static [u8]>{
+        let initrd = self.initrd?;
+
+        if !mmu::is_mapped(initrd.start) || !mmu::is_mapped(initrd.end() - 1){
+            return None;
+        }
+
+        Some(unsafe{ core::slice::from_raw_parts(initrd.start as *const u8, initrd.size) })
+    }
+
+    /// The RAM the device tree describes.
+    pub fn memory_regions(&self) -> impl Iterator<Item = MemoryRegion> + '_{
+        self.memory.iter().flatten().copied()
+    }
+
+    /// Memory that must be left alone: the device tree itself, the initramfs, and anything the firmware reserved.
+    pub fn reserved_regions(&self) -> impl Iterator<Item = MemoryRegion> + '_{
+        self.reserved.iter().flatten().copied()
+    }
+}
@@ -0,0 +1,122 @@
+use core::arch::asm;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use crate::bsp;
+use crate::memory::{cache, mmu};
+
+use super::scheduler;
+use super::task::TaskStack;
+use super::time;
+
+/// The number of cores the kernel can run on.
+pub const MAX_CPUS: usize = bsp::CORE_COUNT;
+
+/// The core main starts on. It keeps time for the others.
+pub const BOOT_CPU: usize = 0;
+
+/// Each secondary core boots on one of these, and keeps it as its idle task's stack.
+const SECONDARY_STACK_SIZE: usize = 8192;
+
+/// How long a secondary core gets to come online before we give up on it.
+const STARTUP_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Bit n is set once core n is scheduling tasks.
+static ONLINE: AtomicU32 = AtomicU32::new(1 << BOOT_CPU);
+
+#[link_section = ".stacks"]
+static SECONDARY_STACKS: [TaskStack<SECONDARY_STACK_SIZE>; MAX_CPUS - 1] = [const { TaskStack::new() }; MAX_CPUS - 1];
+
+/// The top of the stack each secondary core starts on. Read by boot.S with the caches off.
+#[no_mangle]
+static SECONDARY_STACK_TOPS: [AtomicUsize; MAX_CPUS] = [const { AtomicUsize::new(0) }; MAX_CPUS];
+
+/// The bottom of each secondary core's boot stack. Only read once the core's MMU is on.
+static SECONDARY_STACK_BOTTOMS: [AtomicUsize; MAX_CPUS] = [const { AtomicUsize::new(0) }; MAX_CPUS];
+
+extern "C" {
+    fn secondary_entry();
+}
+
+/// The core we are running on. A task can be moved to another core whenever it is preemptible,
+/// so outside an interrupt handler or a lock this is only a hint.
+pub fn id() -> usize{
+    let mpidr: usize;
+    unsafe{
+        asm!("mrs {}, mpidr_el1", out(reg) mpidr, options(nomem, nostack));
+    }
+
+    //The Cortex-A76 reports its core number in Aff1.
+    (mpidr >> 8) & 0xFF
+}
+
+/// Bit n is set if core n is scheduling tasks.
+pub fn online_mask() -> u32{
+    ONLINE.load(Ordering::Acquire)
+}
+
+pub fn is_online(cpu: usize) -> bool{
+    cpu < MAX_CPUS && online_mask() & (1 << cpu) != 0
+}
+
+pub fn online_count() -> usize{
+    online_mask().count_ones() as usize
+}
+
+pub(in crate::kernel) fn set_online(cpu: usize){
+    ONLINE.fetch_or(1 << cpu, Ordering::Release);
+}
+
+/// Start every secondary core. Each one joins the scheduler with an idle task of its own, and
+/// takes tasks from the other cores from then on. Returns the number of cores online.
+/// Must be called after scheduler::start.
+pub fn start_secondary_cores() -> Result<usize, &'static str>{
+    if !scheduler::is_running(){
+        return Err("The scheduler must be running before the secondary cores start.");
+    }
+
+    for cpu in (0..MAX_CPUS).filter(|cpu| *cpu != BOOT_CPU && !is_online(*cpu)){
+        let stack = match SECONDARY_STACKS[cpu - 1].take(){
+            Some(stack) => stack,
+            None => return Err("A secondary core was already started, but never came online."),
+        };
+
+        let bottom = stack.as_mut_ptr() as usize;
+        let top = (bottom + stack.len()) & !0xF;
+
+        SECONDARY_STACK_BOTTOMS[cpu].store(bottom, Ordering::Relaxed);
+        SECONDARY_STACK_TOPS[cpu].store(top, Ordering::Relaxed);
+
+        //The core reads its stack top, and writes its first frames, with its caches off. Push the
+        //top out to memory, and drop any lines we hold for the stack so they can't hide its writes.
+        cache::clean_range(&SECONDARY_STACK_TOPS[cpu] as *const AtomicUsize as usize, core::mem::size_of::<usize>());
+        cache::clean_and_invalidate_range(bottom, stack.len());
+
+        bsp::start_core(cpu, secondary_entry as *const () as usize)?;
+
+        let deadline = time::counter().saturating_add(time::duration_to_counter_cycles(STARTUP_TIMEOUT));
+
+        while !is_online(cpu){
+            if time::counter() >= deadline{
+                return Err("A secondary core didn't come online.");
+            }
+
+            core::hint::spin_loop();
+        }
+    }
+
+    Ok(online_count())
+}
+
+/// Where secondary cores enter Rust, from boot.S, on their boot stacks. They turn on their MMU
+/// and interrupt controller interface, then become their idle task.
+#[no_mangle]
+extern "C" fn secondary_main(cpu: usize) -> !{
+    mmu::init_secondary();
+    bsp::init_secondary();
+
+    let bottom = SECONDARY_STACK_BOTTOMS[cpu].load(Ordering::Relaxed);
+    let top = SECONDARY_STACK_TOPS[cpu].load(Ordering::Relaxed);
+
+    scheduler::start_secondary(cpu, bottom, top)
+}
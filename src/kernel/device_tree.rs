@@ -0,0 +1,620 @@
+use crate::memory::frame_allocator::MemoryRegion;
+use crate::memory::mmu;
+
+/// The magic number every flattened device tree starts with.
+const FDT_MAGIC: u32 = 0xD00D_FEED;
+
+/// The oldest structure version we can read. Version 17 is current.
+const FDT_MINIMUM_VERSION: u32 = 16;
+
+const HEADER_SIZE: usize = 40;
+
+//Structure block tokens
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+/// The deepest nesting of nodes we follow. Real trees are rarely more than five deep.
+const MAX_DEPTH: usize = 16;
+
+//The cell counts a node's children get when it doesn't say
+const DEFAULT_ADDRESS_CELLS: u32 = 2;
+const DEFAULT_SIZE_CELLS: u32 = 1;
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32>{
+    let bytes = data.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Read a value made of cells, most significant first. Values over two cells keep the low 64 bits.
+fn read_cells(data: &[u8], offset: usize, cells: u32) -> Option<u64>{
+    let mut value: u64 = 0;
+
+    for cell in 0..cells as usize{
+        value = value.checked_shl(32).unwrap_or(0) | read_u32(data, offset + cell * 4)? as u64;
+    }
+
+    Some(value)
+}
+
+/// A string terminated by a nul, starting at offset.
+fn read_string(data: &[u8], offset: usize) -> Option<&str>{
+    let bytes = data.get(offset..)?;
+    let length = bytes.iter().position(|byte| *byte == 0)?;
+
+    core::str::from_utf8(&bytes[..length]).ok()
+}
+
+fn align_to_cell(offset: usize) -> usize{
+    offset.next_multiple_of(4)
+}
+
+/// A flattened device tree, read in place. Nothing is copied or allocated, so everything
+/// handed out borrows from the blob.
+#[derive(Clone, Copy)]
+pub struct DeviceTree<'a>{
+    data: &'a [u8],
+    structure: &'a [u8],
+    strings: &'a [u8],
+    reservations: usize
+}
+
+/// A token in the structure block.
+enum Token<'a>{
+    BeginNode(&'a str),
+    EndNode,
+    Property(Property<'a>),
+    Nop,
+    End
+}
+
+/// A property's name and raw value.
+#[derive(Clone, Copy)]
+pub struct Property<'a>{
+    pub name: &'a str,
+    pub value: &'a [u8]
+}
+
+impl<'a> Property<'a>{
+    /// The value as a single string.
+    pub fn as_str(&self) -> Option<&'a str>{
+        let value = self.value.strip_suffix(&[0])?;
+        core::str::from_utf8(value).ok()
+    }
+
+    /// The value as a list of strings, like compatible.
+    pub fn strings(&self) -> impl Iterator<Item = &'a str>{
+        let value = self.value.strip_suffix(&[0]).unwrap_or(&[]);
+
+        value.split(|byte| *byte == 0).filter_map(|string| core::str::from_utf8(string).ok())
+    }
+
+    pub fn as_u32(&self) -> Option<u32>{
+        match self.value.len(){
+            4 => read_u32(self.value, 0),
+            _ => None,
+        }
+    }
+
+    /// A one or two cell value.
+    pub fn as_u64(&self) -> Option<u64>{
+        match self.value.len(){
+            4 => read_cells(self.value, 0, 1),
+            8 => read_cells(self.value, 0, 2),
+            _ => None,
+        }
+    }
+
+    /// The value as a list of cells.
+    pub fn cells(&self) -> impl Iterator<Item = u32> + 'a{
+        let value = self.value;
+        (0..value.len() / 4).filter_map(move |cell| read_u32(value, cell * 4))
+    }
+}
+
+/// What a node inherits from its parents.
+#[derive(Clone, Copy)]
+struct Context{
+    /// The node's BEGIN_NODE token
+    offset: usize,
+    /// The cell counts the node's children use
+    address_cells: u32,
+    size_cells: u32,
+    /// The phandle of the interrupt controller the node's interrupts go to
+    interrupt_parent: Option<u32>
+}
+
+/// A node in the tree.
+#[derive(Clone, Copy)]
+pub struct Node<'a>{
+    tree: DeviceTree<'a>,
+    name: &'a str,
+    /// The BEGIN_NODE token
+    offset: usize,
+    /// The first token after the node's name
+    content: usize,
+    /// The root is at depth zero
+    depth: usize,
+    parent: Option<usize>,
+    /// The cell counts the node's reg property uses, from its parent
+    address_cells: u32,
+    size_cells: u32,
+    interrupt_parent: Option<u32>
+}
+
+/// An address range from a reg property.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct RegEntry{
+    pub address: u64,
+    pub size: u64
+}
+
+/// One interrupt from an interrupts property, as the cells its controller defines.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Interrupt{
+    /// The controller's phandle
+    pub controller: Option<u32>,
+    pub cells: [u32; 4],
+    pub cell_count: usize
+}
+
+impl Interrupt{
+    /// The GIC interrupt ID, for an interrupt whose controller is a GIC. The first cell is 0 for
+    /// an SPI, numbered from 32, or 1 for a PPI, numbered from 16.
+    pub fn gic_id(&self) -> Option<usize>{
+        if self.cell_count < 2{
+            return None;
+        }
+
+        match self.cells[0]{
+            0 => Some(self.cells[1] as usize + 32),
+            1 => Some(self.cells[1] as usize + 16),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> DeviceTree<'a>{
+    /// Check the header, and find the blocks it points to.
+    pub fn from_bytes(data: &'a [u8]) -> Result<DeviceTree<'a>, &'static str>{
+        let field = |index: usize| read_u32(data, index * 4).ok_or("The device tree is truncated.");
+
+        if field(0)? != FDT_MAGIC{
+            return Err("The device tree has the wrong magic number.");
+        }
+
+        let total_size = field(1)? as usize;
+        let structure_offset = field(2)? as usize;
+        let strings_offset = field(3)? as usize;
+        let reservations = field(4)? as usize;
+        let last_compatible_version = field(6)?;
+        let strings_size = field(8)? as usize;
+        let structure_size = field(9)? as usize;
+
+        if last_compatible_version > 17 || field(5)? < FDT_MINIMUM_VERSION{
+            return Err("The device tree version isn't supported.");
+        }
+
+        let data = data.get(..total_size).ok_or("The device tree is truncated.")?;
+        let structure = data.get(structure_offset..structure_offset.saturating_add(structure_size)).ok_or("The device tree structure block is out of bounds.")?;
+        let strings = data.get(strings_offset..strings_offset.saturating_add(strings_size)).ok_or("The device tree strings block is out of bounds.")?;
+
+        Ok(DeviceTree{
+            data,
+            structure,
+            strings,
+            reservations
+        })
+    }
+
+    /// Read the device tree at a physical address, as passed by the firmware.
+    ///
+    /// # Safety
+    /// The address must be identity mapped, and the tree must stay in place and unchanged for as long as it is used.
+    pub unsafe fn from_address(address: usize) -> Result<DeviceTree<'static>, &'static str>{
+        if address == 0 || !address.is_multiple_of(8) || !mmu::is_mapped(address){
+            return Err("The device tree address is invalid.");
+        }
+
+        let header = core::slice::from_raw_parts(address as *const u8, HEADER_SIZE);
+        let total_size = read_u32(header, 4).ok_or("The device tree is truncated.")? as usize;
+
+        if read_u32(header, 0) != Some(FDT_MAGIC) || total_size < HEADER_SIZE{
+            return Err("There is no device tree at the address.");
+        }
+
+        if !mmu::is_mapped(address + total_size - 1){
+            return Err("The device tree isn't mapped.");
+        }
+
+        DeviceTree::from_bytes(core::slice::from_raw_parts(address as *const u8, total_size))
+    }
+
+    /// The memory the blob itself takes up.
+    pub fn region(&self) -> MemoryRegion{
+        MemoryRegion::new(self.data.as_ptr() as usize, self.data.len())
+    }
+
+    fn token(&self, offset: usize) -> Option<(Token<'a>, usize)>{
+        let structure = self.structure;
+        let next = offset + 4;
+
+        match read_u32(structure, offset)?{
+            FDT_BEGIN_NODE => {
+                let name = read_string(structure, next)?;
+                Some((Token::BeginNode(name), align_to_cell(next + name.len() + 1)))
+            },
+            FDT_END_NODE => Some((Token::EndNode, next)),
+            FDT_PROP => {
+                let length = read_u32(structure, next)? as usize;
+                let name = read_string(self.strings, read_u32(structure, next + 4)? as usize)?;
+                let value = structure.get(next + 8..next + 8 + length)?;
+
+                Some((Token::Property(Property{ name, value }), align_to_cell(next + 8 + length)))
+            },
+            FDT_NOP => Some((Token::Nop, next)),
+            FDT_END => Some((Token::End, next)),
+            _ => None,
+        }
+    }
+
+    /// The entries of the memory reservation block, which the firmware marks as off limits.
+    pub fn memory_reservations(&self) -> impl Iterator<Item = MemoryRegion> + 'a{
+        let data = self.data;
+        let mut offset = self.reservations;
+
+        core::iter::from_fn(move || {
+            let address = read_cells(data, offset, 2)?;
+            let size = read_cells(data, offset + 8, 2)?;
+            offset += 16;
+
+            match (address, size){
+                (_, 0) => None,
+                _ => Some(MemoryRegion::new(address as usize, size as usize)),
+            }
+        })
+    }
+
+    /// Every node, depth first, starting with the root.
+    pub fn nodes(&self) -> NodeIter<'a>{
+        NodeIter{
+            tree: *self,
+            offset: 0,
+            stack: [Context{ offset: 0, address_cells: DEFAULT_ADDRESS_CELLS, size_cells: DEFAULT_SIZE_CELLS, interrupt_parent: None }; MAX_DEPTH],
+            depth: 0,
+            done: false
+        }
+    }
+
+    pub fn root(&self) -> Option<Node<'a>>{
+        self.nodes().next()
+    }
+
+    /// Find a node by its full path, like "/soc/serial@7d001000". A component without a unit
+    /// address matches a node with one, so "/memory" finds "/memory@0".
+    pub fn find_node(&self, path: &str) -> Option<Node<'a>>{
+        let mut components = path.split('/').filter(|component| !component.is_empty());
+        let mut wanted = match components.next(){
+            Some(component) => component,
+            None => return self.root(),
+        };
+        let mut matched = 0;
+
+        for node in self.nodes().skip(1){
+            if node.depth <= matched{
+                //We've left the subtree the path runs through.
+                return None;
+            }
+
+            if node.depth == matched + 1 && node.name_matches(wanted){
+                matched += 1;
+
+                wanted = match components.next(){
+                    Some(component) => component,
+                    None => return Some(node),
+                };
+            }
+        }
+
+        None
+    }
+
+    /// Find the node a phandle refers to.
+    pub fn find_phandle(&self, phandle: u32) -> Option<Node<'a>>{
+        self.nodes().find(|node| node.phandle() == Some(phandle))
+    }
+
+    /// Every node compatible with the given string.
+    pub fn compatible_nodes(&self, compatible: &'a str) -> impl Iterator<Item = Node<'a>> + 'a{
+        self.nodes().filter(move |node| node.is_compatible(compatible))
+    }
+
+    /// Find a node from the path in an alias or a property like stdout-path. Anything after a
+    /// colon, like the serial settings in "serial0:115200n8", is ignored.
+    pub fn resolve_path(&self, path: &str) -> Option<Node<'a>>{
+        let path = path.split(':').next()?;
+
+        if path.starts_with('/'){
+            return self.find_node(path);
+        }
+
+        let alias = self.find_node("/aliases")?.property(path)?.as_str()?;
+        self.find_node(alias)
+    }
+}
+
+/// Walks every node in the tree, depth first, keeping track of what each one inherits.
+pub struct NodeIter<'a>{
+    tree: DeviceTree<'a>,
+    offset: usize,
+    stack: [Context; MAX_DEPTH],
+    /// The number of nodes we are inside
+    depth: usize,
+    done: bool
+}
+
+impl<'a> Iterator for NodeIter<'a>{
+    type Item = Node<'a>;
+
+    fn next(&mut self) -> Option<Node<'a>>{
+        while !self.done{
+            let (token, next) = match self.tree.token(self.offset){
+                Some(token) => token,
+                None => {
+                    self.done = true;
+                    return None;
+                },
+            };
+
+            let offset = self.offset;
+            self.offset = next;
+
+            match token{
+                Token::BeginNode(name) => {
+                    if self.depth == MAX_DEPTH{
+                        self.done = true;
+                        return None;
+                    }
+
+                    let parent = match self.depth{
+                        0 => None,
+                        depth => Some(self.stack[depth - 1]),
+                    };
+
+                    let (address_cells, size_cells, interrupt_parent) = match parent{
+                        Some(parent) => (parent.address_cells, parent.size_cells, parent.interrupt_parent),
+                        None => (DEFAULT_ADDRESS_CELLS, DEFAULT_SIZE_CELLS, None),
+                    };
+
+                    let mut node = Node{
+                        tree: self.tree,
+                        name,
+                        offset,
+                        content: next,
+                        depth: self.depth,
+                        parent: parent.map(|parent| parent.offset),
+                        address_cells,
+                        size_cells,
+                        interrupt_parent
+                    };
+
+                    //interrupt-parent applies to the node itself as well as its children.
+                    if let Some(phandle) = node.property("interrupt-parent").and_then(|property| property.as_u32()){
+                        node.interrupt_parent = Some(phandle);
+                    }
+
+                    self.stack[self.depth] = Context{
+                        offset,
+                        address_cells: node.child_address_cells(),
+                        size_cells: node.child_size_cells(),
+                        interrupt_parent: node.interrupt_parent
+                    };
+                    self.depth += 1;
+
+                    return Some(node);
+                },
+                Token::EndNode => {
+                    self.depth = self.depth.saturating_sub(1);
+
+                    if self.depth == 0{
+                        self.done = true;
+                    }
+                },
+                Token::End => self.done = true,
+                Token::Property(_) | Token::Nop => {},
+            }
+        }
+
+        None
+    }
+}
+
+/// Two nodes are the same if they are the same place in the same blob.
+impl PartialEq for Node<'_>{
+    fn eq(&self, other: &Self) -> bool{
+        self.offset == other.offset && core::ptr::eq(self.tree.data, other.tree.data)
+    }
+}
+
+impl<'a> Node<'a>{
+    /// The tree the node is in.
+    pub fn tree(&self) -> DeviceTree<'a>{
+        self.tree
+    }
+
+    /// The full name, including any unit address, like "serial@7d001000". The root's is empty.
+    pub fn name(&self) -> &'a str{
+        self.name
+    }
+
+    /// The name without its unit address.
+    pub fn base_name(&self) -> &'a str{
+        self.name.split('@').next().unwrap_or(self.name)
+    }
+
+    pub fn depth(&self) -> usize{
+        self.depth
+    }
+
+    fn name_matches(&self, wanted: &str) -> bool{
+        self.name == wanted || (!wanted.contains('@') && self.base_name() == wanted)
+    }
+
+    /// The node's own properties, not its children's.
+    pub fn properties(&self) -> impl Iterator<Item = Property<'a>> + 'a{
+        let tree = self.tree;
+        let mut offset = self.content;
+
+        core::iter::from_fn(move || {
+            loop{
+                let (token, next) = tree.token(offset)?;
+                offset = next;
+
+                match token{
+                    Token::Property(property) => return Some(property),
+                    Token::Nop => {},
+                    _ => return None,
+                }
+            }
+        })
+    }
+
+    pub fn property(&self, name: &str) -> Option<Property<'a>>{
+        self.properties().find(|property| property.name == name)
+    }
+
+    pub fn phandle(&self) -> Option<u32>{
+        self.property("phandle")
+            .or_else(|| self.property("linux,phandle"))
+            .and_then(|property| property.as_u32())
+    }
+
+    pub fn compatible(&self) -> impl Iterator<Item = &'a str> + 'a{
+        self.property("compatible").into_iter().flat_map(|property| property.strings())
+    }
+
+    pub fn is_compatible(&self, compatible: &str) -> bool{
+        self.compatible().any(|entry| entry == compatible)
+    }
+
+    /// False if the status property says the device is disabled.
+    pub fn is_enabled(&self) -> bool{
+        match self.property("status").and_then(|property| property.as_str()){
+            Some(status) => status == "okay" || status == "ok",
+            None => true,
+        }
+    }
+
+    fn child_address_cells(&self) -> u32{
+        self.property("#address-cells").and_then(|property| property.as_u32()).unwrap_or(DEFAULT_ADDRESS_CELLS)
+    }
+
+    fn child_size_cells(&self) -> u32{
+        self.property("#size-cells").and_then(|property| property.as_u32()).unwrap_or(DEFAULT_SIZE_CELLS)
+    }
+
+    /// The node's direct children.
+    pub fn children(&self) -> impl Iterator<Item = Node<'a>> + 'a{
+        let (offset, depth) = (self.offset, self.depth);
+
+        self.tree.nodes()
+            .skip_while(move |node| node.offset != offset)
+            .skip(1)
+            .take_while(move |node| node.depth > depth)
+            .filter(move |node| node.depth == depth + 1)
+    }
+
+    pub fn parent(&self) -> Option<Node<'a>>{
+        let parent = self.parent?;
+        self.tree.nodes().find(|node| node.offset == parent)
+    }
+
+    /// The address ranges in the reg property, in the parent bus's address space.
+    pub fn reg(&self) -> impl Iterator<Item = RegEntry> + 'a{
+        let value = self.property("reg").map_or(&[][..], |property| property.value);
+        let (address_cells, size_cells) = (self.address_cells, self.size_cells);
+        let entry_size = (address_cells + size_cells) as usize * 4;
+
+        (0..value.len().checked_div(entry_size).unwrap_or(0)).filter_map(move |index| {
+            let offset = index * entry_size;
+
+            Some(RegEntry{
+                address: read_cells(value, offset, address_cells)?,
+                size: read_cells(value, offset + address_cells as usize * 4, size_cells)?
+            })
+        })
+    }
+
+    /// Translate an address on this node's bus to a CPU physical address, through every
+    /// parent's ranges property. None if some bus doesn't map it.
+    pub fn translate_address(&self, address: u64) -> Option<u64>{
+        let mut address = address;
+        let mut bus = self.parent()?;
+
+        //The root's children are already in the CPU's address space.
+        while bus.parent.is_some(){
+            let ranges = bus.property("ranges")?;
+
+            if !ranges.value.is_empty(){
+                let child_cells = bus.child_address_cells();
+                let parent_cells = bus.address_cells;
+                let size_cells = bus.child_size_cells();
+                let entry_size = (child_cells + parent_cells + size_cells) as usize * 4;
+
+                address = (0..ranges.value.len().checked_div(entry_size).unwrap_or(0)).find_map(|index| {
+                    let offset = index * entry_size;
+                    let child = read_cells(ranges.value, offset, child_cells)?;
+                    let parent = read_cells(ranges.value, offset + child_cells as usize * 4, parent_cells)?;
+                    let size = read_cells(ranges.value, offset + (child_cells + parent_cells) as usize * 4, size_cells)?;
+
+                    match address.checked_sub(child){
+                        Some(delta) if delta < size => Some(parent + delta),
+                        _ => None,
+                    }
+                })?;
+            }
+
+            bus = bus.parent()?;
+        }
+
+        Some(address)
+    }
+
+    /// The reg ranges, translated to CPU physical addresses. Entries a bus doesn't map are left out.
+    pub fn mmio_regions(&self) -> impl Iterator<Item = MemoryRegion> + 'a{
+        let node = *self;
+
+        self.reg().filter_map(move |entry| {
+            let address = node.translate_address(entry.address)?;
+            Some(MemoryRegion::new(address as usize, entry.size as usize))
+        })
+    }
+
+    /// The node's interrupts, split up by its interrupt controller's #interrupt-cells.
+    pub fn interrupts(&self) -> impl Iterator<Item = Interrupt> + 'a{
+        let value = self.property("interrupts").map_or(&[][..], |property| property.value);
+        let controller = self.interrupt_parent;
+
+        let cell_count = controller
+            .and_then(|phandle| self.tree.find_phandle(phandle))
+            .and_then(|controller| controller.property("#interrupt-cells"))
+            .and_then(|property| property.as_u32())
+            .map_or(1, |cells| cells.clamp(1, 4) as usize);
+
+        (0..value.len() / (cell_count * 4)).map(move |index| {
+            let mut cells = [0; 4];
+
+            for (cell, slot) in cells.iter_mut().enumerate().take(cell_count){
+                *slot = read_u32(value, (index * cell_count + cell) * 4).unwrap_or(0);
+            }
+
+            Interrupt{
+                controller,
+                cells,
+                cell_count
+            }
+        })
+    }
+}
@@ -0,0 +1,192 @@
+use crate::memory::frame_allocator::MemoryRegion;
+
+use super::device_tree::{DeviceTree, Interrupt, Node};
+
+/// The most of each kind of resource a Device carries. Anything past these is still in the node.
+pub const MAX_DEVICE_REGS: usize = 4;
+pub const MAX_DEVICE_INTERRUPTS: usize = 4;
+pub const MAX_DEVICE_CLOCKS: usize = 4;
+
+/// A reference to a clock, from a clocks property: the provider's phandle, and the cells it defines.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ClockSpecifier{
+    pub provider: u32,
+    pub cells: [u32; 2],
+    pub cell_count: usize
+}
+
+/// A device tree node a driver matched, with its resources read out.
+pub struct Device{
+    pub node: Node<'static>,
+    /// The reg ranges, as CPU physical addresses
+    pub regs: [Option<MemoryRegion>; MAX_DEVICE_REGS],
+    pub interrupts: [Option<Interrupt>; MAX_DEVICE_INTERRUPTS],
+    pub clocks: [Option<ClockSpecifier>; MAX_DEVICE_CLOCKS]
+}
+
+/// A driver, declared with the driver! macro. It is probed once for every enabled device tree
+/// node with one of its compatible strings.
+#[repr(C)]
+pub struct Driver{
+    pub name: &'static str,
+    pub compatible: &'static [&'static str],
+    pub probe: fn(&Device) -> Result<(), &'static str>
+}
+
+/// What probe_devices did.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct ProbeSummary{
+    /// Nodes a driver accepted
+    pub bound: usize,
+    /// Nodes a driver matched but failed to probe
+    pub failed: usize,
+    /// The error from the first failed probe
+    pub first_error: Option<&'static str>
+}
+
+extern "C" {
+    static __drivers_start: u8;
+    static __drivers_end: u8;
+}
+
+/// Every driver declared with the driver! macro, as collected by the linker.
+pub fn drivers() -> &'static [Driver]{
+    unsafe{
+        let start = &__drivers_start as *const u8 as usize;
+        let end = &__drivers_end as *const u8 as usize;
+        let count = (end - start) / core::mem::size_of::<Driver>();
+
+        core::slice::from_raw_parts(start as *const Driver, count)
+    }
+}
+
+/// Fill the slots from an iterator, dropping anything that doesn't fit.
+fn collect<T: Copy, const N: usize>(items: impl Iterator<Item = T>) -> [Option<T>; N]{
+    let mut slots = [None; N];
+
+    for (slot, item) in slots.iter_mut().zip(items){
+        *slot = Some(item);
+    }
+
+    slots
+}
+
+/// The clocks property, split up by each provider's #clock-cells.
+fn clock_specifiers(tree: &DeviceTree<'static>, node: &Node<'static>) -> impl Iterator<Item = ClockSpecifier>{
+    let mut cells = node.property("clocks").into_iter().flat_map(|property| property.cells());
+    let tree = *tree;
+
+    core::iter::from_fn(move || {
+        let provider = cells.next()?;
+
+        let cell_count = tree.find_phandle(provider)
+            .and_then(|provider| provider.property("#clock-cells"))
+            .and_then(|property| property.as_u32())
+            .map_or(0, |count| count.min(2) as usize);
+
+        let mut specifier = ClockSpecifier{ provider, cells: [0; 2], cell_count };
+
+        for cell in specifier.cells.iter_mut().take(cell_count){
+            *cell = cells.next()?;
+        }
+
+        Some(specifier)
+    })
+}
+
+impl Device{
+    fn from_node(tree: &DeviceTree<'static>, node: Node<'static>) -> Device{
+        Device{
+            node,
+            regs: collect(node.mmio_regions()),
+            interrupts: collect(node.interrupts()),
+            clocks: collect(clock_specifiers(tree, &node))
+        }
+    }
+
+    pub fn reg(&self, index: usize) -> Option<MemoryRegion>{
+        self.regs.get(index).copied().flatten()
+    }
+
+    pub fn interrupt(&self, index: usize) -> Option<Interrupt>{
+        self.interrupts.get(index).copied().flatten()
+    }
+
+    /// The node of the interrupt controller an interrupt goes to.
+    pub fn interrupt_controller(&self, index: usize) -> Option<Node<'static>>{
+        let phandle = self.interrupt(index)?.controller?;
+        self.node.tree().find_phandle(phandle)
+    }
+
+    /// The rate of a clock with a fixed frequency, from its provider's clock-frequency. None for
+    /// clocks that a clock driver controls.
+    pub fn fixed_clock_frequency(&self, index: usize) -> Option<u64>{
+        let clock = self.clocks.get(index).copied().flatten()?;
+        let provider = self.node.tree().find_phandle(clock.provider)?;
+
+        provider.property("clock-frequency")?.as_u64()
+    }
+
+    /// The number in an alias for this node, like 2 for "serial2", if it has one with the given prefix.
+    pub fn alias_index(&self, prefix: &str) -> Option<usize>{
+        let tree = self.node.tree();
+        let aliases = tree.find_node("/aliases")?;
+
+        aliases.properties().find_map(|alias| {
+            let index = alias.name.strip_prefix(prefix)?.parse::<usize>().ok()?;
+            let target = tree.find_node(alias.as_str()?)?;
+
+            (target == self.node).then_some(index)
+        })
+    }
+}
+
+/// The driver for a node. The node's compatible strings go from most to least specific, so
+/// the first one any driver claims wins.
+fn find_driver(node: &Node<'static>) -> Option<&'static Driver>{
+    node.compatible().find_map(|compatible| {
+        drivers().iter().find(|driver| driver.compatible.contains(&compatible))
+    })
+}
+
+/// Probe a driver for every enabled node that one claims.
+pub fn probe_devices(tree: &DeviceTree<'static>) -> ProbeSummary{
+    let mut summary = ProbeSummary::default();
+
+    for node in tree.nodes().filter(|node| node.is_enabled()){
+        let driver = match find_driver(&node){
+            Some(driver) => driver,
+            None => continue,
+        };
+
+        match (driver.probe)(&Device::from_node(tree, node)){
+            Ok(_) => summary.bound += 1,
+            Err(error) => {
+                summary.failed += 1;
+                summary.first_error.get_or_insert(error);
+            },
+        }
+    }
+
+    summary
+}
+
+/// Declare a driver. It is probed for every matching device tree node when main calls probe_devices.
+///
+/// ```ignore
+/// driver!(PL011_UART, compatible = ["arm,pl011-axi", "arm,pl011"], probe = probe);
+/// ```
+#[macro_export]
+macro_rules! driver{
+    ($name:ident, compatible = [$($compatible:expr),+ $(,)?], probe = $probe:path) => {
+        #[used]
+        #[link_section = ".drivers"]
+        static $name: $crate::kernel::driver::Driver = $crate::kernel::driver::Driver{
+            name: stringify!($name),
+            compatible: &[$($compatible),+],
+            probe: $probe
+        };
+    };
+}
+
+pub use driver;
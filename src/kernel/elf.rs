@@ -0,0 +1,492 @@
+use crate::memory::address_space::{Access, AddressSpace, Region};
+use crate::memory::cache;
+use crate::memory::frame_allocator::{self, MemoryRegion};
+use crate::memory::mmu::PAGE_SIZE;
+
+use super::initramfs;
+use super::scheduler;
+use super::task::TaskId;
+
+/// The most PT_LOAD segments an image can have.
+pub const MAX_SEGMENTS: usize = 8;
+
+/// The most arguments a loaded program can be given, including its name.
+pub const MAX_ARGUMENTS: usize = 16;
+
+/// The size of a loaded program's stack, which holds its arguments too.
+pub const USER_STACK_SIZE: usize = 64 * 1024;
+
+/// The largest image spawn_received takes.
+pub const MAX_RECEIVED_SIZE: usize = 16 * 1024 * 1024;
+
+//The ELF header
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const ELF_CLASS_64: u8 = 2;
+const ELF_DATA_LITTLE_ENDIAN: u8 = 1;
+const ELF_VERSION_CURRENT: u8 = 1;
+const ELF_HEADER_SIZE: usize = 64;
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+const EM_AARCH64: u16 = 183;
+
+//Program headers
+const PROGRAM_HEADER_SIZE: usize = 56;
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PT_INTERP: u32 = 3;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+//Dynamic section tags
+const DT_NULL: u64 = 0;
+const DT_RELA: u64 = 7;
+const DT_RELASZ: u64 = 8;
+const DT_RELAENT: u64 = 9;
+const DT_REL: u64 = 17;
+const DT_RELRSZ: u64 = 35;
+const DT_RELR: u64 = 36;
+const DT_RELRENT: u64 = 37;
+
+//Relocations
+const RELA_SIZE: usize = 24;
+const R_AARCH64_NONE: u32 = 0;
+const R_AARCH64_RELATIVE: u32 = 1027;
+
+/// A PT_LOAD segment.
+#[derive(Clone, Copy, Default)]
+struct Segment{
+    offset: usize,
+    virtual_address: usize,
+    file_size: usize,
+    memory_size: usize,
+    flags: u32
+}
+
+/// What the headers say about an image, once they've been checked.
+struct Layout{
+    segments: [Segment; MAX_SEGMENTS],
+    segment_count: usize,
+    /// The virtual address of the PT_DYNAMIC segment, if there is one
+    dynamic: Option<usize>,
+    entry: usize,
+    /// The first page of the lowest segment
+    start: usize,
+    /// The size of the span of pages every segment fits in
+    size: usize,
+    alignment: usize
+}
+
+/// The image once it's been copied into frames it will run from.
+struct LoadedImage<'a>{
+    memory: &'a mut [u8],
+    /// The virtual address the image was linked at that the start of memory corresponds to
+    start: usize
+}
+
+fn read<const N: usize>(bytes: &[u8], offset: usize) -> Option<[u8; N]>{
+    bytes.get(offset..offset.checked_add(N)?)?.try_into().ok()
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16>{
+    read(bytes, offset).map(u16::from_le_bytes)
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32>{
+    read(bytes, offset).map(u32::from_le_bytes)
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Option<usize>{
+    read(bytes, offset).map(|value| u64::from_le_bytes(value) as usize)
+}
+
+impl Segment{
+    fn end(&self) -> Option<usize>{
+        self.virtual_address.checked_add(self.memory_size)
+    }
+
+    fn access(&self) -> Access{
+        if self.flags & PF_X != 0{
+            Access::Execute
+        } else if self.flags & PF_W != 0{
+            Access::ReadWrite
+        } else{
+            Access::ReadOnly
+        }
+    }
+}
+
+impl Layout{
+    /// Check the ELF header and program headers of an AArch64 position independent executable.
+    fn parse(image: &[u8]) -> Result<Layout, &'static str>{
+        let truncated = "The image is truncated.";
+
+        if image.len() < ELF_HEADER_SIZE || &image[..4] != ELF_MAGIC{
+            return Err("The image isn't an ELF file.");
+        }
+
+        if image[4] != ELF_CLASS_64 || image[5] != ELF_DATA_LITTLE_ENDIAN || image[6] != ELF_VERSION_CURRENT{
+            return Err("The image isn't a little endian ELF64 file.");
+        }
+
+        match read_u16(image, 16).ok_or(truncated)?{
+            ET_DYN => {},
+            ET_EXEC => return Err("The image isn't position independent. Link it with -pie."),
+            _ => return Err("The image isn't an executable."),
+        }
+
+        if read_u16(image, 18).ok_or(truncated)? != EM_AARCH64{
+            return Err("The image isn't for AArch64.");
+        }
+
+        let entry = read_u64(image, 24).ok_or(truncated)?;
+        let program_headers = read_u64(image, 32).ok_or(truncated)?;
+        let program_header_size = read_u16(image, 54).ok_or(truncated)? as usize;
+        let program_header_count = read_u16(image, 56).ok_or(truncated)? as usize;
+
+        if program_header_size != PROGRAM_HEADER_SIZE{
+            return Err("The image's program headers are the wrong size.");
+        }
+
+        let mut layout = Layout{
+            segments: [Segment::default(); MAX_SEGMENTS],
+            segment_count: 0,
+            dynamic: None,
+            entry,
+            start: 0,
+            size: 0,
+            alignment: PAGE_SIZE
+        };
+
+        for index in 0..program_header_count{
+            let header = index.checked_mul(PROGRAM_HEADER_SIZE).and_then(|offset| program_headers.checked_add(offset))
+                .and_then(|offset| image.get(offset..offset.checked_add(PROGRAM_HEADER_SIZE)?))
+                .ok_or(truncated)?;
+
+            let segment = Segment{
+                offset: read_u64(header, 8).ok_or(truncated)?,
+                virtual_address: read_u64(header, 16).ok_or(truncated)?,
+                file_size: read_u64(header, 32).ok_or(truncated)?,
+                memory_size: read_u64(header, 40).ok_or(truncated)?,
+                flags: read_u32(header, 4).ok_or(truncated)?
+            };
+
+            match read_u32(header, 0).ok_or(truncated)?{
+                PT_LOAD => {
+                    let alignment = read_u64(header, 48).ok_or(truncated)?;
+
+                    if alignment > 1 && !alignment.is_power_of_two(){
+                        return Err("A segment's alignment isn't a power of two.");
+                    }
+
+                    layout.alignment = layout.alignment.max(alignment);
+                    layout.add_segment(image, segment)?;
+                },
+                PT_DYNAMIC => layout.dynamic = Some(segment.virtual_address),
+                PT_INTERP => return Err("The image needs a dynamic linker. Link it statically."),
+                _ => {},
+            }
+        }
+
+        if layout.segment_count == 0{
+            return Err("The image has nothing to load.");
+        }
+
+        let first = layout.segments[0];
+        let last = layout.segments[layout.segment_count - 1];
+        let end = last.end().and_then(|end| end.checked_next_multiple_of(PAGE_SIZE)).ok_or("A segment wraps around the address space.")?;
+
+        //Starting on the largest alignment keeps every segment as aligned as it was linked.
+        layout.start = first.virtual_address & !(layout.alignment - 1);
+        layout.size = end - layout.start;
+
+        let executable = layout.segments[..layout.segment_count].iter()
+            .any(|segment| segment.flags & PF_X != 0 && (segment.virtual_address..segment.virtual_address + segment.memory_size).contains(&entry));
+
+        if !executable{
+            return Err("The entry point isn't in an executable segment.");
+        }
+
+        Ok(layout)
+    }
+
+    /// Check a PT_LOAD segment and add it. Segments must come in address order and not share a
+    /// page, since access is granted a page at a time.
+    fn add_segment(&mut self, image: &[u8], segment: Segment) -> Result<(), &'static str>{
+        if segment.memory_size == 0{
+            return Ok(());
+        }
+
+        if self.segment_count == MAX_SEGMENTS{
+            return Err("The image has too many segments.");
+        }
+
+        if segment.file_size > segment.memory_size{
+            return Err("A segment has more data in the file than in memory.");
+        }
+
+        if segment.offset.checked_add(segment.file_size).is_none_or(|end| end > image.len()){
+            return Err("A segment's data is outside the image.");
+        }
+
+        if segment.flags & PF_W != 0 && segment.flags & PF_X != 0{
+            return Err("A segment is both writable and executable.");
+        }
+
+        let end = segment.end().ok_or("A segment wraps around the address space.")?;
+
+        if let Some(previous) = self.segment_count.checked_sub(1).map(|index| self.segments[index]){
+            let previous_end = previous.virtual_address + previous.memory_size;
+
+            if previous_end.next_multiple_of(PAGE_SIZE) > segment.virtual_address & !(PAGE_SIZE - 1){
+                return Err("Segments overlap, share a page or are out of order.");
+            }
+        }
+
+        if end > usize::MAX - PAGE_SIZE{
+            return Err("A segment wraps around the address space.");
+        }
+
+        self.segments[self.segment_count] = segment;
+        self.segment_count += 1;
+
+        Ok(())
+    }
+
+    fn segments(&self) -> &[Segment]{
+        &self.segments[..self.segment_count]
+    }
+}
+
+impl LoadedImage<'_>{
+    /// The offset into memory of size bytes at a virtual address the image was linked at.
+    fn offset(&self, virtual_address: usize, size: usize) -> Result<usize, &'static str>{
+        virtual_address.checked_sub(self.start)
+            .filter(|offset| offset.checked_add(size).is_some_and(|end| end <= self.memory.len()))
+            .ok_or("A relocation or dynamic entry is outside the image.")
+    }
+
+    fn read_u64(&self, virtual_address: usize) -> Result<usize, &'static str>{
+        let offset = self.offset(virtual_address, 8)?;
+        Ok(read_u64(self.memory, offset).unwrap_or(0))
+    }
+
+    fn write_u64(&mut self, virtual_address: usize, value: usize) -> Result<(), &'static str>{
+        let offset = self.offset(virtual_address, 8)?;
+        self.memory[offset..offset + 8].copy_from_slice(&(value as u64).to_le_bytes());
+        Ok(())
+    }
+
+    /// Apply the relocations in the dynamic section at a virtual address. Only relative
+    /// relocations are supported, since there is no dynamic linker to resolve symbols.
+    fn relocate(&mut self, dynamic: usize, bias: usize) -> Result<(), &'static str>{
+        let (mut rela, mut rela_size, mut rela_entry) = (0, 0, RELA_SIZE);
+        let (mut relr, mut relr_size, mut relr_entry) = (0, 0, 8);
+        let mut entry = dynamic;
+
+        loop{
+            let tag = self.read_u64(entry)? as u64;
+            let value = self.read_u64(entry.wrapping_add(8))?;
+
+            match tag{
+                DT_NULL => break,
+                DT_RELA => rela = value,
+                DT_RELASZ => rela_size = value,
+                DT_RELAENT => rela_entry = value,
+                DT_RELR => relr = value,
+                DT_RELRSZ => relr_size = value,
+                DT_RELRENT => relr_entry = value,
+                DT_REL => return Err("The image has REL relocations, which AArch64 doesn't use."),
+                _ => {},
+            }
+
+            entry = entry.wrapping_add(16);
+        }
+
+        if rela_entry != RELA_SIZE || relr_entry != 8{
+            return Err("The image's relocations are the wrong size.");
+        }
+
+        //Checking the tables are in the image keeps the loops below from wrapping.
+        let rela_end = if rela_size == 0{ rela } else{ self.offset(rela, rela_size).map(|_| rela + rela_size)? };
+        let relr_end = if relr_size == 0{ relr } else{ self.offset(relr, relr_size).map(|_| relr + relr_size)? };
+
+        for relocation in (rela..rela_end).step_by(RELA_SIZE){
+            let offset = self.read_u64(relocation)?;
+            let kind = self.read_u64(relocation + 8)? as u32;
+            let addend = self.read_u64(relocation + 16)?;
+
+            match kind{
+                R_AARCH64_NONE => {},
+                R_AARCH64_RELATIVE => self.write_u64(offset, bias.wrapping_add(addend))?,
+                _ => return Err("The image has a relocation that needs symbols resolved. Link it statically."),
+            }
+        }
+
+        //RELR packs relative relocations: an even entry is an address, and an odd one is a
+        //bitmap of which of the following 63 words need relocating.
+        let mut next = 0;
+
+        for relocation in (relr..relr_end).step_by(8){
+            let value = self.read_u64(relocation)?;
+
+            if value & 1 == 0{
+                self.write_u64(value, self.read_u64(value)?.wrapping_add(bias))?;
+                next = value.wrapping_add(8);
+            } else{
+                for bit in (1..64).filter(|bit| value >> bit & 1 != 0){
+                    let address = next.wrapping_add((bit - 1) * 8);
+                    self.write_u64(address, self.read_u64(address)?.wrapping_add(bias))?;
+                }
+
+                next = next.wrapping_add(63 * 8);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Allocate frames and hand them to an address space, so they're freed along with it.
+fn allocate_owned(address_space: &AddressSpace, size: usize, alignment: usize) -> Result<&'static mut [u8], &'static str>{
+    let pages = size / PAGE_SIZE;
+    let start = frame_allocator::allocate_contiguous(pages, alignment)?;
+
+    if let Err(error) = address_space.adopt(MemoryRegion::new(start, size)){
+        let _ = frame_allocator::free_contiguous(start, pages);
+        return Err(error);
+    }
+
+    let memory = unsafe{ core::slice::from_raw_parts_mut(start as *mut u8, size) };
+    memory.fill(0);
+
+    Ok(memory)
+}
+
+/// Copy the segments into frames, relocate them and map them with the access their flags ask for.
+/// Returns the entry point.
+fn load_segments(address_space: &AddressSpace, image: &[u8], layout: &Layout) -> Result<usize, &'static str>{
+    let memory = allocate_owned(address_space, layout.size, layout.alignment)?;
+    let base = memory.as_ptr() as usize;
+    let bias = base.wrapping_sub(layout.start);
+
+    for segment in layout.segments(){
+        let offset = segment.virtual_address - layout.start;
+        memory[offset..offset + segment.file_size].copy_from_slice(&image[segment.offset..segment.offset + segment.file_size]);
+    }
+
+    let mut loaded = LoadedImage{ memory, start: layout.start };
+
+    if let Some(dynamic) = layout.dynamic{
+        loaded.relocate(dynamic, bias)?;
+    }
+
+    cache::sync_instructions(base, layout.size);
+
+    //The image's frames are the task's alone and segments never share a page, so each can be rounded out to whole pages.
+    for segment in layout.segments(){
+        let start = segment.virtual_address.wrapping_add(bias);
+        let first_page = start & !(PAGE_SIZE - 1);
+        let end = (start + segment.memory_size).next_multiple_of(PAGE_SIZE);
+
+        address_space.map(&Region::new(first_page, end - first_page, segment.access()))?;
+    }
+
+    Ok(layout.entry.wrapping_add(bias))
+}
+
+/// Allocate and map a stack, and lay out the arguments on it like Linux does: argc, then the argv
+/// pointers and a null, then an empty environment and auxiliary vector, with the strings above.
+/// Returns the stack pointer and where argv is.
+fn build_stack(address_space: &AddressSpace, arguments: &[&str]) -> Result<(usize, usize), &'static str>{
+    if arguments.len() > MAX_ARGUMENTS{
+        return Err("Too many arguments for the program.");
+    }
+
+    let memory = allocate_owned(address_space, USER_STACK_SIZE, PAGE_SIZE)?;
+    let bottom = memory.as_ptr() as usize;
+    let mut strings = [0usize; MAX_ARGUMENTS];
+    let mut top = USER_STACK_SIZE;
+
+    for (index, argument) in arguments.iter().enumerate(){
+        top = top.checked_sub(argument.len() + 1).ok_or("The program's arguments don't fit on its stack.")?;
+        memory[top..top + argument.len()].copy_from_slice(argument.as_bytes());
+        strings[index] = bottom + top;
+    }
+
+    //argc, the argv pointers and their null, the environment's null and an AT_NULL pair
+    let words = 1 + arguments.len() + 1 + 1 + 2;
+    let stack_pointer = (top & !0xF).checked_sub((words * 8).next_multiple_of(16))
+        .filter(|offset| *offset >= PAGE_SIZE)
+        .ok_or("The program's arguments don't fit on its stack.")?;
+
+    let values = core::iter::once(arguments.len()).chain(strings[..arguments.len()].iter().copied());
+
+    for (index, value) in values.enumerate(){
+        let offset = stack_pointer + index * 8;
+        memory[offset..offset + 8].copy_from_slice(&(value as u64).to_le_bytes());
+    }
+
+    address_space.map(&Region::new(bottom, USER_STACK_SIZE, Access::ReadWrite))?;
+
+    Ok((bottom + stack_pointer, bottom + stack_pointer + 8))
+}
+
+/// Load an AArch64 ELF64 executable and start it as an EL0 task in its own address space. It must
+/// be position independent and statically linked, so the only relocations are relative ones. It
+/// starts with argc in x0 and argv in x1, and the same on its stack. arguments conventionally
+/// starts with the program's name. The image is copied, so it needn't outlive the call.
+pub fn spawn(name: &'static str, priority: u8, image: &[u8], arguments: &[&str], kernel_stack: &'static mut [u8]) -> Result<TaskId, &'static str>{
+    let layout = Layout::parse(image)?;
+    let address_space = AddressSpace::new()?;
+
+    let prepared = load_segments(&address_space, image, &layout)
+        .and_then(|entry| build_stack(&address_space, arguments).map(|stack| (entry, stack)));
+
+    match prepared{
+        Ok((entry, (stack_pointer, argv))) => scheduler::start_user_task(name, priority, entry, [arguments.len(), argv], stack_pointer, kernel_stack, address_space),
+        Err(error) => {
+            address_space.destroy();
+            Err(error)
+        },
+    }
+}
+
+/// Load a program from an initramfs by its path, and start it like spawn. The task is named after the path.
+pub fn spawn_from_initramfs(archive: &[u8], path: &'static str, priority: u8, arguments: &[&str], kernel_stack: &'static mut [u8]) -> Result<TaskId, &'static str>{
+    let image = initramfs::find(archive, path).ok_or("The initramfs has no such file.")?;
+
+    spawn(path, priority, image, arguments, kernel_stack)
+}
+
+/// Fill the image by calling receive until it's full.
+fn fill(image: &mut [u8], mut receive: impl FnMut(&mut [u8]) -> Result<usize, &'static str>) -> Result<(), &'static str>{
+    let mut filled = 0;
+
+    while filled < image.len(){
+        match receive(&mut image[filled..])?{
+            0 => return Err("The image ended early."),
+            count => filled += count,
+        }
+    }
+
+    Ok(())
+}
+
+/// Receive an image of size bytes, like one uploaded over a UART, and start it like spawn.
+/// receive is given the part of the image still to come, and returns how much of it it filled.
+/// The image is received into frames of its own, which are freed once it has been loaded.
+pub fn spawn_received(name: &'static str, priority: u8, size: usize, receive: impl FnMut(&mut [u8]) -> Result<usize, &'static str>, arguments: &[&str], kernel_stack: &'static mut [u8]) -> Result<TaskId, &'static str>{
+    if size == 0 || size > MAX_RECEIVED_SIZE{
+        return Err("The image is empty or larger than MAX_RECEIVED_SIZE.");
+    }
+
+    let pages = size.div_ceil(PAGE_SIZE);
+    let start = frame_allocator::allocate_contiguous(pages, PAGE_SIZE)?;
+    let image = unsafe{ core::slice::from_raw_parts_mut(start as *mut u8, size) };
+
+    let result = fill(image, receive).and_then(|_| spawn(name, priority, image, arguments, kernel_stack));
+
+    let _ = frame_allocator::free_contiguous(start, pages);
+
+    result
+}
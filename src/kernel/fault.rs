@@ -0,0 +1,125 @@
+use core::fmt;
+
+use crate::exception;
+use crate::sync::{IrqSpinLock, Once};
+
+use super::scheduler;
+use super::task::TaskId;
+
+/// How many of the latest faults recent_faults keeps.
+pub const FAULT_HISTORY: usize = 8;
+
+//Exception classes in ESR_EL1 for aborts taken from EL0
+const ESR_EC_INSTRUCTION_ABORT_LOWER_EL: u64 = 0x20;
+const ESR_EC_DATA_ABORT_LOWER_EL: u64 = 0x24;
+
+/// ISS.WnR: a data abort was caused by a write
+const ESR_WRITE_NOT_READ: u64 = 1 << 6;
+
+/// What an EL0 task did to fault.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FaultKind{
+    /// Read memory outside its regions, or that it can't read
+    Read,
+    /// Wrote memory outside its regions, or that is read only to it
+    Write,
+    /// Fetched an instruction from memory it can't execute
+    Execute,
+    /// Any other exception, like an undefined instruction. Holds the exception class from ESR_EL1.
+    Other(u8)
+}
+
+/// An EL0 task's fault. The task is ended once it has been reported.
+#[derive(Clone, Copy, Debug)]
+pub struct FaultReport{
+    pub task: TaskId,
+    pub name: &'static str,
+    pub kind: FaultKind,
+    /// The address the task tried to access, from FAR_EL1. Meaningless for FaultKind::Other.
+    pub address: usize,
+    /// The instruction that faulted
+    pub pc: usize
+}
+
+/// Called with every fault report, on the faulting task's kernel stack, before the task ends.
+pub type FaultHandler = fn(&FaultReport);
+
+struct FaultHistory{
+    reports: [Option<FaultReport>; FAULT_HISTORY],
+    next: usize
+}
+
+static HISTORY: IrqSpinLock<FaultHistory> = IrqSpinLock::new(FaultHistory{ reports: [None; FAULT_HISTORY], next: 0 });
+
+static HANDLER: Once<FaultHandler> = Once::new();
+
+impl FaultKind{
+    fn from_syndrome(esr: u64) -> FaultKind{
+        match esr >> 26{
+            ESR_EC_INSTRUCTION_ABORT_LOWER_EL => FaultKind::Execute,
+            ESR_EC_DATA_ABORT_LOWER_EL if esr & ESR_WRITE_NOT_READ != 0 => FaultKind::Write,
+            ESR_EC_DATA_ABORT_LOWER_EL => FaultKind::Read,
+            class => FaultKind::Other(class as u8),
+        }
+    }
+}
+
+impl fmt::Display for FaultReport{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        match self.kind{
+            FaultKind::Read => write!(f, "Task {} ({}) faulted reading {:#x} at {:#x}", self.name, self.task, self.address, self.pc),
+            FaultKind::Write => write!(f, "Task {} ({}) faulted writing {:#x} at {:#x}", self.name, self.task, self.address, self.pc),
+            FaultKind::Execute => write!(f, "Task {} ({}) faulted executing {:#x}", self.name, self.task, self.address),
+            FaultKind::Other(class) => write!(f, "Task {} ({}) took exception class {:#x} at {:#x}", self.name, self.task, class, self.pc),
+        }
+    }
+}
+
+/// Choose what is called with every fault report, like something that prints it. Can only be set once.
+pub fn set_fault_handler(handler: FaultHandler) -> Result<(), &'static str>{
+    if HANDLER.is_completed(){
+        return Err("The fault handler has already been set.");
+    }
+
+    HANDLER.call_once(|| handler);
+    Ok(())
+}
+
+/// The latest faults, oldest first.
+pub fn recent_faults() -> impl Iterator<Item = FaultReport>{
+    let history = HISTORY.lock();
+    let (reports, next) = (history.reports, history.next);
+
+    (0..FAULT_HISTORY).filter_map(move |index| reports[(next + index) % FAULT_HISTORY])
+}
+
+/// Handle a synchronous exception from an EL0 task that isn't a system call, like an access
+/// outside its regions. The fault is recorded and handed to the fault handler, then the task is
+/// ended. Nothing else is affected.
+pub(crate) fn handle_user_fault(esr: u64, far: u64, elr: u64) -> !{
+    let task = scheduler::current().expect("An EL0 task faulted before the scheduler started");
+
+    let report = FaultReport{
+        task,
+        name: scheduler::task_name(task).unwrap_or(""),
+        kind: FaultKind::from_syndrome(esr),
+        address: far as usize,
+        pc: elr as usize
+    };
+
+    {
+        let mut history = HISTORY.lock();
+        let next = history.next;
+
+        history.reports[next] = Some(report);
+        history.next = (next + 1) % FAULT_HISTORY;
+    }
+
+    //The handler may block, writing to a console, so let interrupts in like a system call does.
+    if let Some(handler) = HANDLER.get(){
+        exception::local_irq_enable();
+        handler(&report);
+    }
+
+    scheduler::exit()
+}
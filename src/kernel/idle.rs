@@ -0,0 +1,74 @@
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::exception;
+
+use super::cpu::{self, BOOT_CPU};
+use super::scheduler;
+use super::time;
+use super::timers;
+
+/// The longest the tick is stopped for in one go. Bounds the error from a wakeup we couldn't see coming.
+const MAX_IDLE_TICKS: u64 = 1000;
+
+/// Whether the idle task stops the periodic tick while nothing is due.
+static TICKLESS: AtomicBool = AtomicBool::new(true);
+
+/// Choose whether the idle task stops the tick. With it stopped, the core only wakes for the next
+/// task timeout or software timer, or for a device interrupt, rather than on every tick.
+pub fn set_tickless(enabled: bool){
+    TICKLESS.store(enabled, Ordering::Relaxed);
+}
+
+pub fn is_tickless() -> bool{
+    TICKLESS.load(Ordering::Relaxed)
+}
+
+/// The tick the idle task needs to be woken at, if anything is due before MAX_IDLE_TICKS.
+fn next_wakeup(now: u64) -> u64{
+    let mut wakeup = now + MAX_IDLE_TICKS;
+
+    if let Some(tick) = scheduler::next_timeout(){
+        wakeup = core::cmp::min(wakeup, tick);
+    }
+
+    if let Some(tick) = timers::next_expiry(now){
+        wakeup = core::cmp::min(wakeup, tick);
+    }
+
+    wakeup
+}
+
+/// The idle task. Runs when no other task is ready, and sleeps the core until an interrupt arrives.
+/// Every core has one. Only the boot core's has timeouts to wake for, so the others simply stop
+/// their tick, and are woken by a reschedule interrupt when another core gives them work.
+pub(in crate::kernel) fn idle_task(_argument: usize){
+    let cpu = cpu::id();
+
+    loop{
+        //Masking IRQs keeps an interrupt from slipping in between choosing the wakeup and the WFI.
+        //A pending interrupt still ends the WFI, and is taken as soon as we unmask.
+        let daif = exception::local_irq_save();
+
+        if is_tickless() && cpu != BOOT_CPU{
+            time::stop_local_tick();
+        } else if is_tickless(){
+            //An interrupt other than the tick may have woken us, so catch the tick count up first.
+            time::resync_ticks();
+
+            let now = time::ticks();
+            let wakeup = next_wakeup(now);
+
+            //There's nothing to save if we'd be woken by the very next tick anyway.
+            if wakeup > now + 1{
+                time::stop_tick_until(wakeup);
+            }
+        }
+
+        unsafe{
+            asm!("wfi", options(nostack));
+        }
+
+        exception::local_irq_restore(daif);
+    }
+}
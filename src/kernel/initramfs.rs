@@ -0,0 +1,96 @@
+/// A newc cpio header is this magic and 13 fields of 8 hex digits. 070702 is the same with checksums.
+const MAGIC: &[u8] = b"070701";
+const MAGIC_WITH_CHECKSUM: &[u8] = b"070702";
+const HEADER_SIZE: usize = 110;
+
+//Field offsets in the header
+const MODE_OFFSET: usize = 14;
+const FILE_SIZE_OFFSET: usize = 54;
+const NAME_SIZE_OFFSET: usize = 94;
+
+/// The name of the entry that ends an archive.
+const TRAILER: &str = "TRAILER!!!";
+
+//File type bits in the mode
+const MODE_TYPE_MASK: u32 = 0o170000;
+const MODE_REGULAR_FILE: u32 = 0o100000;
+
+/// A file in an initramfs.
+#[derive(Clone, Copy)]
+pub struct File<'a>{
+    /// The path in the archive, without a leading "./" or "/"
+    pub name: &'a str,
+    pub mode: u32,
+    pub data: &'a [u8]
+}
+
+impl File<'_>{
+    pub fn is_regular_file(&self) -> bool{
+        self.mode & MODE_TYPE_MASK == MODE_REGULAR_FILE
+    }
+}
+
+/// Walks the entries of a cpio archive in the newc format, which is what initramfs images are.
+pub struct Files<'a>{
+    archive: &'a [u8],
+    offset: usize
+}
+
+fn hex_field(header: &[u8], offset: usize) -> Option<usize>{
+    let digits = core::str::from_utf8(header.get(offset..offset + 8)?).ok()?;
+    usize::from_str_radix(digits, 16).ok()
+}
+
+impl<'a> Iterator for Files<'a>{
+    type Item = File<'a>;
+
+    /// The next entry. Stops at the trailer, or at anything malformed.
+    fn next(&mut self) -> Option<File<'a>>{
+        let header = self.archive.get(self.offset..self.offset.checked_add(HEADER_SIZE)?)?;
+
+        if &header[..6] != MAGIC && &header[..6] != MAGIC_WITH_CHECKSUM{
+            return None;
+        }
+
+        let mode = hex_field(header, MODE_OFFSET)? as u32;
+        let file_size = hex_field(header, FILE_SIZE_OFFSET)?;
+        let name_size = hex_field(header, NAME_SIZE_OFFSET)?;
+
+        //The name size counts its NUL. The name and the data both start on 4 byte boundaries.
+        let name_start = self.offset + HEADER_SIZE;
+        let name = self.archive.get(name_start..name_start.checked_add(name_size)?.checked_sub(1)?)?;
+        let name = core::str::from_utf8(name).ok()?;
+
+        let data_start = (name_start + name_size).next_multiple_of(4);
+        let data = self.archive.get(data_start..data_start.checked_add(file_size)?)?;
+
+        if name == TRAILER{
+            return None;
+        }
+
+        self.offset = (data_start + file_size).next_multiple_of(4);
+
+        Some(File{
+            name: name.trim_start_matches("./").trim_start_matches('/'),
+            mode,
+            data
+        })
+    }
+}
+
+/// Every entry in an archive, including directories.
+pub fn files(archive: &[u8]) -> Files<'_>{
+    Files{
+        archive,
+        offset: 0
+    }
+}
+
+/// The contents of a regular file, by its path in the archive. A leading "/" is ignored.
+pub fn find<'a>(archive: &'a [u8], path: &str) -> Option<&'a [u8]>{
+    let path = path.trim_start_matches('/');
+
+    files(archive)
+        .find(|file| file.name == path && file.is_regular_file())
+        .map(|file| file.data)
+}
@@ -0,0 +1,237 @@
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use core::time::Duration;
+
+use crate::bsp;
+use crate::exception;
+use crate::sync::IrqSpinLock;
+
+use super::cpu::{self, MAX_CPUS};
+use super::time;
+
+/// What an inter-processor interrupt asks the receiving core to do. Each kind has its own
+/// software generated interrupt.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IpiKind{
+    /// Look at the run queue again. The sender has already set need_resched.
+    Reschedule = 0,
+    /// Run the functions queued by call_on_cpu.
+    Call = 1,
+    /// Mask every interrupt and halt, for good.
+    Stop = 2
+}
+
+/// The cores an IPI is sent to. Cores that aren't online are always left out.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IpiTarget{
+    Cpu(usize),
+    /// Every core in the mask, bit n for core n
+    Mask(u32),
+    /// Every core but the calling one
+    Others,
+    All
+}
+
+/// The most calls that can be waiting for one core at a time.
+const CALL_QUEUE_LENGTH: usize = 8;
+
+/// A function to run on another core, and the flag to set once it has.
+#[derive(Clone, Copy)]
+struct Call{
+    function: fn(usize),
+    argument: usize,
+    /// The address of the caller's AtomicBool, for a synchronous call, or 0
+    done: usize
+}
+
+/// Calls waiting for a core, in the order they were made.
+struct CallQueue{
+    calls: [Option<Call>; CALL_QUEUE_LENGTH],
+    head: usize,
+    length: usize
+}
+
+impl CallQueue{
+    const fn new() -> CallQueue{
+        CallQueue{
+            calls: [None; CALL_QUEUE_LENGTH],
+            head: 0,
+            length: 0
+        }
+    }
+
+    fn push(&mut self, call: Call) -> Result<(), &'static str>{
+        if self.length == CALL_QUEUE_LENGTH{
+            return Err("The core's call queue is full.");
+        }
+
+        self.calls[(self.head + self.length) % CALL_QUEUE_LENGTH] = Some(call);
+        self.length += 1;
+
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Option<Call>{
+        if self.length == 0{
+            return None;
+        }
+
+        let call = self.calls[self.head].take();
+        self.head = (self.head + 1) % CALL_QUEUE_LENGTH;
+        self.length -= 1;
+
+        call
+    }
+}
+
+static CALL_QUEUES: [IrqSpinLock<CallQueue>; MAX_CPUS] = [const { IrqSpinLock::new(CallQueue::new()) }; MAX_CPUS];
+
+/// How long stop_other_cpus waits for the other cores to halt.
+const STOP_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// Bit n is set once core n has stopped.
+static STOPPED: AtomicU32 = AtomicU32::new(0);
+
+/// Set by the first core to call stop_other_cpus, so two cores panicking at once don't stop each other halfway.
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+/// The online cores a target names.
+fn target_mask(target: IpiTarget) -> u32{
+    let mask = match target{
+        IpiTarget::Cpu(cpu) if cpu < MAX_CPUS => 1 << cpu,
+        IpiTarget::Cpu(_) => 0,
+        IpiTarget::Mask(mask) => mask,
+        IpiTarget::Others => !(1 << cpu::id()),
+        IpiTarget::All => u32::MAX,
+    };
+
+    mask & cpu::online_mask()
+}
+
+/// Interrupt the target cores. Sending to no online core at all is not an error, and does nothing.
+pub fn send_ipi(target: IpiTarget, kind: IpiKind) -> Result<(), &'static str>{
+    let mask = target_mask(target);
+
+    if mask == 0{
+        return Ok(());
+    }
+
+    bsp::send_software_interrupt(kind as usize, mask as u8)
+}
+
+/// Route every kind of IPI to its handler on the calling core. Each core calls this once, as it joins the scheduler.
+pub(in crate::kernel) fn init_cpu() -> Result<(), &'static str>{
+    bsp::enable_software_interrupt(IpiKind::Reschedule as usize, handle_reschedule)?;
+    bsp::enable_software_interrupt(IpiKind::Call as usize, handle_call)?;
+    bsp::enable_software_interrupt(IpiKind::Stop as usize, handle_stop)
+}
+
+/// Another core has already set need_resched for us, and the switch happens on the way out of the interrupt.
+fn handle_reschedule(_context: usize){
+}
+
+/// Run every call queued for this core.
+fn handle_call(_context: usize){
+    let queue = &CALL_QUEUES[cpu::id()];
+
+    loop{
+        //Don't hold the queue while the function runs, so it can make calls of its own.
+        let call = match queue.lock().pop(){
+            Some(call) => call,
+            None => break,
+        };
+
+        (call.function)(call.argument);
+
+        if call.done != 0{
+            //The caller is spinning on the flag, so it is still alive.
+            unsafe{ (*(call.done as *const AtomicBool)).store(true, Ordering::Release) };
+        }
+    }
+}
+
+fn handle_stop(_context: usize){
+    halt();
+}
+
+/// Mask every exception and wait for good, telling the core stopping us that we have.
+fn halt() -> !{
+    unsafe{
+        asm!("msr daifset, #0xf", options(nomem, nostack));
+    }
+
+    STOPPED.fetch_or(1 << cpu::id(), Ordering::Release);
+
+    loop{
+        unsafe{
+            asm!("wfe", options(nomem, nostack));
+        }
+    }
+}
+
+fn queue_call(cpu: usize, function: fn(usize), argument: usize, done: usize) -> Result<(), &'static str>{
+    if !cpu::is_online(cpu){
+        return Err("The core isn't online.");
+    }
+
+    CALL_QUEUES[cpu].lock().push(Call{ function, argument, done })?;
+    send_ipi(IpiTarget::Cpu(cpu), IpiKind::Call)
+}
+
+/// Run function(argument) on a core from its IPI handler, and wait until it has. On the calling
+/// core, it simply runs with interrupts masked. The function runs in interrupt context, so it
+/// mustn't block. Can't be called with interrupts masked, since the target may be waiting on a
+/// call to us.
+pub fn call_on_cpu(cpu: usize, function: fn(usize), argument: usize) -> Result<(), &'static str>{
+    if exception::irqs_masked(){
+        return Err("call_on_cpu waits for the other core, so it can't be called with interrupts masked.");
+    }
+
+    //Stay on this core until we know whether the call is local.
+    let daif = exception::local_irq_save();
+
+    if cpu == cpu::id(){
+        function(argument);
+        exception::local_irq_restore(daif);
+        return Ok(());
+    }
+
+    let done = AtomicBool::new(false);
+    let result = queue_call(cpu, function, argument, &done as *const AtomicBool as usize);
+    exception::local_irq_restore(daif);
+    result?;
+
+    while !done.load(Ordering::Acquire){
+        core::hint::spin_loop();
+    }
+
+    Ok(())
+}
+
+/// Queue function(argument) to run on a core from its IPI handler, and return straight away.
+/// Safe to call from an interrupt handler.
+/// A call to the calling core runs once interrupts are next unmasked.
+pub fn call_on_cpu_async(cpu: usize, function: fn(usize), argument: usize) -> Result<(), &'static str>{
+    queue_call(cpu, function, argument, 0)
+}
+
+/// Halt every other online core, and wait a while for them to say they have. Used by the panic
+/// handler. If another core is already stopping everything, this core halts instead.
+pub fn stop_other_cpus(){
+    if STOPPING.swap(true, Ordering::AcqRel){
+        halt();
+    }
+
+    let others = cpu::online_mask() & !(1 << cpu::id());
+
+    if others == 0 || send_ipi(IpiTarget::Mask(others), IpiKind::Stop).is_err(){
+        return;
+    }
+
+    //A core with interrupts masked for good never answers, so don't wait forever.
+    let deadline = time::counter().saturating_add(time::duration_to_counter_cycles(STOP_TIMEOUT));
+
+    while STOPPED.load(Ordering::Acquire) & others != others && time::counter() < deadline{
+        core::hint::spin_loop();
+    }
+}
@@ -0,0 +1,21 @@
+pub mod wait_queue;
+pub mod peripherals;
+pub mod time;
+pub mod task;
+pub mod stack;
+pub mod scheduler;
+pub mod realtime;
+pub mod timers;
+pub mod idle;
+pub mod stats;
+pub mod cpu;
+pub mod ipi;
+pub mod device_tree;
+pub mod boot_info;
+pub mod driver;
+pub mod params;
+pub mod syscall;
+pub mod user;
+pub mod fault;
+pub mod initramfs;
+pub mod elf;
@@ -0,0 +1,227 @@
+use crate::sync::IrqSpinLock;
+
+/// A parameter's value. The default's kind decides how text from the command line is read.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Value{
+    /// Decimal, or hex with 0x
+    Integer(u64),
+    /// 1/0, y/n, yes/no, true/false or on/off. A key with no value sets it.
+    Flag(bool),
+    /// Anything, with double quotes around it if it has spaces
+    Text(&'static str)
+}
+
+/// A boot parameter, declared with the param! macro and set from /chosen/bootargs as name=value.
+#[repr(C)]
+pub struct Parameter{
+    pub name: &'static str,
+    pub default: Value,
+    /// Checks a value from the command line before it replaces the default
+    pub validate: fn(Value) -> Result<(), &'static str>,
+    /// The command line's value, if it gave one
+    pub value: IrqSpinLock<Option<Value>>
+}
+
+/// What init did with the command line.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct ParseSummary{
+    /// Values that replaced a default
+    pub applied: usize,
+    /// Keys no parameter has. The command line is shared with Linux, so these are normal.
+    pub unknown: usize,
+    /// Values that didn't parse, or that validation refused
+    pub rejected: usize,
+    /// Why the first value was rejected
+    pub first_error: Option<&'static str>
+}
+
+extern "C" {
+    static __params_start: u8;
+    static __params_end: u8;
+}
+
+/// Every parameter declared with the param! macro, as collected by the linker.
+pub fn parameters() -> &'static [Parameter]{
+    unsafe{
+        let start = &__params_start as *const u8 as usize;
+        let end = &__params_end as *const u8 as usize;
+        let count = (end - start) / core::mem::size_of::<Parameter>();
+
+        core::slice::from_raw_parts(start as *const Parameter, count)
+    }
+}
+
+/// The parameter with the given name.
+pub fn find(name: &str) -> Option<&'static Parameter>{
+    parameters().iter().find(|parameter| parameter.name == name)
+}
+
+/// For parameters that take any value of their kind.
+pub fn accept_any(_: Value) -> Result<(), &'static str>{
+    Ok(())
+}
+
+/// Split a command line into words on spaces. Double quotes keep spaces inside a word, and are
+/// left in for parse_word to strip.
+fn words(bootargs: &'static str) -> impl Iterator<Item = &'static str>{
+    let mut rest = bootargs;
+
+    core::iter::from_fn(move || {
+        rest = rest.trim_start();
+
+        if rest.is_empty(){
+            return None;
+        }
+
+        let mut quoted = false;
+        let end = rest.char_indices().find(|(_, character)| {
+            if *character == '"'{
+                quoted = !quoted;
+            }
+
+            character.is_whitespace() && !quoted
+        }).map_or(rest.len(), |(index, _)| index);
+
+        let (word, remainder) = rest.split_at(end);
+        rest = remainder;
+
+        Some(word)
+    })
+}
+
+/// Split a word into its key and value, if it has one.
+fn parse_word(word: &'static str) -> (&'static str, Option<&'static str>){
+    match word.split_once('='){
+        Some((key, value)) => {
+            let value = value.strip_prefix('"').and_then(|value| value.strip_suffix('"')).unwrap_or(value);
+            (key, Some(value))
+        },
+        None => (word, None),
+    }
+}
+
+fn parse_integer(text: &str) -> Option<u64>{
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")){
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+fn parse_flag(text: &str) -> Option<bool>{
+    match text{
+        "1" | "y" | "Y" | "yes" | "true" | "on" => Some(true),
+        "0" | "n" | "N" | "no" | "false" | "off" => Some(false),
+        _ => None,
+    }
+}
+
+impl Parameter{
+    /// Read text from the command line as the same kind of value as the default.
+    fn parse(&self, text: Option<&'static str>) -> Result<Value, &'static str>{
+        match (self.default, text){
+            (Value::Flag(_), None) => Ok(Value::Flag(true)),
+            (_, None) => Err("The parameter needs a value."),
+            (Value::Integer(_), Some(text)) => parse_integer(text).map(Value::Integer).ok_or("The value isn't an integer."),
+            (Value::Flag(_), Some(text)) => parse_flag(text).map(Value::Flag).ok_or("The value isn't a flag."),
+            (Value::Text(_), Some(text)) => Ok(Value::Text(text)),
+        }
+    }
+
+    /// Set the parameter from text, as if it was on the command line.
+    pub fn set(&self, text: Option<&'static str>) -> Result<(), &'static str>{
+        let value = self.parse(text)?;
+        (self.validate)(value)?;
+
+        *self.value.lock() = Some(value);
+        Ok(())
+    }
+
+    /// Go back to the default.
+    pub fn reset(&self){
+        *self.value.lock() = None;
+    }
+
+    /// The value from the command line, or the default.
+    pub fn get(&self) -> Value{
+        self.value.lock().unwrap_or(self.default)
+    }
+
+    /// Whether the command line set the parameter.
+    pub fn is_set(&self) -> bool{
+        self.value.lock().is_some()
+    }
+
+    /// The value of an integer parameter.
+    pub fn integer(&self) -> u64{
+        match self.get(){
+            Value::Integer(value) => value,
+            _ => panic!("The parameter isn't an integer."),
+        }
+    }
+
+    /// The value of a flag parameter.
+    pub fn flag(&self) -> bool{
+        match self.get(){
+            Value::Flag(value) => value,
+            _ => panic!("The parameter isn't a flag."),
+        }
+    }
+
+    /// The value of a text parameter.
+    pub fn text(&self) -> &'static str{
+        match self.get(){
+            Value::Text(value) => value,
+            _ => panic!("The parameter isn't text."),
+        }
+    }
+}
+
+/// Set every declared parameter the command line names. When a key is repeated the last one wins,
+/// and anything that doesn't parse or validate leaves the parameter as it was.
+pub fn init(bootargs: &'static str) -> ParseSummary{
+    let mut summary = ParseSummary::default();
+
+    for (key, text) in words(bootargs).map(parse_word){
+        let parameter = match find(key){
+            Some(parameter) => parameter,
+            None => {
+                summary.unknown += 1;
+                continue;
+            },
+        };
+
+        match parameter.set(text){
+            Ok(_) => summary.applied += 1,
+            Err(error) => {
+                summary.rejected += 1;
+                summary.first_error.get_or_insert(error);
+            },
+        }
+    }
+
+    summary
+}
+
+/// Declare a boot parameter. It keeps its default unless the command line sets it with name=value.
+///
+/// ```ignore
+/// param!(CONSOLE_BAUD_RATE, "console.baud", default = Value::Integer(115200), validate = check_baud_rate);
+/// ```
+#[macro_export]
+macro_rules! param{
+    ($static_name:ident, $name:expr, default = $default:expr) => {
+        $crate::kernel::params::param!($static_name, $name, default = $default, validate = $crate::kernel::params::accept_any);
+    };
+    ($static_name:ident, $name:expr, default = $default:expr, validate = $validate:path) => {
+        #[used]
+        #[link_section = ".params"]
+        static $static_name: $crate::kernel::params::Parameter = $crate::kernel::params::Parameter{
+            name: $name,
+            default: $default,
+            validate: $validate,
+            value: $crate::sync::IrqSpinLock::new(None)
+        };
+    };
+}
+
+pub use param;
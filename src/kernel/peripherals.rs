@@ -0,0 +1,141 @@
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::sync::{Mutex, MutexGuard};
+
+/// Tracks which instances of a peripheral (e.g. UART0-UART5) currently have an owner.
+/// Each index can be taken once, and becomes available again when its PeripheralClaim is dropped.
+pub struct PeripheralRegistry<const COUNT: usize>{
+    /// The error returned when an instance is taken twice
+    already_taken: &'static str,
+    owned: [AtomicBool; COUNT]
+}
+
+/// Proof of exclusive ownership of one peripheral instance. Dropping it releases the instance.
+pub struct PeripheralClaim{
+    owned: &'static AtomicBool,
+    index: usize
+}
+
+impl<const COUNT: usize> PeripheralRegistry<COUNT>{
+    pub const fn new(already_taken: &'static str) -> PeripheralRegistry<COUNT>{
+        PeripheralRegistry{
+            already_taken,
+            owned: [const { AtomicBool::new(false) }; COUNT]
+        }
+    }
+
+    /// Take ownership of an instance. Fails if the index is invalid, or if the instance already has an owner.
+    pub fn take(&'static self, index: usize) -> Result<PeripheralClaim, &'static str>{
+        if index >= COUNT{
+            return Err("Invalid peripheral index.");
+        }
+
+        match self.owned[index].compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed){
+            Ok(_) => Ok(PeripheralClaim{
+                owned: &self.owned[index],
+                index
+            }),
+            Err(_) => Err(self.already_taken),
+        }
+    }
+
+    /// True if the instance currently has an owner.
+    pub fn is_taken(&self, index: usize) -> bool{
+        index < COUNT && self.owned[index].load(Ordering::Acquire)
+    }
+}
+
+impl PeripheralClaim{
+    pub fn index(&self) -> usize{
+        self.index
+    }
+}
+
+impl Drop for PeripheralClaim{
+    fn drop(&mut self){
+        self.owned.store(false, Ordering::Release);
+    }
+}
+
+/// A slot that lets one owned peripheral be shared. The owner is installed once, and
+/// then every user goes through lock, which hands out exclusive access.
+/// The lock is a blocking Mutex, since holders may wait on their own interrupts or be preempted
+/// mid-write, and a waiter must give up the core rather than spin. That means it must not be
+/// locked from an interrupt handler. Code that can't block, like a panic, uses try_lock.
+pub struct Shared<T>{
+    slot: Mutex<Option<T>>
+}
+
+/// Exclusive access to a shared peripheral. The lock is released when this is dropped.
+pub struct SharedGuard<'a, T>{
+    guard: MutexGuard<'a, Option<T>>
+}
+
+impl<T> Shared<T>{
+    pub const fn new() -> Shared<T>{
+        Shared{
+            slot: Mutex::new(None)
+        }
+    }
+
+    /// Install the owner. Hands the value back if one is already installed.
+    pub fn install(&self, value: T) -> Result<(), T>{
+        let mut slot = self.slot.lock();
+
+        if slot.is_some(){
+            return Err(value);
+        }
+
+        *slot = Some(value);
+        Ok(())
+    }
+
+    /// Remove the owner, so it can be dropped or moved elsewhere.
+    pub fn remove(&self) -> Option<T>{
+        self.slot.lock().take()
+    }
+
+    /// Block until the lock is free, then take it. Fails if no owner has been installed.
+    pub fn lock(&self) -> Result<SharedGuard<'_, T>, &'static str>{
+        Self::guard(self.slot.lock())
+    }
+
+    /// Take the lock only if it's free right now.
+    pub fn try_lock(&self) -> Result<SharedGuard<'_, T>, &'static str>{
+        match self.slot.try_lock(){
+            Some(guard) => Self::guard(guard),
+            None => Err("The shared peripheral is in use."),
+        }
+    }
+
+    fn guard(guard: MutexGuard<'_, Option<T>>) -> Result<SharedGuard<'_, T>, &'static str>{
+        if guard.is_none(){
+            return Err("Nothing has been installed in this shared slot.");
+        }
+
+        Ok(SharedGuard{
+            guard
+        })
+    }
+}
+
+impl<T> Default for Shared<T>{
+    fn default() -> Shared<T>{
+        Shared::new()
+    }
+}
+
+impl<T> Deref for SharedGuard<'_, T>{
+    type Target = T;
+
+    fn deref(&self) -> &T{
+        unsafe{ self.guard.as_ref().unwrap_unchecked() }
+    }
+}
+
+impl<T> DerefMut for SharedGuard<'_, T>{
+    fn deref_mut(&mut self) -> &mut T{
+        unsafe{ self.guard.as_mut().unwrap_unchecked() }
+    }
+}
@@ -0,0 +1,141 @@
+use core::time::Duration;
+
+/// How periodic tasks are ordered among themselves. Every other task is scheduled by fixed priority.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SchedulingPolicy{
+    /// The task with the shortest period runs first
+    RateMonotonic,
+    /// The task whose current job has the earliest absolute deadline runs first
+    EarliestDeadlineFirst
+}
+
+/// The policy the scheduler starts with. The earliest_deadline_first feature selects EDF.
+#[cfg(feature = "earliest_deadline_first")]
+pub const DEFAULT_POLICY: SchedulingPolicy = SchedulingPolicy::EarliestDeadlineFirst;
+#[cfg(not(feature = "earliest_deadline_first"))]
+pub const DEFAULT_POLICY: SchedulingPolicy = SchedulingPolicy::RateMonotonic;
+
+/// The timing of a periodic task. A job is released every period, and must finish within
+/// deadline of its release, using no more than budget of CPU time.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PeriodicParameters{
+    pub period: Duration,
+    /// Relative to the release. No longer than the period.
+    pub deadline: Duration,
+    /// The worst case execution time of one job
+    pub budget: Duration
+}
+
+impl PeriodicParameters{
+    /// Parameters with the deadline at the end of the period.
+    pub const fn new(period: Duration, budget: Duration) -> PeriodicParameters{
+        PeriodicParameters{
+            period,
+            deadline: period,
+            budget
+        }
+    }
+
+    pub const fn with_deadline(self, deadline: Duration) -> PeriodicParameters{
+        PeriodicParameters{
+            deadline,
+            ..self
+        }
+    }
+
+    pub(in crate::kernel) fn validate(&self) -> Result<(), &'static str>{
+        if self.budget.is_zero() || self.budget > self.deadline{
+            return Err("The budget must be non-zero and no longer than the deadline.");
+        }
+
+        if self.deadline > self.period{
+            return Err("The deadline must be no longer than the period.");
+        }
+
+        Ok(())
+    }
+}
+
+/// What has happened to a periodic task's jobs so far.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct PeriodicStats{
+    /// The number of jobs that have finished
+    pub jobs: u64,
+    /// The number of jobs that were still running at their deadline
+    pub deadline_misses: u64,
+    /// The number of jobs that ran for longer than the budget
+    pub budget_overruns: u64,
+    /// The longest any job has taken from release to finishing
+    pub worst_response: Duration
+}
+
+/// A periodic task's parameters and the state of its current job. Times are in ticks, apart from the budget.
+#[derive(Clone, Copy)]
+pub(in crate::kernel) struct PeriodicState{
+    pub parameters: PeriodicParameters,
+    pub period_ticks: u64,
+    pub deadline_ticks: u64,
+    pub budget_cycles: u64,
+    /// The tick the current job was released at
+    pub release: u64,
+    pub absolute_deadline: u64,
+    /// The task's run time, in counter cycles, when the current job was released
+    pub job_start_cycles: u64,
+    /// False while the task is waiting for its next release
+    pub job_active: bool,
+    /// Set once the current job's miss or overrun has been counted, so it is only counted once
+    pub deadline_missed: bool,
+    pub budget_overrun: bool,
+    pub stats: PeriodicStats
+}
+
+/// EDF schedules any task set whose density, the sum of budget over deadline, is at most one.
+/// That is exact when every deadline equals its period, and safe otherwise.
+fn edf_admissible(tasks: &[PeriodicParameters]) -> bool{
+    const ONE: u128 = 1_000_000_000;
+
+    //Round each term up, so the test never passes a set it shouldn't.
+    let density: u128 = tasks.iter()
+        .map(|task| (task.budget.as_nanos() * ONE).div_ceil(task.deadline.as_nanos()))
+        .sum();
+
+    density <= ONE
+}
+
+/// Response time analysis. Each task's worst case response is its budget, plus the interference
+/// from every task with a shorter period released while it waits. Iterate until that settles,
+/// and reject the set if any response is later than its deadline.
+fn rate_monotonic_admissible(tasks: &mut [PeriodicParameters]) -> bool{
+    tasks.sort_unstable_by_key(|task| (task.period, task.deadline));
+
+    for (index, task) in tasks.iter().enumerate(){
+        let higher_priority = &tasks[..index];
+        let mut response = task.budget.as_nanos() + higher_priority.iter().map(|other| other.budget.as_nanos()).sum::<u128>();
+
+        loop{
+            if response > task.deadline.as_nanos(){
+                return false;
+            }
+
+            let next = task.budget.as_nanos() + higher_priority.iter()
+                .map(|other| response.div_ceil(other.period.as_nanos()) * other.budget.as_nanos())
+                .sum::<u128>();
+
+            if next == response{
+                break;
+            }
+
+            response = next;
+        }
+    }
+
+    true
+}
+
+/// True if every task in the set is guaranteed to meet its deadlines under the policy.
+pub(in crate::kernel) fn admissible(policy: SchedulingPolicy, tasks: &mut [PeriodicParameters]) -> bool{
+    match policy{
+        SchedulingPolicy::EarliestDeadlineFirst => edf_admissible(tasks),
+        SchedulingPolicy::RateMonotonic => rate_monotonic_admissible(tasks),
+    }
+}
@@ -0,0 +1,1386 @@
+use core::arch::asm;
+use core::cmp::Reverse;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use crate::exception::{self, TrapFrame};
+use crate::memory::address_space::{Access, AddressSpace, Region};
+use crate::memory::mmu::{self, PAGE_SIZE};
+use crate::sync::IrqSpinLock;
+
+use super::cpu::{self, BOOT_CPU, MAX_CPUS};
+use super::task::{self, Task, TaskId, TaskStack, TaskState, WakeReason, ALL_CPUS, IDLE_PRIORITY, MAX_TASKS, MINIMUM_STACK_SIZE, PERIODIC_PRIORITY, PRIORITY_LEVELS};
+use super::idle;
+use super::ipi::{self, IpiKind, IpiTarget};
+use super::realtime::{self, PeriodicParameters, PeriodicState, PeriodicStats, SchedulingPolicy};
+use super::stack;
+use super::stats::{Snapshot, TaskStats};
+use super::time;
+use super::timers;
+use super::user;
+use super::wait_queue::WaitQueue;
+
+/// The saved processor state a new task starts with: EL1h, with every exception unmasked.
+const INITIAL_SPSR: u64 = 0b0101;
+
+/// The saved processor state an EL0 task starts with: EL0t, with every exception unmasked.
+const USER_SPSR: u64 = 0b0000;
+
+/// How often, in ticks, each core looks for work to take from busier cores.
+const BALANCE_INTERVAL: u64 = 10;
+
+/// How many mutex owners priority inheritance follows down a chain. Bounded in case the chain is a deadlock cycle.
+const MAX_INHERITANCE_DEPTH: usize = 8;
+
+/// Each core's idle task is named after it.
+const IDLE_NAMES: [&str; 4] = ["idle", "idle 1", "idle 2", "idle 3"];
+
+/// Stands for no task in the atomics below.
+const NO_TASK: usize = usize::MAX;
+
+#[derive(Clone, Copy)]
+struct ReadyList{
+    head: Option<TaskId>,
+    tail: Option<TaskId>
+}
+
+/// One core's share of the scheduler.
+struct RunQueue{
+    ready: [ReadyList; PRIORITY_LEVELS],
+    /// Bit n is set while the ready list for priority n is non-empty
+    ready_bitmap: u32,
+    /// The number of tasks in the ready lists, including the idle task while it isn't running
+    ready_count: usize,
+    current: Option<TaskId>,
+    idle: Option<TaskId>,
+    /// Set when a task that should preempt the current one becomes ready
+    need_resched: bool,
+    /// The number of times the core has switched between tasks
+    context_switches: u64
+}
+
+/// A fixed priority preemptive scheduler. Each core has a run queue with a FIFO of ready tasks
+/// per priority, and runs the highest non-empty one. Tasks of equal priority are round-robined
+/// on every tick. Periodic tasks share PERIODIC_PRIORITY, where the policy orders them by period
+/// or deadline. Tasks are placed on a core when they wake, and idle or lightly loaded cores pull
+/// ready tasks from busier ones. Everything is under one lock.
+struct Scheduler{
+    tasks: [Task; MAX_TASKS],
+    run_queues: [RunQueue; MAX_CPUS],
+    /// Blocked tasks with a timeout, sorted by wake_tick
+    delayed: Option<TaskId>,
+    /// How periodic tasks are ordered
+    policy: SchedulingPolicy,
+    /// Bit n is set if core n only runs tasks pinned to isolated cores
+    isolated: u32
+}
+
+static SCHEDULER: IrqSpinLock<Scheduler> = IrqSpinLock::new(Scheduler::new());
+
+/// Set once start has been called. Checked without the lock by code that may run before the scheduler.
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Set for each task whose stack a core is using. A core keeps using the stack of the task it
+/// switched away from until it has restored the next task's frame, after dropping the lock, so
+/// another core mustn't start that task until finish_switch clears this.
+static STACK_IN_USE: [AtomicBool; MAX_TASKS] = [const { AtomicBool::new(false) }; MAX_TASKS];
+
+/// The task each core has just switched away from, until finish_switch.
+static SWITCHED_FROM: [AtomicUsize; MAX_CPUS] = [const { AtomicUsize::new(NO_TASK) }; MAX_CPUS];
+
+/// Paint stops this far below main's stack pointer, clear of the frame painting from.
+const BOOT_STACK_PAINT_MARGIN: usize = 256;
+
+extern "C" {
+    static __boot_stack_bottom: u8;
+    static __boot_stack_top: u8;
+}
+
+#[link_section = ".stacks"]
+static IDLE_STACK: TaskStack<4096> = TaskStack::new();
+
+impl RunQueue{
+    const fn new() -> RunQueue{
+        RunQueue{
+            ready: [ReadyList{ head: None, tail: None }; PRIORITY_LEVELS],
+            ready_bitmap: 0,
+            ready_count: 0,
+            current: None,
+            idle: None,
+            need_resched: false,
+            context_switches: 0
+        }
+    }
+
+    fn highest_ready_priority(&self) -> Option<u8>{
+        if self.ready_bitmap == 0{
+            return None;
+        }
+
+        Some(31 - self.ready_bitmap.leading_zeros() as u8)
+    }
+
+    /// The ready task that would run next.
+    fn highest_ready(&self) -> Option<TaskId>{
+        let priority = self.highest_ready_priority()?;
+        self.ready[priority as usize].head
+    }
+
+    /// The number of tasks that want the core, not counting the idle task. The idle task waits in
+    /// the ready lists whenever another task is running, so that is exactly the ready count.
+    fn load(&self) -> usize{
+        self.ready_count
+    }
+
+    fn is_idle(&self) -> bool{
+        self.current.is_some() && self.current == self.idle
+    }
+}
+
+impl Scheduler{
+    const fn new() -> Scheduler{
+        Scheduler{
+            tasks: [const { Task::new() }; MAX_TASKS],
+            run_queues: [const { RunQueue::new() }; MAX_CPUS],
+            delayed: None,
+            policy: realtime::DEFAULT_POLICY,
+            isolated: 0
+        }
+    }
+
+    /// The calling core's run queue. The lock masks interrupts, so we can't move cores while we hold it.
+    fn local(&mut self) -> &mut RunQueue{
+        &mut self.run_queues[cpu::id()]
+    }
+
+    fn current(&self) -> TaskId{
+        self.run_queues[cpu::id()].current.expect("No task is running")
+    }
+
+    /// The core a task is running on, if any.
+    fn running_on(&self, id: TaskId) -> Option<usize>{
+        self.run_queues.iter().position(|queue| queue.current == Some(id))
+    }
+
+    /// Orders tasks of equal priority, lowest first. Periodic tasks are keyed by the policy,
+    /// and every other task by the same value, so they stay in FIFO order.
+    fn ordering_key(&self, id: TaskId) -> u64{
+        match (self.policy, &self.tasks[id].periodic){
+            (SchedulingPolicy::RateMonotonic, Some(periodic)) => periodic.period_ticks,
+            (SchedulingPolicy::EarliestDeadlineFirst, Some(periodic)) => periodic.absolute_deadline,
+            (_, None) => u64::MAX,
+        }
+    }
+
+    /// How urgent a task is. A task only preempts one that is strictly less urgent.
+    fn urgency(&self, id: TaskId) -> (u8, Reverse<u64>){
+        (self.tasks[id].priority, Reverse(self.ordering_key(id)))
+    }
+
+    /// Queue a task on the run queue of the core in its cpu field.
+    fn push_ready(&mut self, id: TaskId){
+        let cpu = self.tasks[id].cpu;
+        let priority = self.tasks[id].priority as usize;
+        let key = self.ordering_key(id);
+
+        self.tasks[id].set_state(TaskState::Ready, time::counter());
+
+        //Insert after the last task with the same or a lower key.
+        let mut previous = self.run_queues[cpu].ready[priority].tail;
+        while let Some(other) = previous{
+            if self.ordering_key(other) <= key{
+                break;
+            }
+
+            previous = self.tasks[other].ready_prev;
+        }
+
+        let next = match previous{
+            Some(previous) => self.tasks[previous].ready_next,
+            None => self.run_queues[cpu].ready[priority].head,
+        };
+
+        self.tasks[id].ready_prev = previous;
+        self.tasks[id].ready_next = next;
+
+        let queue = &mut self.run_queues[cpu];
+
+        match previous{
+            Some(previous) => self.tasks[previous].ready_next = Some(id),
+            None => queue.ready[priority].head = Some(id),
+        }
+
+        match next{
+            Some(next) => self.tasks[next].ready_prev = Some(id),
+            None => queue.ready[priority].tail = Some(id),
+        }
+
+        queue.ready_bitmap |= 1 << priority;
+        queue.ready_count += 1;
+    }
+
+    fn remove_ready(&mut self, id: TaskId){
+        let cpu = self.tasks[id].cpu;
+        let priority = self.tasks[id].priority as usize;
+        let next = self.tasks[id].ready_next.take();
+        let previous = self.tasks[id].ready_prev.take();
+        let queue = &mut self.run_queues[cpu];
+
+        match previous{
+            Some(previous) => self.tasks[previous].ready_next = next,
+            None => queue.ready[priority].head = next,
+        }
+
+        match next{
+            Some(next) => self.tasks[next].ready_prev = previous,
+            None => queue.ready[priority].tail = previous,
+        }
+
+        if queue.ready[priority].head.is_none(){
+            queue.ready_bitmap &= !(1 << priority);
+        }
+
+        queue.ready_count -= 1;
+    }
+
+    fn pop_highest(&mut self, cpu: usize) -> Option<TaskId>{
+        let id = self.run_queues[cpu].highest_ready()?;
+
+        self.remove_ready(id);
+        Some(id)
+    }
+
+    /// The cores a task may be placed on. Isolated cores only take tasks that can't run anywhere
+    /// else. If none of the task's cores are online yet, it waits on the first of them.
+    fn allowed_cpus(&self, id: TaskId) -> u32{
+        let affinity = self.tasks[id].affinity;
+        let online = affinity & cpu::online_mask();
+        let shared = online & !self.isolated;
+
+        if shared != 0{
+            shared
+        } else if online != 0{
+            online
+        } else{
+            1 << affinity.trailing_zeros()
+        }
+    }
+
+    /// Choose a core for a task that is becoming ready. The core it last ran on is kept if it is
+    /// allowed and idle, since its caches are warm. Otherwise an idle core, then the least loaded.
+    fn select_cpu(&self, id: TaskId) -> usize{
+        let allowed = self.allowed_cpus(id);
+        let previous = self.tasks[id].cpu;
+
+        if allowed & (1 << previous) != 0 && self.run_queues[previous].is_idle(){
+            return previous;
+        }
+
+        (0..MAX_CPUS)
+            .filter(|cpu| allowed & (1 << cpu) != 0)
+            .min_by_key(|cpu| (!self.run_queues[*cpu].is_idle(), self.run_queues[*cpu].load(), *cpu != previous))
+            .unwrap_or(previous)
+    }
+
+    /// Ask a core to reschedule, interrupting it if it isn't the calling one.
+    fn resched(&mut self, cpu: usize){
+        self.run_queues[cpu].need_resched = true;
+
+        if cpu != cpu::id() && cpu::is_online(cpu){
+            let _ = ipi::send_ipi(IpiTarget::Cpu(cpu), IpiKind::Reschedule);
+        }
+    }
+
+    /// Ask for a reschedule if a newly ready task should preempt whatever its core is running.
+    fn check_preempt(&mut self, id: TaskId){
+        let cpu = self.tasks[id].cpu;
+
+        match self.run_queues[cpu].current{
+            Some(current) if self.urgency(current) >= self.urgency(id) => {},
+            _ => self.resched(cpu),
+        }
+    }
+
+    /// Make a task ready on the best core for it. A task that is still running, between
+    /// prepare_to_wait and switching away, stays on its core until it has switched.
+    fn make_ready(&mut self, id: TaskId){
+        self.tasks[id].cpu = match self.running_on(id){
+            Some(cpu) => cpu,
+            None => self.select_cpu(id),
+        };
+
+        self.push_ready(id);
+        self.check_preempt(id);
+    }
+
+    /// Take a ready task from the busiest core the given core can help, if it is busier than this
+    /// one by at least the threshold. A threshold of one lets an idle core take the only waiting task.
+    fn pull_task(&mut self, cpu: usize, threshold: usize) -> Option<TaskId>{
+        let load = self.run_queues[cpu].load();
+
+        let busiest = (0..MAX_CPUS)
+            .filter(|other| *other != cpu)
+            .max_by_key(|other| self.run_queues[*other].load())?;
+
+        if self.run_queues[busiest].load() < load + threshold{
+            return None;
+        }
+
+        //Take the most urgent task we're allowed to, that isn't still running over there.
+        let mut candidate = None;
+
+        'search: for priority in (0..PRIORITY_LEVELS).rev(){
+            let mut cursor = self.run_queues[busiest].ready[priority].head;
+
+            while let Some(id) = cursor{
+                if Some(id) != self.run_queues[busiest].idle && self.running_on(id).is_none() && self.allowed_cpus(id) & (1 << cpu) != 0{
+                    candidate = Some(id);
+                    break 'search;
+                }
+
+                cursor = self.tasks[id].ready_next;
+            }
+        }
+
+        let candidate = candidate?;
+
+        self.remove_ready(candidate);
+        self.tasks[candidate].cpu = cpu;
+        self.push_ready(candidate);
+
+        Some(candidate)
+    }
+
+    /// Blocked tasks that timed out and woke on another core are moved by make_ready. This moves
+    /// ready tasks that are no longer allowed where they are queued, after an affinity or isolation change.
+    fn migrate_disallowed(&mut self){
+        for id in 0..MAX_TASKS{
+            if self.tasks[id].state != TaskState::Ready || self.running_on(id).is_some(){
+                continue;
+            }
+
+            if self.allowed_cpus(id) & (1 << self.tasks[id].cpu) == 0{
+                self.remove_ready(id);
+                self.make_ready(id);
+            }
+        }
+
+        //Running tasks move when their core reschedules.
+        for cpu in 0..MAX_CPUS{
+            if let Some(current) = self.run_queues[cpu].current{
+                if self.allowed_cpus(current) & (1 << cpu) == 0{
+                    self.resched(cpu);
+                }
+            }
+        }
+    }
+
+    fn insert_delayed(&mut self, id: TaskId, wake_tick: u64){
+        self.tasks[id].wake_tick = wake_tick;
+        self.tasks[id].delayed = true;
+
+        let mut previous: Option<TaskId> = None;
+        let mut cursor = self.delayed;
+
+        while let Some(other) = cursor{
+            if self.tasks[other].wake_tick > wake_tick{
+                break;
+            }
+
+            previous = Some(other);
+            cursor = self.tasks[other].delay_next;
+        }
+
+        self.tasks[id].delay_next = cursor;
+
+        match previous{
+            Some(previous) => self.tasks[previous].delay_next = Some(id),
+            None => self.delayed = Some(id),
+        }
+
+        //The boot core handles timeouts, and may have stopped its tick without knowing about this one.
+        if time::is_tick_stopped() && cpu::id() != BOOT_CPU{
+            self.resched(BOOT_CPU);
+        }
+    }
+
+    fn remove_delayed(&mut self, id: TaskId){
+        if !self.tasks[id].delayed{
+            return;
+        }
+
+        let mut previous: Option<TaskId> = None;
+        let mut cursor = self.delayed;
+
+        while let Some(other) = cursor{
+            if other == id{
+                let next = self.tasks[id].delay_next.take();
+
+                match previous{
+                    Some(previous) => self.tasks[previous].delay_next = next,
+                    None => self.delayed = next,
+                }
+
+                break;
+            }
+
+            previous = Some(other);
+            cursor = self.tasks[other].delay_next;
+        }
+
+        self.tasks[id].delayed = false;
+    }
+
+    /// Take a blocked task off its wait queue and the delay list.
+    fn detach(&mut self, id: TaskId){
+        let queue = self.tasks[id].waiting_on;
+
+        if queue != 0{
+            //The queue outlives every task blocked on it, since they block inside its methods.
+            unsafe{ (*(queue as *const WaitQueue)).remove_waiter(id) };
+            self.tasks[id].waiting_on = 0;
+        }
+
+        self.remove_delayed(id);
+    }
+
+    /// Make a blocked task ready, and ask for a reschedule if it should preempt the current one.
+    fn wake(&mut self, id: TaskId, reason: WakeReason){
+        if self.tasks[id].state != TaskState::Blocked{
+            return;
+        }
+
+        self.detach(id);
+        self.tasks[id].wake_reason = reason;
+        self.make_ready(id);
+    }
+
+    /// Change a task's running priority, moving it between ready lists if need be.
+    fn set_running_priority(&mut self, id: TaskId, priority: u8){
+        if self.tasks[id].state == TaskState::Ready{
+            self.remove_ready(id);
+            self.tasks[id].priority = priority;
+            self.push_ready(id);
+        } else{
+            self.tasks[id].priority = priority;
+        }
+
+        //Either the current task may now be outranked, or a ready task may now outrank it.
+        let cpu = self.tasks[id].cpu;
+        self.resched(cpu);
+
+        if let Some(running) = self.running_on(id){
+            self.resched(running);
+        }
+    }
+
+    /// Create a task that starts at start(entry, argument).
+    fn create_task(&mut self, name: &'static str, priority: u8, start: extern "C" fn(usize, usize) -> !, entry: usize, argument: usize, stack: &'static mut [u8]) -> Result<TaskId, &'static str>{
+        if priority as usize >= PRIORITY_LEVELS{
+            return Err("Invalid task priority.");
+        }
+
+        if stack.len() < MINIMUM_STACK_SIZE{
+            return Err("The task stack is too small.");
+        }
+
+        let id = match self.tasks.iter().position(|task| task.state == TaskState::Free){
+            Some(id) => id,
+            None => return Err("The task table is full."),
+        };
+
+        let stack_start = stack.as_mut_ptr() as usize;
+        let bounds = stack::install_guard(stack_start, (stack_start + stack.len()) & !0xF);
+
+        //Build the frame the vectors will restore the first time the task is switched to.
+        let frame_address = bounds.top - core::mem::size_of::<TrapFrame>();
+        stack::paint(bounds.bottom, frame_address);
+        let mut regs = [0u64; 31];
+        regs[0] = entry as u64;
+        regs[1] = argument as u64;
+
+        unsafe{
+            core::ptr::write(frame_address as *mut TrapFrame, TrapFrame{
+                regs,
+                elr: start as *const () as u64,
+                spsr: INITIAL_SPSR,
+                sp_el0: 0
+            });
+        }
+
+        let task = &mut self.tasks[id];
+        *task = Task::new();
+        task.name = name;
+        task.base_priority = priority;
+        task.priority = priority;
+        task.frame = frame_address;
+        task.stack_bottom = bounds.bottom;
+        task.stack_top = bounds.top;
+        task.guard_page = bounds.guard_page;
+
+        self.tasks[id].cpu = self.select_cpu(id);
+        self.push_ready(id);
+
+        Ok(id)
+    }
+}
+
+/// Every task starts here, with its entry point and argument in x0 and x1.
+extern "C" fn task_entry(entry: usize, argument: usize) -> !{
+    let entry = unsafe{ core::mem::transmute::<usize, fn(usize)>(entry) };
+
+    entry(argument);
+
+    exit()
+}
+
+/// Turn the caller into the first task, start the idle task and the tick, and begin scheduling.
+/// The caller keeps running, now as a task called "main" with the given priority.
+pub fn start(main_priority: u8) -> Result<TaskId, &'static str>{
+    if main_priority as usize >= PRIORITY_LEVELS{
+        return Err("Invalid task priority.");
+    }
+
+    let idle_stack = match IDLE_STACK.take(){
+        Some(stack) => stack,
+        None => return Err("The scheduler is already running."),
+    };
+
+    let mut scheduler = SCHEDULER.lock();
+
+    let idle = scheduler.create_task(IDLE_NAMES[BOOT_CPU], IDLE_PRIORITY, task_entry, idle::idle_task as *const () as usize, 0, idle_stack)?;
+    scheduler.tasks[idle].affinity = 1 << BOOT_CPU;
+
+    //main is already running on the boot stack. Its frame is saved the first time we switch away.
+    let main = match scheduler.tasks.iter().position(|task| task.state == TaskState::Free){
+        Some(id) => id,
+        None => return Err("The task table is full."),
+    };
+
+    //main keeps the boot stack. Guard it, and paint what it hasn't used yet.
+    let (boot_stack_bottom, boot_stack_top) = unsafe{
+        (&__boot_stack_bottom as *const u8 as usize, &__boot_stack_top as *const u8 as usize)
+    };
+    let bounds = stack::install_guard(boot_stack_bottom - PAGE_SIZE, boot_stack_top);
+    stack::paint(bounds.bottom, stack::stack_pointer() - BOOT_STACK_PAINT_MARGIN);
+
+    let task = &mut scheduler.tasks[main];
+    task.stack_bottom = bounds.bottom;
+    task.stack_top = bounds.top;
+    task.guard_page = bounds.guard_page;
+    task.name = "main";
+    task.base_priority = main_priority;
+    task.priority = main_priority;
+    task.cpu = BOOT_CPU;
+    task.set_state(TaskState::Running, time::counter());
+
+    scheduler.run_queues[BOOT_CPU].current = Some(main);
+    scheduler.run_queues[BOOT_CPU].idle = Some(idle);
+    STACK_IN_USE[main].store(true, Ordering::Relaxed);
+
+    //Start every task declared with task!. They get the core at main's next scheduling point.
+    for task in task::static_tasks(){
+        let stack = match (task.stack)(){
+            Some(stack) => stack,
+            None => return Err("A static task's stack was already taken."),
+        };
+
+        let id = scheduler.create_task(task.name, task.priority, task_entry, task.entry as usize, task.argument, stack)?;
+        task.id.store(id, Ordering::Release);
+    }
+
+    scheduler.run_queues[BOOT_CPU].need_resched = true;
+    RUNNING.store(true, Ordering::Release);
+    drop(scheduler);
+
+    ipi::init_cpu()?;
+    crate::bsp::enable_timer_interrupt(handle_tick)?;
+    time::start_tick();
+
+    preempt_if_needed();
+
+    Ok(main)
+}
+
+/// Turn a secondary core's boot context into its idle task, and start scheduling on it.
+/// Called by cpu::secondary_main once the core's MMU and interrupt controller are set up.
+pub(in crate::kernel) fn start_secondary(cpu: usize, stack_bottom: usize, stack_top: usize) -> !{
+    let bounds = stack::install_guard(stack_bottom, stack_top);
+    stack::paint(bounds.bottom, stack::stack_pointer() - BOOT_STACK_PAINT_MARGIN);
+
+    let mut scheduler = SCHEDULER.lock();
+
+    let idle = scheduler.tasks.iter().position(|task| task.state == TaskState::Free).expect("The task table is full");
+
+    let task = &mut scheduler.tasks[idle];
+    *task = Task::new();
+    task.name = IDLE_NAMES[cpu];
+    task.base_priority = IDLE_PRIORITY;
+    task.priority = IDLE_PRIORITY;
+    task.stack_bottom = bounds.bottom;
+    task.stack_top = bounds.top;
+    task.guard_page = bounds.guard_page;
+    task.cpu = cpu;
+    task.affinity = 1 << cpu;
+    task.set_state(TaskState::Running, time::counter());
+
+    scheduler.run_queues[cpu].current = Some(idle);
+    scheduler.run_queues[cpu].idle = Some(idle);
+    STACK_IN_USE[idle].store(true, Ordering::Relaxed);
+
+    //From here on, other cores can place tasks on us.
+    cpu::set_online(cpu);
+    scheduler.migrate_disallowed();
+    scheduler.run_queues[cpu].need_resched = true;
+    drop(scheduler);
+
+    ipi::init_cpu().expect("Failed to enable inter-processor interrupts");
+    crate::bsp::enable_timer_interrupt(handle_tick).expect("Failed to enable the timer interrupt");
+    time::start_local_tick();
+
+    exception::local_irq_enable();
+    idle::idle_task(0);
+
+    unreachable!("The idle task returned");
+}
+
+/// Create a task. It runs entry(argument) on the given stack, and exits when entry returns.
+pub fn spawn(name: &'static str, priority: u8, entry: fn(usize), argument: usize, stack: &'static mut [u8]) -> Result<TaskId, &'static str>{
+    let mut scheduler = SCHEDULER.lock();
+    let id = scheduler.create_task(name, priority, task_entry, entry as usize, argument, stack)?;
+    scheduler.check_preempt(id);
+    drop(scheduler);
+
+    preempt_if_needed();
+
+    Ok(id)
+}
+
+/// Create a task that runs entry(argument) at EL0, and exits when entry returns. It gets its own
+/// translation tables, which only let it reach the kernel's code and constants, its user stack,
+/// and the regions it is given, like a shared buffer or one UART's registers. Anything else faults,
+/// and ends the task. It talks to the kernel through the system calls in syscall. Exceptions it
+/// takes, including system calls, run on the kernel stack, which should have room for a few nested frames.
+pub fn spawn_user(name: &'static str, priority: u8, entry: fn(usize), argument: usize, kernel_stack: &'static mut [u8], user_stack: &'static mut [u8], regions: &[Region]) -> Result<TaskId, &'static str>{
+    let user_stack_bottom = user_stack.as_mut_ptr() as usize;
+
+    if !user_stack_bottom.is_multiple_of(PAGE_SIZE) || !user_stack.len().is_multiple_of(PAGE_SIZE){
+        return Err("The user stack isn't whole pages. Use a UserStack.");
+    }
+
+    let address_space = AddressSpace::new()?;
+    let stack_region = Region::new(user_stack_bottom, user_stack.len(), Access::ReadWrite);
+
+    if let Err(error) = core::iter::once(&stack_region).chain(regions).try_for_each(|region| address_space.map(region)){
+        address_space.destroy();
+        return Err(error);
+    }
+
+    let entry_point = user::user_task_entry as *const () as usize;
+    start_user_task(name, priority, entry_point, [entry as usize, argument], user_stack_bottom + user_stack.len(), kernel_stack, address_space)
+}
+
+/// Create an EL0 task that starts at entry_point with the arguments in x0 and x1 and the stack
+/// pointer given, in an address space that is already set up. The task owns the address space
+/// from then on, and it is destroyed if the task can't be created.
+pub(in crate::kernel) fn start_user_task(name: &'static str, priority: u8, entry_point: usize, arguments: [usize; 2], stack_pointer: usize, kernel_stack: &'static mut [u8], address_space: AddressSpace) -> Result<TaskId, &'static str>{
+    let mut scheduler = SCHEDULER.lock();
+
+    let id = match scheduler.create_task(name, priority, user::user_task_entry, arguments[0], arguments[1], kernel_stack){
+        Ok(id) => id,
+        Err(error) => {
+            drop(scheduler);
+            address_space.destroy();
+            return Err(error);
+        },
+    };
+
+    //No other core can have picked it up while we hold the lock.
+    let frame = unsafe{ &mut *(scheduler.tasks[id].frame as *mut TrapFrame) };
+    frame.elr = entry_point as u64;
+    frame.spsr = USER_SPSR;
+    frame.sp_el0 = stack_pointer as u64;
+    scheduler.tasks[id].address_space = Some(address_space);
+
+    scheduler.check_preempt(id);
+    drop(scheduler);
+
+    preempt_if_needed();
+
+    Ok(id)
+}
+
+/// Periodic tasks start here. entry is called once for every job, and the task sleeps until
+/// its next release in between.
+extern "C" fn periodic_task_entry(entry: usize, argument: usize) -> !{
+    let entry = unsafe{ core::mem::transmute::<usize, fn(usize)>(entry) };
+
+    loop{
+        entry(argument);
+        complete_job();
+    }
+}
+
+/// Choose how periodic tasks are ordered. Only possible while there are none, since the
+/// admission test they passed depends on the policy.
+pub fn set_policy(policy: SchedulingPolicy) -> Result<(), &'static str>{
+    let mut scheduler = SCHEDULER.lock();
+
+    if scheduler.tasks.iter().any(|task| task.state != TaskState::Free && task.periodic.is_some()){
+        return Err("The policy can't change while periodic tasks exist.");
+    }
+
+    scheduler.policy = policy;
+
+    Ok(())
+}
+
+pub fn policy() -> SchedulingPolicy{
+    SCHEDULER.lock().policy
+}
+
+/// Create a periodic task, if the task set still passes the admission test with it added.
+/// entry(argument) is called once per job: straight away, then once every period. The task
+/// runs at PERIODIC_PRIORITY, ordered against other periodic tasks by the policy.
+pub fn spawn_periodic(name: &'static str, parameters: PeriodicParameters, entry: fn(usize), argument: usize, stack: &'static mut [u8]) -> Result<TaskId, &'static str>{
+    parameters.validate()?;
+
+    let mut scheduler = SCHEDULER.lock();
+
+    let mut task_set = [parameters; MAX_TASKS + 1];
+    let mut count = 1;
+
+    for periodic in scheduler.tasks.iter().filter(|task| task.state != TaskState::Free).filter_map(|task| task.periodic.as_ref()){
+        task_set[count] = periodic.parameters;
+        count += 1;
+    }
+
+    if !realtime::admissible(scheduler.policy, &mut task_set[..count]){
+        return Err("The task set fails the admission test.");
+    }
+
+    let id = scheduler.create_task(name, PERIODIC_PRIORITY, periodic_task_entry, entry as usize, argument, stack)?;
+    let release = time::ticks();
+    let deadline_ticks = time::duration_to_ticks(parameters.deadline);
+
+    //Requeue the task now it has a key to be ordered by.
+    scheduler.remove_ready(id);
+    scheduler.tasks[id].periodic = Some(PeriodicState{
+        parameters,
+        period_ticks: core::cmp::max(time::duration_to_ticks(parameters.period), 1),
+        deadline_ticks,
+        budget_cycles: time::duration_to_counter_cycles(parameters.budget),
+        release,
+        absolute_deadline: release + deadline_ticks,
+        job_start_cycles: 0,
+        job_active: true,
+        deadline_missed: false,
+        budget_overrun: false,
+        stats: PeriodicStats::default()
+    });
+    scheduler.push_ready(id);
+    scheduler.check_preempt(id);
+    drop(scheduler);
+
+    preempt_if_needed();
+
+    Ok(id)
+}
+
+/// Finish the calling periodic task's job, and sleep until its next release. If the job ran
+/// past the next release, the following one starts straight away, and any releases it ran
+/// past altogether are skipped.
+fn complete_job(){
+    let mut scheduler = SCHEDULER.lock();
+    let current = scheduler.current();
+    let now = time::ticks();
+
+    let periodic = match scheduler.tasks[current].periodic.as_mut(){
+        Some(periodic) => periodic,
+        None => return,
+    };
+
+    periodic.stats.jobs += 1;
+    periodic.stats.worst_response = core::cmp::max(periodic.stats.worst_response, time::ticks_to_duration(now - periodic.release));
+
+    if now > periodic.absolute_deadline && !periodic.deadline_missed{
+        periodic.stats.deadline_misses += 1;
+    }
+
+    let mut release = periodic.release + periodic.period_ticks;
+    while release + periodic.period_ticks <= now{
+        release += periodic.period_ticks;
+    }
+
+    periodic.release = release;
+    periodic.absolute_deadline = release + periodic.deadline_ticks;
+    periodic.job_active = false;
+    drop(scheduler);
+
+    sleep_until(release);
+
+    let mut scheduler = SCHEDULER.lock();
+    let (run_cycles, _, _) = scheduler.tasks[current].accounted_cycles(time::counter());
+
+    if let Some(periodic) = scheduler.tasks[current].periodic.as_mut(){
+        periodic.job_start_cycles = run_cycles;
+        periodic.job_active = true;
+        periodic.deadline_missed = false;
+        periodic.budget_overrun = false;
+    }
+}
+
+/// Record periodic jobs still running at their deadline, and any running job that has used up its budget.
+fn check_deadlines(scheduler: &mut Scheduler, now: u64){
+    for task in scheduler.tasks.iter_mut().filter(|task| task.state != TaskState::Free){
+        if let Some(periodic) = task.periodic.as_mut(){
+            if periodic.job_active && !periodic.deadline_missed && now > periodic.absolute_deadline{
+                periodic.deadline_missed = true;
+                periodic.stats.deadline_misses += 1;
+            }
+        }
+    }
+
+    let counter = time::counter();
+
+    for cpu in 0..MAX_CPUS{
+        let current = match scheduler.run_queues[cpu].current{
+            Some(current) => current,
+            None => continue,
+        };
+
+        let (run_cycles, _, _) = scheduler.tasks[current].accounted_cycles(counter);
+
+        if let Some(periodic) = scheduler.tasks[current].periodic.as_mut(){
+            if periodic.job_active && !periodic.budget_overrun && run_cycles - periodic.job_start_cycles > periodic.budget_cycles{
+                periodic.budget_overrun = true;
+                periodic.stats.budget_overruns += 1;
+            }
+        }
+    }
+}
+
+/// Deadline misses and budget overruns for a periodic task.
+pub fn periodic_stats(id: TaskId) -> Option<PeriodicStats>{
+    let scheduler = SCHEDULER.lock();
+
+    match scheduler.tasks.get(id){
+        Some(task) if task.state != TaskState::Free => task.periodic.map(|periodic| periodic.stats),
+        _ => None,
+    }
+}
+
+/// True once start has been called.
+pub fn is_running() -> bool{
+    RUNNING.load(Ordering::Acquire)
+}
+
+/// The calling task, or None before the scheduler has started.
+pub fn current() -> Option<TaskId>{
+    if !is_running(){
+        return None;
+    }
+
+    SCHEDULER.lock().local().current
+}
+
+pub fn task_name(id: TaskId) -> Option<&'static str>{
+    let scheduler = SCHEDULER.lock();
+
+    match scheduler.tasks.get(id){
+        Some(task) if task.state != TaskState::Free => Some(task.name),
+        _ => None,
+    }
+}
+
+/// The priority a task is running at, including any it has inherited.
+pub fn priority(id: TaskId) -> Option<u8>{
+    let scheduler = SCHEDULER.lock();
+
+    match scheduler.tasks.get(id){
+        Some(task) if task.state != TaskState::Free => Some(task.priority),
+        _ => None,
+    }
+}
+
+/// The size of a task's usable stack, in bytes.
+pub fn stack_size(id: TaskId) -> Option<usize>{
+    let scheduler = SCHEDULER.lock();
+
+    match scheduler.tasks.get(id){
+        Some(task) if task.state != TaskState::Free => Some(task.stack_top - task.stack_bottom),
+        _ => None,
+    }
+}
+
+/// The most stack a task has ever used, in bytes, measured from the paint left on its stack.
+pub fn stack_high_water_mark(id: TaskId) -> Option<usize>{
+    let scheduler = SCHEDULER.lock();
+
+    match scheduler.tasks.get(id){
+        Some(task) if task.state != TaskState::Free => Some(stack::high_water_mark(task.stack_bottom, task.stack_top)),
+        _ => None,
+    }
+}
+
+/// Fill in every task's run time accounting. Stack usage is left to the caller, so the
+/// stacks aren't scanned with the scheduler locked.
+pub(in crate::kernel) fn collect_stats(snapshot: &mut Snapshot){
+    let scheduler = SCHEDULER.lock();
+    let now = time::counter();
+
+    snapshot.timestamp = time::counter_cycles_to_duration(now);
+    snapshot.context_switches = scheduler.run_queues.iter().map(|queue| queue.context_switches).sum();
+
+    for (cpu, queue) in scheduler.run_queues.iter().enumerate(){
+        snapshot.idle[cpu] = queue.idle;
+    }
+
+    for (id, task) in scheduler.tasks.iter().enumerate(){
+        if task.state == TaskState::Free{
+            snapshot.tasks[id] = None;
+            continue;
+        }
+
+        let (run_cycles, ready_cycles, blocked_cycles) = task.accounted_cycles(now);
+
+        snapshot.tasks[id] = Some(TaskStats{
+            id,
+            name: task.name,
+            state: task.state,
+            priority: task.priority,
+            cpu: task.cpu,
+            affinity: task.affinity,
+            run_time: time::counter_cycles_to_duration(run_cycles),
+            ready_time: time::counter_cycles_to_duration(ready_cycles),
+            blocked_time: time::counter_cycles_to_duration(blocked_cycles),
+            switches: task.switches,
+            stack_used: 0,
+            stack_size: task.stack_top - task.stack_bottom,
+            stack_bottom: task.stack_bottom,
+            periodic: task.periodic.map(|periodic| periodic.stats)
+        });
+    }
+}
+
+/// The name of the task whose guard page contains the address. Used while reporting a fault,
+/// so it gives up rather than wait if the scheduler is locked.
+pub(crate) fn stack_overflow_owner(address: usize) -> Option<&'static str>{
+    let scheduler = SCHEDULER.try_lock()?;
+
+    scheduler.tasks.iter()
+        .find(|task| task.state != TaskState::Free && task.guard_page != 0 && (task.guard_page..task.guard_page + PAGE_SIZE).contains(&address))
+        .map(|task| task.name)
+}
+
+/// Give up the core to any other ready task of the same or higher priority.
+pub fn yield_now(){
+    unsafe{
+        asm!("svc #{}", const exception::SVC_YIELD, options(nostack));
+    }
+}
+
+/// Block the calling task for at least the given duration.
+pub fn sleep(duration: Duration){
+    sleep_until(time::deadline_after(duration));
+}
+
+/// Block the calling task until the given tick.
+pub fn sleep_until(tick: u64){
+    if !is_running(){
+        //Nothing is ticking yet, so time the wait with the system counter.
+        let end = time::counter().saturating_add(time::ticks_to_counter_cycles(tick.saturating_sub(time::ticks())));
+
+        while time::counter() < end{
+            core::hint::spin_loop();
+        }
+        return;
+    }
+
+    let daif = exception::local_irq_save();
+    prepare_to_wait(None, Some(tick));
+    block();
+    exception::local_irq_restore(daif);
+}
+
+/// End the calling task. Its slot is freed once the scheduler has switched away from it.
+pub fn exit() -> !{
+    exception::local_irq_disable();
+
+    let mut scheduler = SCHEDULER.lock();
+    let current = scheduler.current();
+    scheduler.tasks[current].set_state(TaskState::Exited, time::counter());
+    let address_space = scheduler.tasks[current].address_space.take();
+    drop(scheduler);
+
+    //Nothing can switch us back to our own tables now, so leave them and free them.
+    if let Some(address_space) = address_space{
+        mmu::activate(mmu::kernel_ttbr());
+        address_space.destroy();
+    }
+
+    yield_now();
+
+    unreachable!("An exited task was scheduled again");
+}
+
+/// Mark the calling task as blocked on a queue, a deadline or both. The task keeps running
+/// until block is called, so the caller can re-check its condition without missing a wakeup.
+/// Must be called with interrupts masked.
+pub(in crate::kernel) fn prepare_to_wait(queue: Option<&WaitQueue>, deadline: Option<u64>){
+    let mut scheduler = SCHEDULER.lock();
+    let current = scheduler.current();
+
+    scheduler.tasks[current].set_state(TaskState::Blocked, time::counter());
+    scheduler.tasks[current].wake_reason = WakeReason::Notified;
+
+    if let Some(queue) = queue{
+        queue.add_waiter(current);
+        scheduler.tasks[current].waiting_on = queue as *const WaitQueue as usize;
+    }
+
+    if let Some(deadline) = deadline{
+        scheduler.insert_delayed(current, deadline);
+    }
+}
+
+/// Undo prepare_to_wait, because the condition came true before we blocked.
+pub(in crate::kernel) fn cancel_wait(){
+    let mut scheduler = SCHEDULER.lock();
+    let current = scheduler.current();
+
+    match scheduler.tasks[current].state{
+        TaskState::Blocked => scheduler.detach(current),
+        //We were woken before we blocked, and are already queued to run.
+        TaskState::Ready => scheduler.remove_ready(current),
+        _ => {},
+    }
+
+    scheduler.tasks[current].set_state(TaskState::Running, time::counter());
+}
+
+/// Switch away from a task marked blocked by prepare_to_wait, and return why it was woken.
+pub(in crate::kernel) fn block() -> WakeReason{
+    yield_now();
+
+    let scheduler = SCHEDULER.lock();
+    scheduler.tasks[scheduler.current()].wake_reason
+}
+
+/// Wake the highest priority task blocked on the queue. Returns false if there were none.
+pub(in crate::kernel) fn wake_one(queue: &WaitQueue) -> bool{
+    let mut scheduler = SCHEDULER.lock();
+    let waiters = queue.waiters();
+
+    let chosen = (0..MAX_TASKS)
+        .filter(|id| waiters & (1 << id) != 0)
+        .max_by_key(|id| (scheduler.tasks[*id].priority, core::cmp::Reverse(*id)));
+
+    match chosen{
+        Some(id) => {
+            scheduler.wake(id, WakeReason::Notified);
+            true
+        },
+        None => false,
+    }
+}
+
+/// Wake every task blocked on the queue.
+pub(in crate::kernel) fn wake_all(queue: &WaitQueue){
+    let mut scheduler = SCHEDULER.lock();
+    let waiters = queue.waiters();
+
+    for id in (0..MAX_TASKS).filter(|id| waiters & (1 << id) != 0){
+        scheduler.wake(id, WakeReason::Notified);
+    }
+}
+
+/// The earliest tick a blocked task times out at.
+pub(in crate::kernel) fn next_timeout() -> Option<u64>{
+    let scheduler = SCHEDULER.lock();
+
+    scheduler.delayed.map(|id| scheduler.tasks[id].wake_tick)
+}
+
+/// Lend a waiter's running priority to the owner of the mutex it's blocked on, for priority
+/// inheritance. If the owner is blocked on another mutex, that mutex's owner gets it too, and so
+/// on down the chain, up to MAX_INHERITANCE_DEPTH owners.
+pub(crate) fn inherit_priority(waiter: TaskId, owner: TaskId){
+    let mut scheduler = SCHEDULER.lock();
+
+    if waiter >= MAX_TASKS{
+        return;
+    }
+
+    let priority = scheduler.tasks[waiter].priority;
+    scheduler.tasks[waiter].blocked_on_owner = Some(owner);
+
+    let mut next = Some(owner);
+
+    for _ in 0..MAX_INHERITANCE_DEPTH{
+        let id = match next{
+            Some(id) if id < MAX_TASKS && scheduler.tasks[id].state != TaskState::Free => id,
+            _ => break,
+        };
+
+        if scheduler.tasks[id].priority < priority{
+            scheduler.set_running_priority(id, priority);
+        }
+
+        next = scheduler.tasks[id].blocked_on_owner;
+    }
+}
+
+/// Record that the calling task took a mutex.
+pub(crate) fn mutex_acquired(){
+    if !is_running(){
+        return;
+    }
+
+    let mut scheduler = SCHEDULER.lock();
+    let current = scheduler.current();
+    scheduler.tasks[current].held_mutexes += 1;
+    scheduler.tasks[current].blocked_on_owner = None;
+}
+
+/// Record that the calling task released a mutex. Once it holds none, any inherited priority is dropped.
+pub(crate) fn mutex_released(){
+    if !is_running(){
+        return;
+    }
+
+    let mut scheduler = SCHEDULER.lock();
+    let current = scheduler.current();
+    let task = &mut scheduler.tasks[current];
+
+    task.held_mutexes = task.held_mutexes.saturating_sub(1);
+
+    if task.held_mutexes == 0 && task.priority != task.base_priority{
+        let base_priority = task.base_priority;
+        scheduler.set_running_priority(current, base_priority);
+    }
+}
+
+/// Switch now if a higher priority task has become ready. Does nothing in an interrupt handler,
+/// where the switch happens on the way out, or while the caller has interrupts masked.
+pub fn preempt_if_needed(){
+    if !is_running() || exception::in_interrupt() || exception::irqs_masked(){
+        return;
+    }
+
+    if SCHEDULER.lock().local().need_resched{
+        yield_now();
+    }
+}
+
+/// The tick interrupt handler, on every core. The boot core keeps time: it wakes tasks whose
+/// timeouts have expired, checks deadlines and runs the timers. Every core time slices between
+/// its tasks of equal priority, and now and then takes work from busier cores.
+fn handle_tick(_context: usize){
+    let cpu = cpu::id();
+
+    let ticks = if cpu == BOOT_CPU{
+        let (previous, now) = time::advance_tick();
+        let mut scheduler = SCHEDULER.lock();
+
+        while let Some(id) = scheduler.delayed{
+            if scheduler.tasks[id].wake_tick > now{
+                break;
+            }
+
+            scheduler.wake(id, WakeReason::TimedOut);
+        }
+
+        check_deadlines(&mut scheduler, now);
+
+        //Waking the timer task takes the scheduler lock again.
+        drop(scheduler);
+        timers::tick(previous, now);
+
+        now
+    } else{
+        time::start_local_tick();
+        time::ticks()
+    };
+
+    let mut scheduler = SCHEDULER.lock();
+
+    if ticks % BALANCE_INTERVAL == 0{
+        if let Some(id) = scheduler.pull_task(cpu, 2){
+            scheduler.check_preempt(id);
+        }
+    }
+
+    if let (Some(current), Some(highest)) = (scheduler.run_queues[cpu].current, scheduler.run_queues[cpu].highest_ready()){
+        if scheduler.urgency(highest) >= scheduler.urgency(current){
+            scheduler.run_queues[cpu].need_resched = true;
+        }
+    }
+}
+
+/// Called from the svc yield. Round-robins even if nothing more urgent is ready.
+pub(crate) fn yield_from_exception(frame: *mut TrapFrame) -> *mut TrapFrame{
+    SCHEDULER.lock().local().need_resched = true;
+    switch(frame)
+}
+
+/// Pick the task to return to from an exception. Called with the interrupted task's frame on
+/// its stack, and returns the frame to restore, which belongs to whichever task should run next.
+pub(crate) fn switch(frame: *mut TrapFrame) -> *mut TrapFrame{
+    if !is_running(){
+        return frame;
+    }
+
+    let cpu = cpu::id();
+    let mut scheduler = SCHEDULER.lock();
+
+    if !scheduler.run_queues[cpu].need_resched{
+        return frame;
+    }
+
+    scheduler.run_queues[cpu].need_resched = false;
+
+    let current = scheduler.current();
+    let idle = scheduler.run_queues[cpu].idle;
+
+    match scheduler.tasks[current].state{
+        //A task no longer allowed here moves on, whatever else is ready.
+        TaskState::Running if scheduler.allowed_cpus(current) & (1 << cpu) == 0 => {
+            scheduler.tasks[current].cpu = scheduler.select_cpu(current);
+            scheduler.push_ready(current);
+
+            let moved_to = scheduler.tasks[current].cpu;
+            scheduler.resched(moved_to);
+        },
+        TaskState::Running => {
+            //Only give up the core to a task at least as urgent.
+            match scheduler.run_queues[cpu].highest_ready(){
+                Some(highest) if scheduler.urgency(highest) >= scheduler.urgency(current) => scheduler.push_ready(current),
+                _ => return frame,
+            }
+        },
+        //We're still on the exited task's stack, but nothing uses it after the switch.
+        TaskState::Exited => scheduler.tasks[current].set_state(TaskState::Free, time::counter()),
+        _ => {},
+    }
+
+    scheduler.tasks[current].frame = frame as usize;
+
+    //Rather than go idle, look for a task waiting on another core.
+    if scheduler.run_queues[cpu].highest_ready() == idle{
+        scheduler.pull_task(cpu, 1);
+    }
+
+    let next = scheduler.pop_highest(cpu).expect("The idle task is always ready");
+
+    //The idle task may have stopped the tick. Anything else needs it running.
+    if Some(current) == idle && Some(next) != idle{
+        if cpu == BOOT_CPU{
+            time::resume_tick();
+        } else{
+            time::start_local_tick();
+        }
+    }
+
+    if next != current{
+        scheduler.tasks[next].switches += 1;
+        scheduler.run_queues[cpu].context_switches += 1;
+
+        //The core that last ran the next task may still be on its stack, for a few instructions.
+        SWITCHED_FROM[cpu].store(current, Ordering::Relaxed);
+
+        while STACK_IN_USE[next].load(Ordering::Acquire){
+            core::hint::spin_loop();
+        }
+
+        STACK_IN_USE[next].store(true, Ordering::Relaxed);
+    }
+
+    scheduler.tasks[next].set_state(TaskState::Running, time::counter());
+    scheduler.run_queues[cpu].current = Some(next);
+
+    mmu::activate(scheduler.tasks[next].address_space.map_or(mmu::kernel_ttbr(), |space| space.ttbr()));
+
+    scheduler.tasks[next].frame as *mut TrapFrame
+}
+
+/// Called by the exception vectors once they have moved onto the frame switch returned, so
+/// the task this core switched away from can run elsewhere.
+#[no_mangle]
+extern "C" fn finish_switch(){
+    let previous = SWITCHED_FROM[cpu::id()].swap(NO_TASK, Ordering::Relaxed);
+
+    if previous != NO_TASK{
+        STACK_IN_USE[previous].store(false, Ordering::Release);
+    }
+}
+
+/// Restrict a task to the cores in the mask. A running task moves at its core's next reschedule.
+pub fn set_affinity(id: TaskId, mask: u32) -> Result<(), &'static str>{
+    if mask & ALL_CPUS == 0 || mask & !ALL_CPUS != 0{
+        return Err("The affinity mask must name at least one core, and no others.");
+    }
+
+    let mut scheduler = SCHEDULER.lock();
+
+    match scheduler.tasks.get(id){
+        Some(task) if task.state != TaskState::Free => {},
+        _ => return Err("No such task."),
+    }
+
+    if Some(id) == scheduler.run_queues[scheduler.tasks[id].cpu].idle{
+        return Err("Idle tasks can't be moved.");
+    }
+
+    scheduler.tasks[id].affinity = mask;
+    scheduler.migrate_disallowed();
+
+    Ok(())
+}
+
+/// The cores a task may run on.
+pub fn affinity(id: TaskId) -> Option<u32>{
+    let scheduler = SCHEDULER.lock();
+
+    match scheduler.tasks.get(id){
+        Some(task) if task.state != TaskState::Free => Some(task.affinity),
+        _ => None,
+    }
+}
+
+/// The core a task is queued on, running on, or last ran on.
+pub fn cpu_of(id: TaskId) -> Option<usize>{
+    let scheduler = SCHEDULER.lock();
+
+    match scheduler.tasks.get(id){
+        Some(task) if task.state != TaskState::Free => Some(task.cpu),
+        _ => None,
+    }
+}
+
+/// Isolate a core, so it only runs tasks whose affinity allows no other shared core, or return it
+/// to general use. Tasks that can run elsewhere are moved off it.
+pub fn isolate_cpu(cpu: usize, isolated: bool) -> Result<(), &'static str>{
+    if cpu >= MAX_CPUS{
+        return Err("No such core.");
+    }
+
+    let mut scheduler = SCHEDULER.lock();
+
+    if isolated{
+        scheduler.isolated |= 1 << cpu;
+    } else{
+        scheduler.isolated &= !(1 << cpu);
+    }
+
+    scheduler.migrate_disallowed();
+
+    Ok(())
+}
+
+/// Make sure the boot core notices a new timer, if it has stopped its tick.
+pub(in crate::kernel) fn kick_timekeeper(){
+    if time::is_tick_stopped() && cpu::id() != BOOT_CPU{
+        SCHEDULER.lock().resched(BOOT_CPU);
+    }
+}
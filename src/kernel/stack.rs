@@ -0,0 +1,75 @@
+use crate::memory::mmu::{self, PAGE_SIZE};
+
+use super::task::MINIMUM_STACK_SIZE;
+
+/// The byte unused stack is filled with, so the deepest point a stack has reached can be found later.
+const STACK_PAINT: u8 = 0xA5;
+
+/// The bounds of a task's stack, once a guard page has been carved out of it.
+#[derive(Clone, Copy)]
+pub(in crate::kernel) struct StackBounds{
+    /// The lowest usable address
+    pub bottom: usize,
+    pub top: usize,
+    /// The unmapped page just below bottom, or 0 if the stack has no guard
+    pub guard_page: usize
+}
+
+/// Turn the lowest whole page of a stack into a guard page, if the MMU is on and the stack is
+/// big enough to spare one. Anything below the guard page is left unused.
+pub(in crate::kernel) fn install_guard(bottom: usize, top: usize) -> StackBounds{
+    let guard_page = bottom.next_multiple_of(PAGE_SIZE);
+
+    if !mmu::is_enabled() || guard_page + PAGE_SIZE + MINIMUM_STACK_SIZE > top{
+        return StackBounds{
+            bottom,
+            top,
+            guard_page: 0
+        };
+    }
+
+    match mmu::unmap_page(guard_page){
+        Ok(_) => StackBounds{
+            bottom: guard_page + PAGE_SIZE,
+            top,
+            guard_page
+        },
+        Err(_) => StackBounds{
+            bottom,
+            top,
+            guard_page: 0
+        },
+    }
+}
+
+/// Fill a range of stack with the paint byte.
+pub(in crate::kernel) fn paint(bottom: usize, top: usize){
+    if top <= bottom{
+        return;
+    }
+
+    unsafe{
+        core::ptr::write_bytes(bottom as *mut u8, STACK_PAINT, top - bottom);
+    }
+}
+
+/// The most stack ever used, in bytes, found by looking for the lowest byte that isn't paint.
+/// A function can leave paint intact in its frame, so this can read slightly low.
+pub(in crate::kernel) fn high_water_mark(bottom: usize, top: usize) -> usize{
+    let mut address = bottom;
+
+    while address < top && unsafe{ core::ptr::read_volatile(address as *const u8) } == STACK_PAINT{
+        address += 1;
+    }
+
+    top - address
+}
+
+/// The current stack pointer.
+pub(in crate::kernel) fn stack_pointer() -> usize{
+    let sp: usize;
+    unsafe{
+        core::arch::asm!("mov {}, sp", out(reg) sp, options(nomem, nostack));
+    }
+    sp
+}
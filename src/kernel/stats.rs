@@ -0,0 +1,293 @@
+use core::fmt::{self, Write};
+use core::time::Duration;
+
+use crate::sync::Once;
+
+use super::cpu::MAX_CPUS;
+use super::realtime::PeriodicStats;
+use super::scheduler;
+use super::stack;
+use super::task::{TaskId, TaskStack, TaskState, MAX_TASKS};
+use super::time;
+
+/// Function the periodic report hands its output to, a line at a time. Each line ends with a newline.
+pub type ReportOutput = fn(&str);
+
+/// The longest line the report writer buffers before handing it on.
+const LINE_LENGTH: usize = 128;
+
+/// One task's accounting, as of a snapshot.
+#[derive(Clone, Copy)]
+pub struct TaskStats{
+    pub id: TaskId,
+    pub name: &'static str,
+    pub state: TaskState,
+    /// The priority the task was running at, including any it had inherited
+    pub priority: u8,
+    /// The core the task is on, or last ran on
+    pub cpu: usize,
+    /// Bit n is set if the task may run on core n
+    pub affinity: u32,
+    /// Time spent running on a core
+    pub run_time: Duration,
+    /// Time spent ready, waiting for a core
+    pub ready_time: Duration,
+    /// Time spent blocked on a wait queue or a timeout
+    pub blocked_time: Duration,
+    /// The number of times the task has been switched to
+    pub switches: u64,
+    /// The most stack the task has ever used, in bytes
+    pub stack_used: usize,
+    pub stack_size: usize,
+    pub(in crate::kernel) stack_bottom: usize,
+    /// Job counts, deadline misses and budget overruns, for a periodic task
+    pub periodic: Option<PeriodicStats>
+}
+
+/// The run time accounting of every task at one moment. Comparing two snapshots gives the load over the time between them.
+pub struct Snapshot{
+    /// When the snapshot was taken, by the system counter
+    pub timestamp: Duration,
+    /// The number of task switches since the scheduler started
+    pub context_switches: u64,
+    pub tasks: [Option<TaskStats>; MAX_TASKS],
+    /// Each core's idle task, once the core is online
+    pub(in crate::kernel) idle: [Option<TaskId>; MAX_CPUS]
+}
+
+impl Snapshot{
+    pub const fn new() -> Snapshot{
+        Snapshot{
+            timestamp: Duration::ZERO,
+            context_switches: 0,
+            tasks: [None; MAX_TASKS],
+            idle: [None; MAX_CPUS]
+        }
+    }
+
+    /// Take a snapshot of every task as it is now.
+    pub fn take() -> Snapshot{
+        let mut snapshot = Snapshot::new();
+        snapshot.refresh();
+        snapshot
+    }
+
+    /// Update the snapshot to now.
+    pub fn refresh(&mut self){
+        scheduler::collect_stats(self);
+
+        for task in self.tasks.iter_mut().flatten(){
+            task.stack_used = stack::high_water_mark(task.stack_bottom, task.stack_bottom + task.stack_size);
+        }
+    }
+
+    /// Every task that existed when the snapshot was taken.
+    pub fn tasks(&self) -> impl Iterator<Item = &TaskStats>{
+        self.tasks.iter().flatten()
+    }
+
+    /// A task's run time since the earlier snapshot, or since it started if it didn't exist then.
+    fn run_time_since(&self, task: &TaskStats, earlier: Option<&Snapshot>) -> Duration{
+        let before = earlier
+            .and_then(|earlier| earlier.tasks[task.id])
+            .filter(|before| before.name == task.name && before.run_time <= task.run_time)
+            .map_or(Duration::ZERO, |before| before.run_time);
+
+        task.run_time - before
+    }
+
+    /// The CPU time used by every task since the earlier snapshot.
+    fn total_run_time_since(&self, earlier: Option<&Snapshot>) -> Duration{
+        self.tasks().map(|task| self.run_time_since(task, earlier)).sum()
+    }
+
+    /// A task's share of the CPU time used on every core since the earlier snapshot, or since the
+    /// scheduler started, in tenths of a percent.
+    pub fn task_load(&self, id: TaskId, earlier: Option<&Snapshot>) -> Option<u32>{
+        let task = self.tasks.get(id).copied().flatten()?;
+        let total = self.total_run_time_since(earlier);
+
+        if total.is_zero(){
+            return Some(0);
+        }
+
+        Some((self.run_time_since(&task, earlier).as_nanos() * 1000 / total.as_nanos()) as u32)
+    }
+
+    /// The share of time a core spent in its idle task, in tenths of a percent. A core that isn't online is all idle.
+    pub fn idle_load(&self, cpu: usize, earlier: Option<&Snapshot>) -> u32{
+        let task = match self.idle.get(cpu).copied().flatten().and_then(|idle| self.tasks[idle]){
+            Some(task) => task,
+            None => return 1000,
+        };
+
+        let elapsed = self.timestamp - earlier.map_or(Duration::ZERO, |earlier| earlier.timestamp);
+
+        if elapsed.is_zero(){
+            return 1000;
+        }
+
+        let idle = self.run_time_since(&task, earlier).as_nanos() * 1000 / elapsed.as_nanos();
+        core::cmp::min(idle, 1000) as u32
+    }
+}
+
+impl Default for Snapshot{
+    fn default() -> Snapshot{
+        Snapshot::new()
+    }
+}
+
+fn state_name(state: TaskState) -> &'static str{
+    match state{
+        TaskState::Free => "free",
+        TaskState::Ready => "ready",
+        TaskState::Running => "running",
+        TaskState::Blocked => "blocked",
+        TaskState::Exited => "exited",
+    }
+}
+
+/// Write tenths of a percent as a percentage with one decimal place.
+fn write_permille<W: Write>(out: &mut W, permille: u32) -> fmt::Result{
+    write!(out, "{:>3}.{}%", permille / 10, permille % 10)
+}
+
+/// Write a top-like table of every task in the snapshot. With an earlier snapshot, the CPU column
+/// is the load since then. Without one, it is the load since the scheduler started.
+pub fn write_report<W: Write>(out: &mut W, snapshot: &Snapshot, earlier: Option<&Snapshot>) -> fmt::Result{
+    let switches = snapshot.context_switches - earlier.map_or(0, |earlier| earlier.context_switches);
+
+    writeln!(out, "Uptime {}.{:03}s, {} context switches", snapshot.timestamp.as_secs(), snapshot.timestamp.subsec_millis(), switches)?;
+
+    //A line for each core the snapshot has an idle task for, which is every core that was online when it was taken.
+    for core in (0..MAX_CPUS).filter(|core| snapshot.idle[*core].is_some()){
+        let idle = snapshot.idle_load(core, earlier);
+
+        write!(out, "CPU {}: ", core)?;
+        write_permille(out, 1000 - idle)?;
+        write!(out, " busy, ")?;
+        write_permille(out, idle)?;
+        writeln!(out, " idle")?;
+    }
+
+    writeln!(out, " ID NAME             PRI STATE    CORE AFFINITY    CPU    RUN ms  READY ms  BLOCKED ms  SWITCHES  MISSED  OVERRUN  STACK")?;
+
+    for task in snapshot.tasks(){
+        write!(out, "{:>3} {:<16} {:>3} {:<8} {:>4} {:#010x} ", task.id, task.name, task.priority, state_name(task.state), task.cpu, task.affinity)?;
+        write_permille(out, snapshot.task_load(task.id, earlier).unwrap_or(0))?;
+        write!(out, " {:>9} {:>9} {:>11} {:>9}",
+            task.run_time.as_millis(),
+            task.ready_time.as_millis(),
+            task.blocked_time.as_millis(),
+            task.switches
+        )?;
+
+        //Deadline misses and budget overruns only mean anything for periodic tasks.
+        match task.periodic{
+            Some(periodic) => write!(out, " {:>7} {:>8}", periodic.deadline_misses, periodic.budget_overruns)?,
+            None => write!(out, " {:>7} {:>8}", "-", "-")?,
+        }
+
+        writeln!(out, "  {}/{}", task.stack_used, task.stack_size)?;
+    }
+
+    Ok(())
+}
+
+/// Buffers formatted text, and hands it to a ReportOutput a line at a time.
+struct LineWriter{
+    output: ReportOutput,
+    buffer: [u8; LINE_LENGTH],
+    length: usize
+}
+
+impl LineWriter{
+    fn new(output: ReportOutput) -> LineWriter{
+        LineWriter{
+            output,
+            buffer: [0; LINE_LENGTH],
+            length: 0
+        }
+    }
+
+    fn flush(&mut self){
+        if self.length == 0{
+            return;
+        }
+
+        //Only whole characters are ever buffered, so this can't fail.
+        if let Ok(line) = core::str::from_utf8(&self.buffer[..self.length]){
+            (self.output)(line);
+        }
+
+        self.length = 0;
+    }
+}
+
+impl Write for LineWriter{
+    fn write_str(&mut self, text: &str) -> fmt::Result{
+        for character in text.chars(){
+            if self.length + character.len_utf8() > LINE_LENGTH{
+                self.flush();
+            }
+
+            self.length += character.encode_utf8(&mut self.buffer[self.length..]).len();
+
+            if character == '\n'{
+                self.flush();
+            }
+        }
+
+        Ok(())
+    }
+}
+
+struct ReportSettings{
+    period: Duration,
+    output: ReportOutput
+}
+
+static REPORT_SETTINGS: Once<ReportSettings> = Once::new();
+
+#[link_section = ".stacks"]
+static REPORT_TASK_STACK: TaskStack<16384> = TaskStack::new();
+
+fn report_task(_argument: usize){
+    let settings = REPORT_SETTINGS.get().expect("The report task started without its settings");
+    let period = core::cmp::max(time::duration_to_ticks(settings.period), 1);
+
+    let mut previous = Snapshot::take();
+    let mut next = time::ticks();
+
+    loop{
+        //Sleep to a deadline rather than for a period, so the reports don't drift.
+        next += period;
+        scheduler::sleep_until(next);
+
+        let current = Snapshot::take();
+        let mut writer = LineWriter::new(settings.output);
+
+        let _ = write_report(&mut writer, &current, Some(&previous));
+        writer.flush();
+
+        previous = current;
+    }
+}
+
+/// Start a task that writes a report every period, showing the load over that period.
+///
+/// ```ignore
+/// fn print(line: &str){ CONSOLE.write_all(line.as_bytes()).ok(); }
+/// stats::start_report(2, Duration::from_secs(5), print)?;
+/// ```
+pub fn start_report(priority: u8, period: Duration, output: ReportOutput) -> Result<TaskId, &'static str>{
+    let stack = match REPORT_TASK_STACK.take(){
+        Some(stack) => stack,
+        None => return Err("The stats report is already running."),
+    };
+
+    REPORT_SETTINGS.call_once(|| ReportSettings{ period, output });
+
+    scheduler::spawn("stats", priority, report_task, 0, stack)
+}
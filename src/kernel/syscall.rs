@@ -0,0 +1,252 @@
+use core::time::Duration;
+
+use crate::exception::{self, TrapFrame};
+use crate::memory::mmu::{self, PAGE_SIZE};
+use crate::sync::{IrqSpinLock, MessageQueue, Once};
+
+use super::scheduler;
+
+/// The system calls EL0 tasks can make. The number goes in x8 and up to three arguments in
+/// x0-x2, then svc #0. The result comes back in x0, where a negative value is a SyscallError.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u64)]
+pub enum Syscall{
+    /// Give up the core to any other ready task of the same or higher priority.
+    Yield = 0,
+    /// Block for x0 milliseconds.
+    Sleep = 1,
+    /// Send x1 to the queue with handle x0, waiting up to x2 milliseconds for space.
+    QueueSend = 2,
+    /// Receive from the queue with handle x0 into the u64 at x1, waiting up to x2 milliseconds.
+    QueueReceive = 3,
+    /// Write x1 bytes from x0 to the console. Returns how many were written.
+    ConsoleWrite = 4,
+    /// End the calling task.
+    Exit = 5
+}
+
+/// Why a system call failed, as the negative value it returns in x0.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(i64)]
+pub enum SyscallError{
+    UnknownSyscall = -1,
+    /// A pointer argument isn't memory the task can access
+    BadAddress = -2,
+    /// No queue has been shared with that handle
+    BadHandle = -3,
+    /// A queue stayed full or empty for the whole timeout
+    TimedOut = -4,
+    NoConsole = -5,
+    /// The console driver failed
+    DeviceError = -6
+}
+
+/// Queue timeouts, in milliseconds, of this long wait forever. 0 doesn't wait at all.
+pub const WAIT_FOREVER: u64 = u64::MAX;
+
+/// The most queues that can be shared with EL0 tasks.
+pub const MAX_USER_QUEUES: usize = 16;
+
+/// A queue EL0 tasks can use through the queue system calls. Messages are a u64 each.
+pub trait UserQueue: Sync{
+    fn send(&self, message: u64, timeout: u64) -> Result<(), SyscallError>;
+    fn receive(&self, timeout: u64) -> Result<u64, SyscallError>;
+    /// Put a received message back at the front, when it couldn't be delivered.
+    fn put_back(&self, message: u64);
+}
+
+impl<const DEPTH: usize> UserQueue for MessageQueue<u64, DEPTH>{
+    fn send(&self, message: u64, timeout: u64) -> Result<(), SyscallError>{
+        let result = match timeout{
+            0 => self.try_send(message),
+            WAIT_FOREVER => {
+                MessageQueue::send(self, message);
+                Ok(())
+            },
+            milliseconds => self.send_timeout(message, Duration::from_millis(milliseconds)),
+        };
+
+        result.map_err(|_| SyscallError::TimedOut)
+    }
+
+    fn receive(&self, timeout: u64) -> Result<u64, SyscallError>{
+        match timeout{
+            0 => self.try_receive().ok_or(SyscallError::TimedOut),
+            WAIT_FOREVER => Ok(MessageQueue::receive(self)),
+            milliseconds => self.receive_timeout(Duration::from_millis(milliseconds)).map_err(|_| SyscallError::TimedOut),
+        }
+    }
+
+    fn put_back(&self, message: u64){
+        //The slot it came from is free unless a sender has taken it since, so this rarely waits.
+        self.send_to_front(message);
+    }
+}
+
+/// Queues shared with EL0 tasks. A queue's handle is its index.
+static USER_QUEUES: IrqSpinLock<[Option<&'static dyn UserQueue>; MAX_USER_QUEUES]> = IrqSpinLock::new([None; MAX_USER_QUEUES]);
+
+/// Writes bytes to the console, and returns how many it wrote.
+pub type ConsoleWriter = fn(&[u8]) -> Result<usize, &'static str>;
+
+/// Where ConsoleWrite sends its bytes.
+static CONSOLE: Once<ConsoleWriter> = Once::new();
+
+impl Syscall{
+    pub fn from_number(number: u64) -> Option<Syscall>{
+        match number{
+            0 => Some(Syscall::Yield),
+            1 => Some(Syscall::Sleep),
+            2 => Some(Syscall::QueueSend),
+            3 => Some(Syscall::QueueReceive),
+            4 => Some(Syscall::ConsoleWrite),
+            5 => Some(Syscall::Exit),
+            _ => None,
+        }
+    }
+}
+
+impl SyscallError{
+    pub fn from_code(code: i64) -> SyscallError{
+        match code{
+            -2 => SyscallError::BadAddress,
+            -3 => SyscallError::BadHandle,
+            -4 => SyscallError::TimedOut,
+            -5 => SyscallError::NoConsole,
+            -6 => SyscallError::DeviceError,
+            _ => SyscallError::UnknownSyscall,
+        }
+    }
+
+    pub fn message(self) -> &'static str{
+        match self{
+            SyscallError::UnknownSyscall => "There is no such system call.",
+            SyscallError::BadAddress => "The task can't access the memory it passed.",
+            SyscallError::BadHandle => "No queue has been shared with that handle.",
+            SyscallError::TimedOut => "The queue operation timed out.",
+            SyscallError::NoConsole => "No console has been set.",
+            SyscallError::DeviceError => "The console failed to write.",
+        }
+    }
+}
+
+/// Let EL0 tasks use a queue. Returns the handle they pass to the queue system calls.
+pub fn share_queue(queue: &'static dyn UserQueue) -> Result<usize, &'static str>{
+    let mut queues = USER_QUEUES.lock();
+
+    match queues.iter().position(|slot| slot.is_none()){
+        Some(handle) => {
+            queues[handle] = Some(queue);
+            Ok(handle)
+        },
+        None => Err("Too many queues are shared with EL0 tasks."),
+    }
+}
+
+/// Choose where ConsoleWrite sends its bytes. Can only be set once.
+pub fn set_console(write: ConsoleWriter) -> Result<(), &'static str>{
+    if CONSOLE.is_completed(){
+        return Err("The console has already been set.");
+    }
+
+    CONSOLE.call_once(|| write);
+    Ok(())
+}
+
+/// Check the calling task could access every page of a buffer itself, so the kernel never
+/// touches memory on its behalf that it couldn't touch directly.
+fn check_user_buffer(address: u64, length: u64, write: bool) -> Result<(), SyscallError>{
+    let (address, length) = (address as usize, length as usize);
+    let end = address.checked_add(length).ok_or(SyscallError::BadAddress)?;
+
+    if length == 0{
+        return Ok(());
+    }
+
+    let mut page = address & !(PAGE_SIZE - 1);
+
+    while page < end{
+        if !mmu::is_user_accessible(page, write){
+            return Err(SyscallError::BadAddress);
+        }
+
+        page += PAGE_SIZE;
+    }
+
+    Ok(())
+}
+
+fn user_queue(handle: u64) -> Result<&'static dyn UserQueue, SyscallError>{
+    let queues = USER_QUEUES.lock();
+
+    match queues.get(handle as usize){
+        Some(Some(queue)) => Ok(*queue),
+        _ => Err(SyscallError::BadHandle),
+    }
+}
+
+fn dispatch(number: u64, arguments: [u64; 3]) -> Result<u64, SyscallError>{
+    match Syscall::from_number(number).ok_or(SyscallError::UnknownSyscall)?{
+        Syscall::Yield => {
+            scheduler::yield_now();
+            Ok(0)
+        },
+        Syscall::Sleep => {
+            scheduler::sleep(Duration::from_millis(arguments[0]));
+            Ok(0)
+        },
+        Syscall::QueueSend => {
+            user_queue(arguments[0])?.send(arguments[1], arguments[2])?;
+            Ok(0)
+        },
+        Syscall::QueueReceive => {
+            let queue = user_queue(arguments[0])?;
+
+            if !arguments[1].is_multiple_of(8){
+                return Err(SyscallError::BadAddress);
+            }
+
+            //Fail early on a bad buffer, but check again once we have a message, since the wait can be long.
+            check_user_buffer(arguments[1], 8, true)?;
+            let message = queue.receive(arguments[2])?;
+
+            if let Err(error) = check_user_buffer(arguments[1], 8, true){
+                queue.put_back(message);
+                return Err(error);
+            }
+
+            unsafe{
+                core::ptr::write_volatile(arguments[1] as *mut u64, message);
+            }
+
+            Ok(0)
+        },
+        Syscall::ConsoleWrite => {
+            let write = CONSOLE.get().ok_or(SyscallError::NoConsole)?;
+
+            check_user_buffer(arguments[0], arguments[1], false)?;
+            let bytes = unsafe{ core::slice::from_raw_parts(arguments[0] as *const u8, arguments[1] as usize) };
+
+            write(bytes).map(|written| written as u64).map_err(|_| SyscallError::DeviceError)
+        },
+        Syscall::Exit => scheduler::exit(),
+    }
+}
+
+/// Handle an svc from an EL0 task. Runs on the task's kernel stack, so a system call can block
+/// like any kernel code, and it returns to the task with the result in x0.
+pub(crate) fn handle_svc(frame: &mut TrapFrame){
+    let number = frame.regs[8];
+    let arguments = [frame.regs[0], frame.regs[1], frame.regs[2]];
+
+    //The task's state is saved, so interrupts can come in while the call runs. They must be
+    //masked again before the vectors restore it.
+    exception::local_irq_enable();
+    let result = dispatch(number, arguments);
+    exception::local_irq_disable();
+
+    frame.regs[0] = match result{
+        Ok(value) => value,
+        Err(error) => error as i64 as u64,
+    };
+}
@@ -0,0 +1,256 @@
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::memory::address_space::AddressSpace;
+
+use super::cpu::MAX_CPUS;
+use super::realtime::PeriodicState;
+
+/// Tasks are identified by their slot in the scheduler's task table.
+pub type TaskId = usize;
+
+/// The size of the task table. Wait queues track their waiters in a 32 bit mask, so this can't grow past 32.
+pub const MAX_TASKS: usize = 32;
+
+/// Priorities run from 0, the idle task, up to PRIORITY_LEVELS - 1. Higher numbers are more urgent.
+pub const PRIORITY_LEVELS: usize = 32;
+pub const IDLE_PRIORITY: u8 = 0;
+
+/// An affinity mask allowing every core.
+pub const ALL_CPUS: u32 = (1 << MAX_CPUS) - 1;
+
+/// Periodic tasks all run at this priority, ordered among themselves by the scheduling policy.
+/// Tasks above it preempt them, so admission control only holds if those tasks are short.
+pub const PERIODIC_PRIORITY: u8 = 24;
+
+/// The smallest stack spawn accepts. The initial TrapFrame alone takes 272 bytes.
+pub const MINIMUM_STACK_SIZE: usize = 1024;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TaskState{
+    /// The slot is unused
+    Free,
+    /// Waiting in a ready queue for a core
+    Ready,
+    Running,
+    /// Waiting on a wait queue, a timeout, or both
+    Blocked,
+    /// Finished, but still on its stack until the scheduler switches away
+    Exited
+}
+
+/// Why a blocked task was made ready again.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WakeReason{
+    Notified,
+    TimedOut
+}
+
+/// Memory for a task's stack. Declare one as a static and hand it to scheduler::spawn.
+#[repr(C, align(16))]
+pub struct TaskStack<const SIZE: usize>{
+    memory: UnsafeCell<[u8; SIZE]>,
+    taken: AtomicBool
+}
+
+// The memory is handed out at most once, by take.
+unsafe impl<const SIZE: usize> Sync for TaskStack<SIZE>{}
+
+impl<const SIZE: usize> TaskStack<SIZE>{
+    pub const fn new() -> TaskStack<SIZE>{
+        TaskStack{
+            memory: UnsafeCell::new([0; SIZE]),
+            taken: AtomicBool::new(false)
+        }
+    }
+
+    /// Hand out the stack memory. Only the first call succeeds, so two tasks can never share a stack.
+    #[allow(clippy::mut_from_ref)]
+    pub fn take(&'static self) -> Option<&'static mut [u8]>{
+        match self.taken.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed){
+            Ok(_) => Some(unsafe{ &mut *self.memory.get() }),
+            Err(_) => None,
+        }
+    }
+}
+
+impl<const SIZE: usize> Default for TaskStack<SIZE>{
+    fn default() -> TaskStack<SIZE>{
+        TaskStack::new()
+    }
+}
+
+/// A task control block.
+pub(in crate::kernel) struct Task{
+    pub name: &'static str,
+    pub state: TaskState,
+    /// The priority the task was given
+    pub base_priority: u8,
+    /// The priority it runs at, which priority inheritance can raise above base_priority
+    pub priority: u8,
+    /// The saved TrapFrame, while the task isn't running
+    pub frame: usize,
+    /// The core whose run queue the task is on, or last ran on
+    pub cpu: usize,
+    /// Bit n is set if the task may run on core n
+    pub affinity: u32,
+    /// The lowest usable stack address, above the guard page if there is one
+    pub stack_bottom: usize,
+    pub stack_top: usize,
+    /// The unmapped page below the stack, or 0
+    pub guard_page: usize,
+    /// The tick a blocked task times out at, if it is on the delay list
+    pub wake_tick: u64,
+    pub wake_reason: WakeReason,
+    /// The address of the WaitQueue the task is blocked on, or 0
+    pub waiting_on: usize,
+    pub ready_next: Option<TaskId>,
+    pub ready_prev: Option<TaskId>,
+    pub delay_next: Option<TaskId>,
+    pub delayed: bool,
+    /// The number of mutexes the task holds. Inherited priority is dropped once this reaches zero.
+    pub held_mutexes: usize,
+    /// The owner of the mutex the task is blocked on, so inherited priority can be passed down a chain of owners
+    pub blocked_on_owner: Option<TaskId>,
+    /// Counter cycles spent running, ready and blocked, up to state_since
+    pub run_cycles: u64,
+    pub ready_cycles: u64,
+    pub blocked_cycles: u64,
+    /// The counter value when the task last changed state
+    pub state_since: u64,
+    /// The number of times the task has been switched to
+    pub switches: u64,
+    /// The timing and current job of a periodic task
+    pub periodic: Option<PeriodicState>,
+    /// The translation tables of an EL0 task. Other tasks use the kernel's.
+    pub address_space: Option<AddressSpace>
+}
+
+impl Task{
+    /// Counter cycles spent running, ready and blocked, counting the current state up to now.
+    pub fn accounted_cycles(&self, now: u64) -> (u64, u64, u64){
+        let elapsed = now.saturating_sub(self.state_since);
+
+        match self.state{
+            TaskState::Running => (self.run_cycles + elapsed, self.ready_cycles, self.blocked_cycles),
+            TaskState::Ready => (self.run_cycles, self.ready_cycles + elapsed, self.blocked_cycles),
+            TaskState::Blocked => (self.run_cycles, self.ready_cycles, self.blocked_cycles + elapsed),
+            _ => (self.run_cycles, self.ready_cycles, self.blocked_cycles),
+        }
+    }
+
+    /// Change state, charging the time since the last change to the state being left.
+    pub fn set_state(&mut self, state: TaskState, now: u64){
+        (self.run_cycles, self.ready_cycles, self.blocked_cycles) = self.accounted_cycles(now);
+        self.state_since = now;
+        self.state = state;
+    }
+
+    pub const fn new() -> Task{
+        Task{
+            name: "",
+            state: TaskState::Free,
+            base_priority: IDLE_PRIORITY,
+            priority: IDLE_PRIORITY,
+            frame: 0,
+            cpu: 0,
+            affinity: ALL_CPUS,
+            stack_bottom: 0,
+            stack_top: 0,
+            guard_page: 0,
+            wake_tick: 0,
+            wake_reason: WakeReason::Notified,
+            waiting_on: 0,
+            ready_next: None,
+            ready_prev: None,
+            delay_next: None,
+            delayed: false,
+            held_mutexes: 0,
+            blocked_on_owner: None,
+            run_cycles: 0,
+            ready_cycles: 0,
+            blocked_cycles: 0,
+            state_since: 0,
+            switches: 0,
+            periodic: None,
+            address_space: None
+        }
+    }
+}
+
+/// A task declared with the task! macro. The macro places one of these in the .tasks section,
+/// and the scheduler starts every one of them when it starts.
+#[repr(C)]
+pub struct StaticTask{
+    pub name: &'static str,
+    pub priority: u8,
+    pub entry: fn(usize),
+    pub argument: usize,
+    /// Hands out the task's stack. The macro generates a closure over its own TaskStack.
+    pub stack: fn() -> Option<&'static mut [u8]>,
+    /// The task's ID, once it has been started. usize::MAX until then.
+    pub id: AtomicUsize
+}
+
+impl StaticTask{
+    /// The task's ID, once the scheduler has started it.
+    pub fn id(&self) -> Option<TaskId>{
+        match self.id.load(Ordering::Acquire){
+            usize::MAX => None,
+            id => Some(id),
+        }
+    }
+}
+
+extern "C" {
+    static __tasks_start: u8;
+    static __tasks_end: u8;
+}
+
+/// Every task declared with the task! macro, as collected by the linker.
+pub(in crate::kernel) fn static_tasks() -> &'static [StaticTask]{
+    unsafe{
+        let start = &__tasks_start as *const u8 as usize;
+        let end = &__tasks_end as *const u8 as usize;
+        let count = (end - start) / core::mem::size_of::<StaticTask>();
+
+        core::slice::from_raw_parts(start as *const StaticTask, count)
+    }
+}
+
+/// Declare a task with a statically allocated stack. It is started automatically, along with
+/// every other declared task, when main starts the scheduler. The stack size is checked at compile time.
+///
+/// ```ignore
+/// task!(BLINKY, blink_led, priority = 5, stack_size = 4096);
+/// task!(LOGGER, log_forever, priority = 2, stack_size = 8192, argument = 1);
+/// ```
+#[macro_export]
+macro_rules! task{
+    ($name:ident, $entry:path, priority = $priority:expr, stack_size = $stack_size:expr) => {
+        $crate::kernel::task::task!($name, $entry, priority = $priority, stack_size = $stack_size, argument = 0);
+    };
+    ($name:ident, $entry:path, priority = $priority:expr, stack_size = $stack_size:expr, argument = $argument:expr) => {
+        #[used]
+        #[link_section = ".tasks"]
+        static $name: $crate::kernel::task::StaticTask = {
+            const _: () = assert!($stack_size >= $crate::kernel::task::MINIMUM_STACK_SIZE, "The task stack is smaller than MINIMUM_STACK_SIZE");
+            const _: () = assert!($stack_size % 16 == 0, "The task stack size must be a multiple of 16");
+            const _: () = assert!(($priority as usize) < $crate::kernel::task::PRIORITY_LEVELS, "The task priority is out of range");
+
+            #[link_section = ".stacks"]
+            static STACK: $crate::kernel::task::TaskStack<{ $stack_size }> = $crate::kernel::task::TaskStack::new();
+
+            $crate::kernel::task::StaticTask{
+                name: stringify!($name),
+                priority: $priority,
+                entry: $entry,
+                argument: $argument,
+                stack: || STACK.take(),
+                id: core::sync::atomic::AtomicUsize::new(usize::MAX)
+            }
+        };
+    };
+}
+
+pub use task;
@@ -0,0 +1,166 @@
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+
+/// How often the scheduler tick fires.
+pub const TICK_HZ: u64 = 1000;
+
+/// The number of ticks since the scheduler started.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// The counter value at tick zero. Tick n is due at TICK_BASE + n periods, so the tick
+/// count can always be worked out from the counter, however many interrupts were skipped.
+static TICK_BASE: AtomicU64 = AtomicU64::new(0);
+
+/// Set while the periodic tick is stopped for tickless idle.
+static TICK_STOPPED: AtomicBool = AtomicBool::new(false);
+
+/// The frequency of the generic timer's system counter, in Hz.
+pub fn counter_frequency() -> u64{
+    let frequency: u64;
+    unsafe{
+        asm!("mrs {}, cntfrq_el0", out(reg) frequency, options(nomem, nostack));
+    }
+    frequency
+}
+
+/// The current value of the system counter.
+pub fn counter() -> u64{
+    let now: u64;
+    unsafe{
+        asm!("isb", "mrs {}, cntpct_el0", out(reg) now, options(nomem, nostack));
+    }
+    now
+}
+
+/// The number of counter cycles in one tick.
+fn counter_cycles_per_tick() -> u64{
+    counter_frequency() / TICK_HZ
+}
+
+/// The number of counter cycles in the given number of ticks.
+pub fn ticks_to_counter_cycles(ticks: u64) -> u64{
+    ticks.saturating_mul(counter_cycles_per_tick())
+}
+
+/// The number of counter cycles in a duration.
+pub fn duration_to_counter_cycles(duration: Duration) -> u64{
+    let cycles = duration.as_nanos() * counter_frequency() as u128 / 1_000_000_000;
+
+    core::cmp::min(cycles, u64::MAX as u128) as u64
+}
+
+/// The length of time the given number of counter cycles takes.
+pub fn counter_cycles_to_duration(cycles: u64) -> Duration{
+    let nanoseconds = cycles as u128 * 1_000_000_000 / counter_frequency() as u128;
+
+    Duration::from_nanos(core::cmp::min(nanoseconds, u64::MAX as u128) as u64)
+}
+
+/// The length of the given number of ticks.
+pub fn ticks_to_duration(ticks: u64) -> Duration{
+    Duration::from_nanos(ticks.saturating_mul(1_000_000_000 / TICK_HZ))
+}
+
+/// The number of scheduler ticks since the scheduler started.
+pub fn ticks() -> u64{
+    //While tickless idle has the boot core's tick stopped, other cores work the count out from the counter.
+    if TICK_STOPPED.load(Ordering::Acquire){
+        return core::cmp::max(TICKS.load(Ordering::Acquire), tick_from_counter());
+    }
+
+    TICKS.load(Ordering::Acquire)
+}
+
+/// Convert a duration to ticks, rounding up so a wait is never shorter than asked for.
+pub fn duration_to_ticks(duration: Duration) -> u64{
+    let nanoseconds = duration.as_nanos();
+    let ticks = nanoseconds.div_ceil(1_000_000_000 / TICK_HZ as u128);
+
+    core::cmp::min(ticks, u64::MAX as u128) as u64
+}
+
+/// The tick at which a wait starting now for the given duration should end.
+pub fn deadline_after(duration: Duration) -> u64{
+    ticks().saturating_add(duration_to_ticks(duration))
+}
+
+fn write_compare_value(value: u64){
+    unsafe{
+        asm!("msr cntp_cval_el0, {}", in(reg) value, options(nostack));
+    }
+}
+
+/// The tick the counter is currently in.
+fn tick_from_counter() -> u64{
+    counter().saturating_sub(TICK_BASE.load(Ordering::Relaxed)) / counter_cycles_per_tick()
+}
+
+/// The counter value a tick is due at.
+fn tick_deadline(tick: u64) -> u64{
+    TICK_BASE.load(Ordering::Relaxed).saturating_add(ticks_to_counter_cycles(tick))
+}
+
+fn enable_timer(){
+    //ENABLE, with IMASK clear
+    unsafe{
+        asm!("msr cntp_ctl_el0, {}", "isb", in(reg) 1u64, options(nostack));
+    }
+}
+
+/// Start the EL1 physical timer firing once a tick. Called on the boot core, which keeps the tick count.
+pub fn start_tick(){
+    TICK_BASE.store(counter(), Ordering::Relaxed);
+    write_compare_value(tick_deadline(1));
+    enable_timer();
+}
+
+/// Arm the calling secondary core's timer for the next tick, in step with the boot core's.
+/// Secondary cores only use their tick to time slice, so they call this again from every tick.
+pub fn start_local_tick(){
+    write_compare_value(tick_deadline(tick_from_counter() + 1));
+    enable_timer();
+}
+
+/// Stop the calling secondary core's tick, while it has nothing to time slice.
+pub fn stop_local_tick(){
+    write_compare_value(u64::MAX);
+}
+
+/// Bring the tick count up to date with the counter, and arm the timer for the next tick.
+/// Returns the tick count before and after, which differ by more than one if ticks were skipped.
+/// Deadlines are whole periods from the base, so the tick doesn't drift with interrupt latency.
+pub fn advance_tick() -> (u64, u64){
+    let now = tick_from_counter();
+    write_compare_value(tick_deadline(now + 1));
+    TICK_STOPPED.store(false, Ordering::Release);
+
+    let previous = TICKS.swap(now, Ordering::AcqRel);
+
+    (previous, core::cmp::max(previous, now))
+}
+
+/// Correct the tick count after the tick was stopped, without touching the timer.
+pub fn resync_ticks(){
+    TICKS.fetch_max(tick_from_counter(), Ordering::AcqRel);
+}
+
+/// Stop the periodic tick, and have the timer fire at the given tick instead.
+/// A tick that has already passed fires straight away.
+pub fn stop_tick_until(tick: u64){
+    TICK_STOPPED.store(true, Ordering::Release);
+    write_compare_value(tick_deadline(tick));
+}
+
+/// True while tickless idle has the boot core's tick stopped.
+pub fn is_tick_stopped() -> bool{
+    TICK_STOPPED.load(Ordering::Acquire)
+}
+
+/// Restart the periodic tick if tickless idle stopped it, and correct the tick count.
+pub fn resume_tick(){
+    if TICK_STOPPED.swap(false, Ordering::AcqRel){
+        resync_ticks();
+        write_compare_value(tick_deadline(ticks() + 1));
+    }
+}
@@ -0,0 +1,295 @@
+use core::cell::UnsafeCell;
+use core::time::Duration;
+
+use crate::sync::{IrqSpinLock, Semaphore};
+
+use super::scheduler;
+use super::task::task;
+use super::time;
+
+/// The number of slots in the timer wheel. A timer lands in the slot for its expiry tick modulo this,
+/// so the tick only ever looks at one slot. Must be a power of two.
+const WHEEL_SIZE: usize = 256;
+
+/// The list a timer sits on while the timer task is working through its slot.
+const PENDING_LIST: usize = WHEEL_SIZE;
+
+/// A timer callback. It is passed the context value the timer was created with.
+pub type TimerCallback = fn(usize);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TimerMode{
+    /// Fire once, then stop
+    OneShot,
+    /// Fire every period until stopped
+    AutoReload
+}
+
+/// The parts of a timer that belong to the wheel. Only touched with the wheel locked.
+struct TimerLinks{
+    next: Option<&'static SoftwareTimer>,
+    prev: Option<&'static SoftwareTimer>,
+    /// The list the timer is on, while it is active
+    list: Option<usize>,
+    expiry: u64,
+    period: Duration
+}
+
+/// A software timer. Declare one as a static, then start it. When it expires, its callback
+/// runs in the timer task, so callbacks can block briefly but shouldn't take long, since
+/// they delay every other timer.
+///
+/// ```ignore
+/// static BLINK: SoftwareTimer = SoftwareTimer::new("blink", TimerMode::AutoReload, Duration::from_millis(500), toggle_led, 0);
+/// BLINK.start();
+/// ```
+pub struct SoftwareTimer{
+    name: &'static str,
+    mode: TimerMode,
+    callback: TimerCallback,
+    context: usize,
+    links: UnsafeCell<TimerLinks>
+}
+
+// The links are only accessed with the wheel locked.
+unsafe impl Sync for SoftwareTimer{}
+
+struct TimerWheel{
+    /// The head of each slot's list, plus the pending list
+    lists: [Option<&'static SoftwareTimer>; WHEEL_SIZE + 1],
+    /// The last tick the timer task has finished with
+    processed: u64
+}
+
+static WHEEL: IrqSpinLock<TimerWheel> = IrqSpinLock::new(TimerWheel::new());
+
+/// Given by the tick when a slot with timers in it comes round.
+static TIMER_TASK_SIGNAL: Semaphore = Semaphore::new(0, 1);
+
+/// Timer callbacks run above every application task, so they fire on time.
+const TIMER_TASK_PRIORITY: u8 = 30;
+
+//The timer task starts with the scheduler. Timers can be started before then, but won't fire until it runs.
+task!(TIMER_TASK, timer_task, priority = TIMER_TASK_PRIORITY, stack_size = 8192);
+
+impl TimerWheel{
+    const fn new() -> TimerWheel{
+        TimerWheel{
+            lists: [None; WHEEL_SIZE + 1],
+            processed: 0
+        }
+    }
+
+    /// A timer's links. Callers must hold the wheel lock, and not keep two borrows of the same timer.
+    #[allow(clippy::mut_from_ref)]
+    fn links(timer: &'static SoftwareTimer) -> &'static mut TimerLinks{
+        unsafe{ &mut *timer.links.get() }
+    }
+
+    fn push(&mut self, list: usize, timer: &'static SoftwareTimer){
+        let head = self.lists[list];
+
+        if let Some(head) = head{
+            Self::links(head).prev = Some(timer);
+        }
+
+        let links = Self::links(timer);
+        links.next = head;
+        links.prev = None;
+        links.list = Some(list);
+
+        self.lists[list] = Some(timer);
+    }
+
+    fn unlink(&mut self, timer: &'static SoftwareTimer){
+        let links = Self::links(timer);
+
+        let list = match links.list.take(){
+            Some(list) => list,
+            None => return,
+        };
+
+        let next = links.next.take();
+        let prev = links.prev.take();
+
+        match prev{
+            Some(prev) => Self::links(prev).next = next,
+            None => self.lists[list] = next,
+        }
+
+        if let Some(next) = next{
+            Self::links(next).prev = prev;
+        }
+    }
+
+    /// Put a timer in the slot for its expiry tick.
+    fn arm(&mut self, timer: &'static SoftwareTimer, expiry: u64){
+        self.unlink(timer);
+        Self::links(timer).expiry = expiry;
+        self.push(expiry as usize & (WHEEL_SIZE - 1), timer);
+    }
+}
+
+impl SoftwareTimer{
+    pub const fn new(name: &'static str, mode: TimerMode, period: Duration, callback: TimerCallback, context: usize) -> SoftwareTimer{
+        SoftwareTimer{
+            name,
+            mode,
+            callback,
+            context,
+            links: UnsafeCell::new(TimerLinks{
+                next: None,
+                prev: None,
+                list: None,
+                expiry: 0,
+                period
+            })
+        }
+    }
+
+    pub fn name(&self) -> &'static str{
+        self.name
+    }
+
+    pub fn mode(&self) -> TimerMode{
+        self.mode
+    }
+
+    /// Start the timer, so it expires one period from now. Restarts it if it is already running.
+    /// Safe to call from an interrupt handler.
+    pub fn start(&'static self){
+        let mut wheel = WHEEL.lock();
+        let period = TimerWheel::links(self).period;
+
+        //A timer always waits at least one full tick.
+        let expiry = time::ticks() + core::cmp::max(time::duration_to_ticks(period), 1);
+        wheel.arm(self, expiry);
+        drop(wheel);
+
+        //The boot core runs the timers, and may have stopped its tick without knowing about this one.
+        scheduler::kick_timekeeper();
+    }
+
+    /// Restart the timer from now. The same as start.
+    pub fn reset(&'static self){
+        self.start();
+    }
+
+    /// Stop the timer. Its callback won't run again until it is restarted.
+    /// Safe to call from an interrupt handler.
+    pub fn stop(&'static self){
+        WHEEL.lock().unlink(self);
+    }
+
+    /// Change the period, and restart the timer with it.
+    pub fn change_period(&'static self, period: Duration){
+        let wheel = WHEEL.lock();
+        TimerWheel::links(self).period = period;
+        drop(wheel);
+
+        self.start();
+    }
+
+    pub fn period(&'static self) -> Duration{
+        let _wheel = WHEEL.lock();
+        TimerWheel::links(self).period
+    }
+
+    pub fn is_active(&'static self) -> bool{
+        let _wheel = WHEEL.lock();
+        TimerWheel::links(self).list.is_some()
+    }
+
+    /// The tick the timer next expires at, if it is running.
+    pub fn expiry_tick(&'static self) -> Option<u64>{
+        let _wheel = WHEEL.lock();
+        let links = TimerWheel::links(self);
+
+        links.list.map(|_| links.expiry)
+    }
+}
+
+/// Called by the scheduler tick, outside its lock. Only looks at one slot, so the cost doesn't
+/// grow with the number of timers. If ticks were skipped, the timer task is woken to catch up.
+pub(in crate::kernel) fn tick(previous: u64, now: u64){
+    let has_timers = now > previous + 1 || WHEEL.lock().lists[now as usize & (WHEEL_SIZE - 1)].is_some();
+
+    if has_timers{
+        //The signal only counts to one, so it may already be given.
+        let _ = TIMER_TASK_SIGNAL.give();
+    }
+}
+
+/// The first tick after now with a timer in its slot, looking at most one revolution ahead.
+/// The timer there may be a revolution or more further off, in which case we wake early, which is harmless.
+pub(in crate::kernel) fn next_expiry(now: u64) -> Option<u64>{
+    let wheel = WHEEL.lock();
+
+    (now + 1..=now + WHEEL_SIZE as u64).find(|tick| wheel.lists[*tick as usize & (WHEEL_SIZE - 1)].is_some())
+}
+
+/// Fire every timer in one slot whose expiry has been reached. Timers a whole wheel
+/// revolution or more away are put back.
+fn process_slot(tick: u64){
+    let slot = tick as usize & (WHEEL_SIZE - 1);
+
+    //Move the slot to the pending list, so timers can be started and stopped
+    //from their callbacks, or from elsewhere, while we work through it.
+    let mut wheel = WHEEL.lock();
+    while let Some(timer) = wheel.lists[slot]{
+        wheel.unlink(timer);
+        wheel.push(PENDING_LIST, timer);
+    }
+    drop(wheel);
+
+    loop{
+        let mut wheel = WHEEL.lock();
+
+        let timer = match wheel.lists[PENDING_LIST]{
+            Some(timer) => timer,
+            None => break,
+        };
+
+        let links = TimerWheel::links(timer);
+        let expiry = links.expiry;
+
+        if expiry > tick{
+            wheel.arm(timer, expiry);
+            continue;
+        }
+
+        match timer.mode{
+            //Reload from the expiry rather than from now, so a periodic timer doesn't drift.
+            TimerMode::AutoReload => {
+                let period = core::cmp::max(time::duration_to_ticks(links.period), 1);
+                wheel.arm(timer, expiry + period);
+            },
+            TimerMode::OneShot => wheel.unlink(timer),
+        }
+
+        drop(wheel);
+
+        (timer.callback)(timer.context);
+    }
+}
+
+fn timer_task(_argument: usize){
+    loop{
+        TIMER_TASK_SIGNAL.take();
+
+        //Catch up on every tick since we last ran. Slots with nothing in them cost next to nothing.
+        loop{
+            let mut wheel = WHEEL.lock();
+
+            if wheel.processed >= time::ticks(){
+                break;
+            }
+
+            wheel.processed += 1;
+            let tick = wheel.processed;
+            drop(wheel);
+
+            process_slot(tick);
+        }
+    }
+}
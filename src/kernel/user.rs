@@ -0,0 +1,111 @@
+use core::arch::asm;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use super::syscall::{Syscall, SyscallError};
+
+/// The stack an EL0 task runs on. It is page aligned and a whole number of pages, since whole
+/// pages are opened up to EL0. Declare one as a static in the .stacks section and hand it to scheduler::spawn_user.
+#[repr(C, align(4096))]
+pub struct UserStack<const SIZE: usize>{
+    memory: UnsafeCell<[u8; SIZE]>,
+    taken: AtomicBool
+}
+
+// The memory is handed out at most once, by take.
+unsafe impl<const SIZE: usize> Sync for UserStack<SIZE>{}
+
+impl<const SIZE: usize> UserStack<SIZE>{
+    pub const fn new() -> UserStack<SIZE>{
+        assert!(SIZE > 0 && SIZE.is_multiple_of(crate::memory::mmu::PAGE_SIZE), "A user stack must be a whole number of pages");
+
+        UserStack{
+            memory: UnsafeCell::new([0; SIZE]),
+            taken: AtomicBool::new(false)
+        }
+    }
+
+    /// Hand out the stack memory. Only the first call succeeds, so two tasks can never share a stack.
+    #[allow(clippy::mut_from_ref)]
+    pub fn take(&'static self) -> Option<&'static mut [u8]>{
+        match self.taken.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed){
+            Ok(_) => Some(unsafe{ &mut *self.memory.get() }),
+            Err(_) => None,
+        }
+    }
+}
+
+impl<const SIZE: usize> Default for UserStack<SIZE>{
+    fn default() -> UserStack<SIZE>{
+        UserStack::new()
+    }
+}
+
+/// Make a system call from an EL0 task. A negative result is a SyscallError.
+#[inline(always)]
+fn syscall(number: Syscall, arguments: [u64; 3]) -> Result<u64, &'static str>{
+    let result: i64;
+
+    unsafe{
+        asm!(
+            "svc #0",
+            inlateout("x0") arguments[0] => result,
+            in("x1") arguments[1],
+            in("x2") arguments[2],
+            in("x8") number as u64,
+            options(nostack)
+        );
+    }
+
+    if result < 0{
+        return Err(SyscallError::from_code(result).message());
+    }
+
+    Ok(result as u64)
+}
+
+/// EL0 tasks start here, with their entry point and argument in x0 and x1.
+pub(in crate::kernel) extern "C" fn user_task_entry(entry: usize, argument: usize) -> !{
+    let entry = unsafe{ core::mem::transmute::<usize, fn(usize)>(entry) };
+
+    entry(argument);
+
+    exit()
+}
+
+/// Give up the core to any other ready task of the same or higher priority.
+pub fn yield_now(){
+    let _ = syscall(Syscall::Yield, [0; 3]);
+}
+
+/// Block for at least the given number of milliseconds.
+pub fn sleep(milliseconds: u64){
+    let _ = syscall(Syscall::Sleep, [milliseconds, 0, 0]);
+}
+
+/// Send a message to a queue the kernel shared with syscall::share_queue. Waits up to timeout
+/// milliseconds for space, or forever with syscall::WAIT_FOREVER.
+pub fn queue_send(handle: usize, message: u64, timeout: u64) -> Result<(), &'static str>{
+    syscall(Syscall::QueueSend, [handle as u64, message, timeout]).map(|_| ())
+}
+
+/// Receive a message from a shared queue. Waits up to timeout milliseconds for one, or forever with syscall::WAIT_FOREVER.
+pub fn queue_receive(handle: usize, timeout: u64) -> Result<u64, &'static str>{
+    let mut message = 0u64;
+
+    syscall(Syscall::QueueReceive, [handle as u64, &mut message as *mut u64 as u64, timeout])?;
+
+    Ok(message)
+}
+
+/// Write to the console. Returns how many bytes were written, which may be fewer than asked.
+pub fn console_write(bytes: &[u8]) -> Result<usize, &'static str>{
+    syscall(Syscall::ConsoleWrite, [bytes.as_ptr() as u64, bytes.len() as u64, 0]).map(|written| written as usize)
+}
+
+/// End the calling task.
+pub fn exit() -> !{
+    let _ = syscall(Syscall::Exit, [0; 3]);
+
+    unreachable!("An exited task was scheduled again")
+}
@@ -0,0 +1,147 @@
+use core::arch::asm;
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::time::Duration;
+
+use crate::exception;
+
+use super::scheduler;
+use super::task::{TaskId, WakeReason};
+use super::time;
+
+/// A queue of tasks blocked until some condition becomes true.
+/// Once the scheduler is running, a waiter is taken off the core until it is notified or times out.
+/// Before then, and in interrupt handlers, a waiter sleeps the core with WFE instead. notify sends
+/// an event as well, which wakes every sleeping core so it can re-check its condition.
+pub struct WaitQueue{
+    /// Bit n is set while task n is blocked on this queue. Only changed with the scheduler locked.
+    waiters: AtomicU32
+}
+
+impl WaitQueue{
+    pub const fn new() -> WaitQueue{
+        WaitQueue{
+            waiters: AtomicU32::new(0)
+        }
+    }
+
+    pub(in crate::kernel) fn add_waiter(&self, id: TaskId){
+        self.waiters.fetch_or(1 << id, Ordering::Relaxed);
+    }
+
+    pub(in crate::kernel) fn remove_waiter(&self, id: TaskId){
+        self.waiters.fetch_and(!(1 << id), Ordering::Relaxed);
+    }
+
+    pub(in crate::kernel) fn waiters(&self) -> u32{
+        self.waiters.load(Ordering::Relaxed)
+    }
+
+    /// True if any task is blocked on the queue.
+    pub fn has_waiters(&self) -> bool{
+        self.waiters() != 0
+    }
+
+    /// Block until the condition returns true. The condition is re-checked every time the queue is notified.
+    pub fn wait_until<F>(&self, condition: F) where F: FnMut() -> bool{
+        self.wait_until_deadline(condition, None);
+    }
+
+    /// Block until the condition returns true, or the timeout expires. Returns the last result of the condition.
+    pub fn wait_until_timeout<F>(&self, condition: F, timeout: Option<Duration>) -> bool where F: FnMut() -> bool{
+        self.wait_until_deadline(condition, timeout.map(time::deadline_after))
+    }
+
+    /// Block until the condition returns true, or the tick count reaches the deadline.
+    /// The condition may have side effects, like taking a semaphore: once it returns true we stop waiting.
+    pub fn wait_until_deadline<F>(&self, mut condition: F, deadline: Option<u64>) -> bool where F: FnMut() -> bool{
+        if !scheduler::is_running() || exception::in_interrupt(){
+            return Self::spin_until(condition, deadline);
+        }
+
+        loop{
+            if condition(){
+                return true;
+            }
+
+            if let Some(deadline) = deadline{
+                if time::ticks() >= deadline{
+                    return false;
+                }
+            }
+
+            //Join the queue before the final check, so a notify after the check still wakes us.
+            let daif = exception::local_irq_save();
+            scheduler::prepare_to_wait(Some(self), deadline);
+
+            if condition(){
+                scheduler::cancel_wait();
+                exception::local_irq_restore(daif);
+                return true;
+            }
+
+            let reason = scheduler::block();
+            exception::local_irq_restore(daif);
+
+            if reason == WakeReason::TimedOut{
+                return condition();
+            }
+        }
+    }
+
+    fn spin_until<F>(mut condition: F, deadline: Option<u64>) -> bool where F: FnMut() -> bool{
+        //The tick may not be running yet, so time the wait with the system counter instead.
+        let counter_deadline = deadline.map(|deadline| {
+            time::counter().saturating_add(time::ticks_to_counter_cycles(deadline.saturating_sub(time::ticks())))
+        });
+
+        while !condition(){
+            match counter_deadline{
+                //Nothing would wake a WFE when the deadline passes, so spin.
+                Some(counter_deadline) => {
+                    if time::counter() >= counter_deadline{
+                        return false;
+                    }
+
+                    core::hint::spin_loop();
+                },
+                //An event sent between the check and the WFE is latched, so we can't miss a wakeup.
+                None => unsafe{
+                    asm!("wfe", options(nostack));
+                },
+            }
+        }
+
+        true
+    }
+
+    /// Wake the highest priority waiter. Safe to call from an interrupt handler.
+    pub fn notify_one(&self){
+        Self::send_event();
+
+        if scheduler::is_running() && scheduler::wake_one(self){
+            scheduler::preempt_if_needed();
+        }
+    }
+
+    /// Wake every waiter. Safe to call from an interrupt handler.
+    pub fn notify_all(&self){
+        Self::send_event();
+
+        if scheduler::is_running(){
+            scheduler::wake_all(self);
+            scheduler::preempt_if_needed();
+        }
+    }
+
+    fn send_event(){
+        unsafe{
+            asm!("dsb ishst", "sev", options(nostack));
+        }
+    }
+}
+
+impl Default for WaitQueue{
+    fn default() -> WaitQueue{
+        WaitQueue::new()
+    }
+}
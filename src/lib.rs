@@ -0,0 +1,8 @@
+#![no_std]
+
+pub mod panic_wait;
+pub mod bsp;
+pub mod exception;
+pub mod kernel;
+pub mod memory;
+pub mod sync;
@@ -1,10 +1,17 @@
 #![no_std]
 #![no_main]
 
-use bsp::raspberry_pi_5::uart::TransmitMode;
+use core::fmt::Write;
+use core::time::Duration;
 
-mod panic_wait;
-mod bsp;
+use rust_aarch64_kernel::{bsp, exception, kernel, memory, panic_wait, sync};
+use bsp::raspberry_pi_5::uart::{TransmitMode, UartInstance};
+use kernel::boot_info::BootInfo;
+use kernel::fault::FaultReport;
+use kernel::params::{param, Value};
+use kernel::peripherals::Shared;
+use kernel::task::{TaskId, TaskStack};
+use sync::Once;
 
 mod boot {
     use core::arch::global_asm;
@@ -14,19 +21,207 @@ mod boot {
     );
 }
 
+/// The priority main runs at once the scheduler has started.
+const MAIN_TASK_PRIORITY: u8 = 1;
+
+/// The program named by init= runs at the same priority as main.
+const INIT_TASK_PRIORITY: u8 = 1;
+
+static BOOT_INFO: Once<BootInfo> = Once::new();
+
+/// The console UART, shared with EL0 tasks through the ConsoleWrite system call.
+static CONSOLE: Shared<UartInstance> = Shared::new();
+
+param!(CONSOLE_UART, "console.uart", default = Value::Integer(0), validate = check_console_uart);
+param!(CONSOLE_BAUD_RATE, "console.baud", default = Value::Integer(115200), validate = check_console_baud_rate);
+param!(INIT_PROGRAM, "init", default = Value::Text(""));
+param!(UPLOAD_PROGRAM, "upload", default = Value::Flag(false));
+
+#[link_section = ".stacks"]
+static INIT_KERNEL_STACK: TaskStack<16384> = TaskStack::new();
+
+#[link_section = ".stacks"]
+static UPLOAD_KERNEL_STACK: TaskStack<16384> = TaskStack::new();
+
+/// How long an upload can stall, once it has started, before it's abandoned.
+const UPLOAD_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often to check whether an upload has started.
+const UPLOAD_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+//check_console_uart's error message names the valid range, so it has to change with UART_COUNT.
+const _: () = assert!(bsp::raspberry_pi_5::uart::UART_COUNT == 6);
+
+fn check_console_uart(value: Value) -> Result<(), &'static str>{
+    match value{
+        Value::Integer(index) if index < bsp::raspberry_pi_5::uart::UART_COUNT as u64 => Ok(()),
+        _ => Err("console.uart must be a valid UART index (0 to 5)."),
+    }
+}
+
+//The UART checks the rate is achievable when the console is built.
+fn check_console_baud_rate(value: Value) -> Result<(), &'static str>{
+    match value{
+        Value::Integer(0) => Err("console.baud can't be 0."),
+        Value::Integer(baud_rate) if baud_rate > u32::MAX as u64 => Err("console.baud is too large."),
+        _ => Ok(()),
+    }
+}
+
+fn write_console(bytes: &[u8]) -> Result<usize, &'static str>{
+    CONSOLE.lock()?.write(bytes)
+}
+
+/// Formats straight to the console.
+struct ConsoleWriter;
+
+impl core::fmt::Write for ConsoleWriter{
+    fn write_str(&mut self, text: &str) -> core::fmt::Result{
+        CONSOLE.lock().and_then(|console| console.write_all(text.as_bytes())).map_err(|_| core::fmt::Error)
+    }
+}
+
+fn print_fault(report: &FaultReport){
+    let _ = writeln!(ConsoleWriter, "{}", report);
+}
+
+/// Start the program init= names, like init=/bin/hello, from the initramfs as an EL0 task.
+fn start_init_program(boot_info: &BootInfo){
+    let path = INIT_PROGRAM.text();
+
+    if path.is_empty(){
+        return;
+    }
+
+    let result = boot_info.initramfs().ok_or("The firmware didn't load an initramfs.").and_then(|archive| {
+        let kernel_stack = INIT_KERNEL_STACK.take().ok_or("The init program's kernel stack is already in use.")?;
+        kernel::elf::spawn_from_initramfs(archive, path, INIT_TASK_PRIORITY, &[path], kernel_stack)
+    });
+
+    if let Err(error) = result{
+        let _ = writeln!(ConsoleWriter, "Failed to start {}: {}", path, error);
+    }
+}
+
+/// Fill the buffer from the console, waiting at most UPLOAD_TIMEOUT for each part of it.
+fn read_console_exact(buffer: &mut [u8]) -> Result<(), &'static str>{
+    let mut filled = 0;
+
+    while filled < buffer.len(){
+        filled += CONSOLE.lock()?.read_timeout(&mut buffer[filled..], Some(UPLOAD_TIMEOUT))?;
+    }
+
+    Ok(())
+}
+
+/// Receive a program on the console and start it as an EL0 task. The sender writes the image's
+/// size and the wrapping sum of its bytes, each as a little-endian u32, then the image itself.
+fn receive_upload() -> Result<TaskId, &'static str>{
+    //Wait for the sender without holding the console, so other tasks can still write to it.
+    while CONSOLE.lock()?.bytes_available() == 0{
+        kernel::scheduler::sleep(UPLOAD_POLL_INTERVAL);
+    }
+
+    let mut header = [0u8; 8];
+    read_console_exact(&mut header)?;
+
+    let size = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let checksum = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    let kernel_stack = UPLOAD_KERNEL_STACK.take().ok_or("The uploaded program's kernel stack is already in use.")?;
+    let mut sum = 0u32;
+
+    kernel::elf::spawn_received("upload", INIT_TASK_PRIORITY, size, |remaining| {
+        let count = CONSOLE.lock()?.read_timeout(remaining, Some(UPLOAD_TIMEOUT))?;
+        sum = remaining[..count].iter().fold(sum, |sum, byte| sum.wrapping_add(*byte as u32));
+
+        //The UART has no flow control, so a dropped byte only shows up here, once the last part has arrived.
+        if count == remaining.len() && sum != checksum{
+            return Err("The upload's checksum doesn't match.");
+        }
+
+        Ok(count)
+    }, &["upload"], kernel_stack)
+}
+
+/// With upload set on the command line, wait for a program on the console and start it.
+fn start_uploaded_program(){
+    if !UPLOAD_PROGRAM.flag(){
+        return;
+    }
+
+    let _ = writeln!(ConsoleWriter, "Waiting for a program upload");
+
+    if let Err(error) = receive_upload(){
+        let _ = writeln!(ConsoleWriter, "Failed to start the uploaded program: {}", error);
+    }
+}
+
+/// boot.S enters Rust here, with the address of the device tree the firmware passed.
 #[no_mangle]
-pub fn main() -> ! {
+extern "C" fn kernel_entry(device_tree: usize) -> !{
+    //Translation has to be on before any task is created, so their stacks get guard pages.
+    //The device tree is read through the identity map too.
+    memory::mmu::init().expect("Failed to enable the MMU");
+
+    let boot_info = BOOT_INFO.call_once(|| unsafe{ BootInfo::from_device_tree(device_tree) });
+    main(boot_info)
+}
+
+pub fn main(boot_info: &'static BootInfo) -> ! {
+    //There's no console yet to report bad parameters on. They keep their defaults.
+    if let Some(bootargs) = boot_info.bootargs{
+        kernel::params::init(bootargs);
+    }
+
+    //Without a device tree, ask the firmware how much memory there is.
+    let reserved = boot_info.reserved_regions().chain(bsp::FIRMWARE_RESERVED);
+
+    if boot_info.memory_regions().next().is_some(){
+        memory::frame_allocator::init(boot_info.memory_regions(), reserved).expect("Failed to initialize the frame allocator");
+    } else{
+        let memory = bsp::firmware_memory().expect("Failed to read the memory map");
+        memory::frame_allocator::init([memory], reserved).expect("Failed to initialize the frame allocator");
+    }
+
     bsp::init();
 
-    let builder = bsp::raspberry_pi_5::uart::InstanceBuilder::new(0)
-    .with_baud_rate(115200)
+    //Drivers find their devices in the device tree, rather than at fixed addresses. Without one,
+    //only the console UART is known.
+    if let Some(tree) = &boot_info.device_tree{
+        kernel::driver::probe_devices(tree);
+    } else{
+        bsp::raspberry_pi_5::uart::register_fallback_console();
+    }
+
+    exception::local_irq_enable();
+
+    //From here on main is a task, and anything it blocks on gives the core to other tasks.
+    //Every task declared with task! starts here too.
+    kernel::scheduler::start(MAIN_TASK_PRIORITY).expect("Failed to start the scheduler");
+
+    //The other cores join in once there is a scheduler to join.
+    kernel::cpu::start_secondary_cores().expect("Failed to start the secondary cores");
+
+    let builder = bsp::raspberry_pi_5::uart::InstanceBuilder::new(CONSOLE_UART.integer() as usize)
+    .with_baud_rate(CONSOLE_BAUD_RATE.integer() as usize)
     .with_transmit_mode(TransmitMode::Bidirectional)
     .with_word_length(bsp::raspberry_pi_5::uart::WordLength::Bits8);
 
     let instance = builder.build().expect("Failed to create the UART instance!");
 
+    if CONSOLE.install(instance).is_err(){
+        panic!("The console was already installed");
+    }
+
+    //Panics write to the console's FIFO directly, so they get out even if the console is locked.
+    panic_wait::set_console(CONSOLE_UART.integer() as usize);
+
+    kernel::syscall::set_console(write_console).expect("Failed to set the console");
+    kernel::fault::set_fault_handler(print_fault).expect("Failed to set the fault handler");
+
+    start_init_program(boot_info);
+    start_uploaded_program();
 
-    loop{
-        instance.poll_write("We're looping!".as_bytes()).expect("Failed to write a byte to UART 0!");
-    };
+    //Everything else runs in its own task, so main has nothing left to do.
+    kernel::scheduler::exit();
 }
\ No newline at end of file
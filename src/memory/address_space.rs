@@ -0,0 +1,274 @@
+use core::arch::asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::sync::SpinLock;
+
+use super::frame_allocator::{self, MemoryRegion};
+use super::mmu::{self, Table, DESCRIPTOR_ADDRESS, DESCRIPTOR_EL0_ACCESS, DESCRIPTOR_PAGE, DESCRIPTOR_PXN, DESCRIPTOR_READ_ONLY,
+    DESCRIPTOR_TABLE, DESCRIPTOR_UXN, DESCRIPTOR_VALID, ENTRIES_PER_TABLE, LEVEL_1_BLOCK_SIZE, LEVEL_2_BLOCK_SIZE, PAGE_SIZE, TABLE_LOCK};
+
+/// The most address spaces at once. Each has its own ASID, and ASID 0 is the kernel's.
+pub const MAX_ADDRESS_SPACES: usize = 64;
+
+/// The most runs of frames an address space can own.
+pub const MAX_OWNED_FRAMES: usize = 4;
+
+/// The level 1 table of each address space, by ASID, or 0. Only changed with TABLE_LOCK held.
+static SPACES: [AtomicUsize; MAX_ADDRESS_SPACES] = [const { AtomicUsize::new(0) }; MAX_ADDRESS_SPACES];
+
+/// Frames each address space owns, by ASID, freed along with it.
+static OWNED_FRAMES: SpinLock<[[Option<MemoryRegion>; MAX_OWNED_FRAMES]; MAX_ADDRESS_SPACES]> = SpinLock::new([[None; MAX_OWNED_FRAMES]; MAX_ADDRESS_SPACES]);
+
+/// How an EL0 task may use a region.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Access{
+    /// The task can read the region. There's no way to map a page read only for EL0 and writable
+    /// for EL1, so the kernel can't write it either while the task's tables are in use. Don't
+    /// give read only access to memory an interrupt handler writes.
+    ReadOnly,
+    ReadWrite,
+    /// The task can read the region and run code in it, like a loaded image's text. The kernel never runs it.
+    Execute
+}
+
+/// Memory a task may reach from EL0, like its stack, a buffer it shares, or one peripheral's registers.
+/// Access is granted a page at a time, so the start and size must be whole pages. Rounding them
+/// out would quietly hand over whatever shares the first and last pages.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Region{
+    pub start: usize,
+    pub size: usize,
+    pub access: Access
+}
+
+impl Region{
+    pub const fn new(start: usize, size: usize, access: Access) -> Region{
+        Region{ start, size, access }
+    }
+
+    /// The first page and the end of the last page.
+    fn pages(&self) -> Result<(usize, usize), &'static str>{
+        if !self.start.is_multiple_of(PAGE_SIZE) || !self.size.is_multiple_of(PAGE_SIZE){
+            return Err("The region isn't a whole number of pages.");
+        }
+
+        let end = self.start.checked_add(self.size).ok_or("The region wraps around the address space.")?;
+        Ok((self.start, end))
+    }
+}
+
+/// A task's own translation tables, tagged with its own ASID. They map the kernel exactly like
+/// the kernel's tables, so EL1 code runs the same in any address space, and add EL0 access to
+/// the task's regions. Tables are only copied where they differ from the kernel's.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct AddressSpace{
+    asid: usize,
+    level_1: usize
+}
+
+fn table(address: usize) -> &'static mut Table{
+    unsafe{ &mut *(address as *mut Table) }
+}
+
+/// A frame for a table. Tables are written through the identity map, which covers every frame the allocator hands out.
+fn allocate_table() -> Result<usize, &'static str>{
+    frame_allocator::allocate().ok_or("Out of memory for translation tables.")
+}
+
+/// The table an entry points to, made private to this address space. A kernel table is copied,
+/// and a block is split into the next level's blocks or pages with the same attributes.
+fn private_table(entry: &mut u64, child_size: usize) -> Result<&'static mut Table, &'static str>{
+    if *entry & DESCRIPTOR_VALID == 0{
+        return Err("The region isn't in memory the kernel maps.");
+    }
+
+    let is_table = *entry & DESCRIPTOR_TABLE != 0;
+    let address = (*entry & DESCRIPTOR_ADDRESS) as usize;
+
+    if is_table && !mmu::is_kernel_table(address){
+        return Ok(table(address));
+    }
+
+    let copy = allocate_table()?;
+
+    for (index, child) in table(copy).0.iter_mut().enumerate(){
+        *child = if is_table{
+            table(address).0[index]
+        } else{
+            let attributes = *entry & !DESCRIPTOR_ADDRESS & !DESCRIPTOR_TABLE;
+            let page = if child_size == PAGE_SIZE{ DESCRIPTOR_PAGE } else{ 0 };
+
+            (address + index * child_size) as u64 | attributes | page
+        };
+    }
+
+    *entry = copy as u64 | DESCRIPTOR_VALID | DESCRIPTOR_TABLE;
+
+    Ok(table(copy))
+}
+
+/// Free a table and every private table below it.
+fn free_private(address: usize, level: usize){
+    if level < 3{
+        for entry in table(address).0.iter(){
+            let child = (entry & DESCRIPTOR_ADDRESS) as usize;
+
+            if entry & DESCRIPTOR_VALID != 0 && entry & DESCRIPTOR_TABLE != 0 && !mmu::is_kernel_table(child){
+                free_private(child, level + 1);
+            }
+        }
+    }
+
+    let _ = frame_allocator::free(address);
+}
+
+/// The level 3 entry for a page, if this address space has a private level 3 table for it.
+fn private_page_entry(level_1: usize, address: usize) -> Option<&'static mut u64>{
+    let mut entry = &mut table(level_1).0[address / LEVEL_1_BLOCK_SIZE % ENTRIES_PER_TABLE];
+
+    for index in [address / LEVEL_2_BLOCK_SIZE % ENTRIES_PER_TABLE, address / PAGE_SIZE % ENTRIES_PER_TABLE]{
+        let child = (*entry & DESCRIPTOR_ADDRESS) as usize;
+
+        if *entry & DESCRIPTOR_VALID == 0 || *entry & DESCRIPTOR_TABLE == 0 || mmu::is_kernel_table(child){
+            return None;
+        }
+
+        entry = &mut table(child).0[index];
+    }
+
+    Some(entry)
+}
+
+/// Called by the mmu, with TABLE_LOCK held, when it changes a page of the kernel's tables. Address
+/// spaces that have their own copy of that page's table get the change too.
+pub(super) fn update_kernel_page(address: usize, descriptor: u64){
+    for space in SPACES.iter().skip(1){
+        let level_1 = space.load(Ordering::Relaxed);
+
+        if level_1 == 0{
+            continue;
+        }
+
+        if let Some(entry) = private_page_entry(level_1, address){
+            *entry = descriptor;
+        }
+    }
+}
+
+fn invalidate_asid(asid: usize){
+    unsafe{
+        asm!(
+            "dsb ishst",
+            "tlbi aside1is, {}",
+            "dsb ish",
+            "isb",
+            in(reg) (asid as u64) << 48,
+            options(nostack)
+        );
+    }
+}
+
+impl AddressSpace{
+    /// Create an address space that maps only what the kernel's tables do.
+    pub fn new() -> Result<AddressSpace, &'static str>{
+        if !mmu::is_enabled(){
+            return Err("Address spaces can't be created before the MMU is enabled.");
+        }
+
+        let _lock = TABLE_LOCK.lock();
+
+        let asid = match SPACES.iter().skip(1).position(|space| space.load(Ordering::Relaxed) == 0){
+            Some(index) => index + 1,
+            None => return Err("There are no free ASIDs."),
+        };
+
+        let level_1 = allocate_table()?;
+        table(level_1).0 = table(mmu::kernel_level_1()).0;
+
+        SPACES[asid].store(level_1, Ordering::Relaxed);
+
+        Ok(AddressSpace{ asid, level_1 })
+    }
+
+    pub fn asid(&self) -> usize{
+        self.asid
+    }
+
+    /// The translation table base to switch to this address space with.
+    pub fn ttbr(&self) -> u64{
+        self.level_1 as u64 | (self.asid as u64) << 48
+    }
+
+    /// Let EL0 reach a region. Every page of it must be mapped by the kernel, so guard pages and
+    /// memory outside the kernel's map can't be given out. Device memory stays device memory, and
+    /// nothing mapped for EL0 is executable apart from the kernel's code, which can't be remapped,
+    /// and Execute regions.
+    pub fn map(&self, region: &Region) -> Result<(), &'static str>{
+        let (start, end) = region.pages()?;
+        let shared_code = mmu::shared_code();
+
+        if start < shared_code.end && shared_code.start < end{
+            return Err("The region overlaps the kernel's code.");
+        }
+
+        let _lock = TABLE_LOCK.lock();
+
+        //Check every page first, so a bad region changes nothing EL0 can see. Tables made
+        //private along the way still map the same as the kernel's.
+        for address in (start..end).step_by(PAGE_SIZE){
+            if *self.page_entry(address)? & DESCRIPTOR_VALID == 0{
+                return Err("The region includes a page the kernel doesn't map.");
+            }
+        }
+
+        let access = match region.access{
+            Access::ReadOnly => DESCRIPTOR_EL0_ACCESS | DESCRIPTOR_READ_ONLY | DESCRIPTOR_UXN,
+            Access::ReadWrite => DESCRIPTOR_EL0_ACCESS | DESCRIPTOR_UXN,
+            Access::Execute => DESCRIPTOR_EL0_ACCESS | DESCRIPTOR_READ_ONLY,
+        };
+
+        for address in (start..end).step_by(PAGE_SIZE){
+            let page = self.page_entry(address)?;
+            *page = (*page & !(DESCRIPTOR_READ_ONLY | DESCRIPTOR_UXN)) | access | DESCRIPTOR_PXN;
+        }
+
+        invalidate_asid(self.asid);
+
+        Ok(())
+    }
+
+    /// The level 3 entry for a page, making every table on the way private.
+    fn page_entry(&self, address: usize) -> Result<&'static mut u64, &'static str>{
+        let level_1 = &mut table(self.level_1).0[address / LEVEL_1_BLOCK_SIZE % ENTRIES_PER_TABLE];
+        let level_2 = &mut private_table(level_1, LEVEL_2_BLOCK_SIZE)?.0[address / LEVEL_2_BLOCK_SIZE % ENTRIES_PER_TABLE];
+
+        Ok(&mut private_table(level_2, PAGE_SIZE)?.0[address / PAGE_SIZE % ENTRIES_PER_TABLE])
+    }
+
+    /// Hand over frames from frame_allocator::allocate_contiguous, like a loaded image, to be freed
+    /// along with the address space.
+    pub fn adopt(&self, frames: MemoryRegion) -> Result<(), &'static str>{
+        let mut owned = OWNED_FRAMES.lock();
+
+        match owned[self.asid].iter_mut().find(|slot| slot.is_none()){
+            Some(slot) => {
+                *slot = Some(frames);
+                Ok(())
+            },
+            None => Err("The address space owns too many runs of frames."),
+        }
+    }
+
+    /// Free the tables, the ASID, and any frames it adopted. The address space mustn't be active on any core.
+    pub fn destroy(self){
+        let _lock = TABLE_LOCK.lock();
+
+        SPACES[self.asid].store(0, Ordering::Relaxed);
+        free_private(self.level_1, 1);
+        invalidate_asid(self.asid);
+
+        for frames in OWNED_FRAMES.lock()[self.asid].iter_mut().filter_map(|slot| slot.take()){
+            let _ = frame_allocator::free_contiguous(frames.start, frames.size / PAGE_SIZE);
+        }
+    }
+}
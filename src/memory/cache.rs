@@ -0,0 +1,61 @@
+use core::arch::asm;
+
+/// The smallest data cache line size in the system, read from CTR_EL0.
+pub fn data_cache_line_size() -> usize{
+    let ctr: usize;
+    unsafe{
+        asm!("mrs {}, ctr_el0", out(reg) ctr, options(nomem, nostack));
+    }
+
+    //DminLine (bits 16-19) is log2 of the number of words in the smallest line.
+    4 << ((ctr >> 16) & 0xF)
+}
+
+/// Run a data cache maintenance instruction over every line in a range, then wait for it to complete.
+macro_rules! for_each_line{
+    ($instruction:literal, $address:expr, $length:expr) => {{
+        let line_size = data_cache_line_size();
+        let mut line = $address & !(line_size - 1);
+        let end = $address + $length;
+
+        while line < end{
+            unsafe{
+                asm!(concat!("dc ", $instruction, ", {}"), in(reg) line, options(nostack));
+            }
+            line += line_size;
+        }
+
+        unsafe{
+            asm!("dsb sy", options(nostack));
+        }
+    }};
+}
+
+/// Write any dirty lines in the range back to memory, so a device reading memory sees what the CPU wrote.
+/// Use before a device reads a buffer (DMA to a peripheral).
+pub fn clean_range(address: usize, length: usize){
+    for_each_line!("cvac", address, length);
+}
+
+/// Discard the cached copy of a range, so the CPU sees what a device wrote to memory.
+/// Use before and after a device writes a buffer (DMA from a peripheral). Any dirty data
+/// in lines shared with the range is lost, so DMA buffers should be cache line aligned.
+pub fn invalidate_range(address: usize, length: usize){
+    for_each_line!("ivac", address, length);
+}
+
+/// Write back and then discard every line in the range.
+pub fn clean_and_invalidate_range(address: usize, length: usize){
+    for_each_line!("civac", address, length);
+}
+
+/// Make instructions written to a range visible to instruction fetch on every core, for code
+/// the kernel has just loaded. The data is cleaned to where instruction fetches see it, then every
+/// core's instruction cache is discarded.
+pub fn sync_instructions(address: usize, length: usize){
+    for_each_line!("cvau", address, length);
+
+    unsafe{
+        asm!("ic ialluis", "dsb ish", "isb", options(nostack));
+    }
+}
@@ -0,0 +1,276 @@
+use crate::sync::IrqSpinLock;
+
+use super::mmu::{DRAM_MAPPED_SIZE, PAGE_SIZE};
+
+/// Frames are used through the identity map, so the allocator only tracks memory the kernel maps.
+const FRAME_COUNT: usize = DRAM_MAPPED_SIZE / PAGE_SIZE;
+const BITMAP_WORDS: usize = FRAME_COUNT / 64;
+
+extern "C" {
+    static __boot_stack_bottom: u8;
+    static _end: u8;
+}
+
+/// A range of physical memory.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct MemoryRegion{
+    pub start: usize,
+    pub size: usize
+}
+
+impl MemoryRegion{
+    pub const fn new(start: usize, size: usize) -> MemoryRegion{
+        MemoryRegion{ start, size }
+    }
+
+    pub const fn end(&self) -> usize{
+        self.start.saturating_add(self.size)
+    }
+}
+
+/// A bitmap of every 4KB frame of mapped memory. A set bit is a free frame, so everything
+/// starts out unavailable until init hands over the memory map. Two more bitmaps record which
+/// frames came from the memory map and which are reserved, so a frame is only ever allocated
+/// if it's managed, not reserved and not free.
+struct FrameAllocator{
+    bitmap: [u64; BITMAP_WORDS],
+    managed: [u64; BITMAP_WORDS],
+    reserved: [u64; BITMAP_WORDS],
+    total: usize,
+    free: usize,
+    /// Where the next single frame search starts
+    next_word: usize
+}
+
+static FRAMES: IrqSpinLock<FrameAllocator> = IrqSpinLock::new(FrameAllocator::new());
+
+impl FrameAllocator{
+    const fn new() -> FrameAllocator{
+        FrameAllocator{
+            bitmap: [0; BITMAP_WORDS],
+            managed: [0; BITMAP_WORDS],
+            reserved: [0; BITMAP_WORDS],
+            total: 0,
+            free: 0,
+            next_word: 0
+        }
+    }
+
+    fn is_free(&self, frame: usize) -> bool{
+        self.bitmap[frame / 64] & (1 << (frame % 64)) != 0
+    }
+
+    fn set_free(&mut self, frame: usize){
+        self.bitmap[frame / 64] |= 1 << (frame % 64);
+    }
+
+    fn set_used(&mut self, frame: usize){
+        self.bitmap[frame / 64] &= !(1 << (frame % 64));
+    }
+
+    fn is_managed(&self, frame: usize) -> bool{
+        self.managed[frame / 64] & (1 << (frame % 64)) != 0
+    }
+
+    fn is_reserved(&self, frame: usize) -> bool{
+        self.reserved[frame / 64] & (1 << (frame % 64)) != 0
+    }
+
+    /// True if the frame was handed out by allocate or allocate_contiguous and not freed since.
+    fn is_allocated(&self, frame: usize) -> bool{
+        self.is_managed(frame) && !self.is_reserved(frame) && !self.is_free(frame)
+    }
+
+    /// The whole frames inside a region, clipped to the memory we track.
+    fn frames_inside(region: &MemoryRegion) -> core::ops::Range<usize>{
+        let start = region.start.div_ceil(PAGE_SIZE);
+        let end = core::cmp::min(region.end(), DRAM_MAPPED_SIZE) / PAGE_SIZE;
+
+        start..core::cmp::max(start, end)
+    }
+
+    /// Every frame a region touches, even partly, clipped to the memory we track.
+    fn frames_touching(region: &MemoryRegion) -> core::ops::Range<usize>{
+        let start = core::cmp::min(region.start, DRAM_MAPPED_SIZE) / PAGE_SIZE;
+        let end = core::cmp::min(region.end(), DRAM_MAPPED_SIZE).div_ceil(PAGE_SIZE);
+
+        start..core::cmp::max(start, end)
+    }
+
+    /// Frames already managed are left as they are, so adding memory again can't free an
+    /// allocated frame, and reserved frames stay reserved.
+    fn add(&mut self, region: &MemoryRegion){
+        for frame in Self::frames_inside(region){
+            if self.is_managed(frame){
+                continue;
+            }
+
+            self.managed[frame / 64] |= 1 << (frame % 64);
+
+            if !self.is_reserved(frame){
+                self.set_free(frame);
+                self.total += 1;
+                self.free += 1;
+            }
+        }
+    }
+
+    /// A reserved frame is never handed out or accepted back, even if it's allocated right now.
+    fn reserve(&mut self, region: &MemoryRegion){
+        for frame in Self::frames_touching(region){
+            if self.is_reserved(frame){
+                continue;
+            }
+
+            self.reserved[frame / 64] |= 1 << (frame % 64);
+
+            if self.is_managed(frame){
+                self.total -= 1;
+
+                if self.is_free(frame){
+                    self.set_used(frame);
+                    self.free -= 1;
+                }
+            }
+        }
+    }
+
+    fn allocate(&mut self) -> Option<usize>{
+        for offset in 0..BITMAP_WORDS{
+            let word = (self.next_word + offset) % BITMAP_WORDS;
+
+            if self.bitmap[word] != 0{
+                let frame = word * 64 + self.bitmap[word].trailing_zeros() as usize;
+
+                self.set_used(frame);
+                self.free -= 1;
+                self.next_word = word;
+
+                return Some(frame);
+            }
+        }
+
+        None
+    }
+
+    /// Find count free frames in a row, starting at a multiple of the alignment, in frames.
+    fn allocate_contiguous(&mut self, count: usize, alignment: usize) -> Option<usize>{
+        let mut start = 0;
+
+        while start + count <= FRAME_COUNT{
+            //Skip whole words of used frames quickly.
+            if self.bitmap[start / 64] == 0{
+                start = ((start / 64 + 1) * 64).next_multiple_of(alignment);
+                continue;
+            }
+
+            match (start..start + count).find(|frame| !self.is_free(*frame)){
+                Some(used) => start = (used + 1).next_multiple_of(alignment),
+                None => {
+                    for frame in start..start + count{
+                        self.set_used(frame);
+                    }
+
+                    self.free -= count;
+                    return Some(start);
+                },
+            }
+        }
+
+        None
+    }
+
+    fn free(&mut self, frame: usize, count: usize) -> Result<(), &'static str>{
+        if frame + count > FRAME_COUNT || !(frame..frame + count).all(|frame| self.is_allocated(frame)){
+            return Err("The frames weren't allocated.");
+        }
+
+        for frame in frame..frame + count{
+            self.set_free(frame);
+        }
+
+        self.free += count;
+        Ok(())
+    }
+}
+
+/// The memory the kernel image and the boot stack live in, including the boot stack's guard page.
+fn kernel_image() -> MemoryRegion{
+    let start = unsafe{ &__boot_stack_bottom as *const u8 as usize } - PAGE_SIZE;
+    let end = unsafe{ &_end as *const u8 as usize };
+
+    MemoryRegion::new(start, end - start)
+}
+
+/// Hand the allocator the memory map. Every frame inside a memory region becomes free, apart
+/// from those touching a reserved region, the kernel image or the boot stack. Reserved regions
+/// are for whatever the firmware or the boot loader have left in memory, like the device tree.
+/// Memory beyond what the kernel maps is left out, since frames are used through the identity
+/// map. Can be called again to add memory.
+pub fn init(memory: impl IntoIterator<Item = MemoryRegion>, reserved: impl IntoIterator<Item = MemoryRegion>) -> Result<(), &'static str>{
+    let mut frames = FRAMES.lock();
+
+    for region in memory{
+        frames.add(&region);
+    }
+
+    frames.reserve(&kernel_image());
+
+    for region in reserved{
+        frames.reserve(&region);
+    }
+
+    if frames.free == 0{
+        return Err("The memory map has no free memory.");
+    }
+
+    Ok(())
+}
+
+/// Allocate one frame, and return its physical address. The frame isn't zeroed.
+pub fn allocate() -> Option<usize>{
+    FRAMES.lock().allocate().map(|frame| frame * PAGE_SIZE)
+}
+
+/// Allocate count physically contiguous frames, starting at a multiple of alignment bytes,
+/// for DMA buffers and page tables. The alignment must be a power of two, and is at least a page.
+pub fn allocate_contiguous(count: usize, alignment: usize) -> Result<usize, &'static str>{
+    if count == 0{
+        return Err("Can't allocate zero frames.");
+    }
+
+    if !alignment.is_power_of_two(){
+        return Err("The alignment must be a power of two.");
+    }
+
+    let alignment = core::cmp::max(alignment, PAGE_SIZE) / PAGE_SIZE;
+
+    match FRAMES.lock().allocate_contiguous(count, alignment){
+        Some(frame) => Ok(frame * PAGE_SIZE),
+        None => Err("No free run of frames is long enough."),
+    }
+}
+
+/// Free a frame from allocate.
+pub fn free(address: usize) -> Result<(), &'static str>{
+    free_contiguous(address, 1)
+}
+
+/// Free frames from allocate_contiguous. They needn't all be freed at once.
+pub fn free_contiguous(address: usize, count: usize) -> Result<(), &'static str>{
+    if !address.is_multiple_of(PAGE_SIZE){
+        return Err("Frame addresses are page aligned.");
+    }
+
+    FRAMES.lock().free(address / PAGE_SIZE, count)
+}
+
+/// The number of frames the allocator manages, free or not.
+pub fn total_frames() -> usize{
+    FRAMES.lock().total
+}
+
+/// The number of frames available to allocate.
+pub fn free_frames() -> usize{
+    FRAMES.lock().free
+}
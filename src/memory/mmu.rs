@@ -0,0 +1,337 @@
+use core::arch::asm;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::exception;
+use crate::sync::SpinLock;
+
+use super::address_space;
+
+/// The translation granule. Guard pages are unmapped at this granularity.
+pub const PAGE_SIZE: usize = 4096;
+
+pub(super) const ENTRIES_PER_TABLE: usize = 512;
+pub(super) const LEVEL_1_BLOCK_SIZE: usize = 1 << 30;
+pub(super) const LEVEL_2_BLOCK_SIZE: usize = 1 << 21;
+
+/// DRAM mapped as normal memory, from address zero. The kernel and every buffer it owns live in the first gigabyte.
+pub(super) const DRAM_MAPPED_SIZE: usize = LEVEL_1_BLOCK_SIZE;
+
+/// The BCM2712's peripherals, including the GIC, the PCIe root complex and the RP1 window, are mapped as device memory.
+const PERIPHERAL_START: usize = 0x10_0000_0000;
+const PERIPHERAL_END: usize = 0x20_0000_0000;
+
+/// The number of level 3 tables available for mapping the kernel image with pages. Each covers 2MB.
+const LEVEL_3_TABLE_COUNT: usize = 8;
+
+//Descriptor bits
+pub(super) const DESCRIPTOR_VALID: u64 = 1 << 0;
+/// Set for table and page descriptors, clear for blocks
+pub(super) const DESCRIPTOR_TABLE: u64 = 1 << 1;
+pub(super) const DESCRIPTOR_PAGE: u64 = 1 << 1;
+/// AP[1]: EL0 may access the page as well as EL1
+pub(super) const DESCRIPTOR_EL0_ACCESS: u64 = 1 << 6;
+/// AP[2]: the page is read only, at every level
+pub(super) const DESCRIPTOR_READ_ONLY: u64 = 1 << 7;
+const DESCRIPTOR_ACCESS_FLAG: u64 = 1 << 10;
+const DESCRIPTOR_INNER_SHAREABLE: u64 = 0b11 << 8;
+/// nG: TLB entries are tagged with the ASID. Every mapping has it, since each task's tables can differ anywhere.
+const DESCRIPTOR_NOT_GLOBAL: u64 = 1 << 11;
+pub(super) const DESCRIPTOR_PXN: u64 = 1 << 53;
+pub(super) const DESCRIPTOR_UXN: u64 = 1 << 54;
+
+/// The output address bits of a descriptor
+pub(super) const DESCRIPTOR_ADDRESS: u64 = 0x0000_FFFF_FFFF_F000;
+
+/// MAIR_EL1 attribute indices
+const ATTR_INDEX_NORMAL: u64 = 0;
+const ATTR_INDEX_DEVICE: u64 = 1;
+
+/// Attribute 0: normal memory, write-back, read and write allocate. Attribute 1: Device-nGnRE.
+const MAIR_VALUE: u64 = 0xFF | (0x04 << 8);
+
+/// TCR_EL1: 39 bit input addresses through TTBR0 (T0SZ = 25, walks start at level 1), walks
+/// cacheable and inner shareable, 4KB granule, TTBR1 walks disabled, 40 bit physical addresses.
+const TCR_VALUE: u64 = 25 | (0b01 << 8) | (0b01 << 10) | (0b11 << 12) | (1 << 23) | (0b010 << 32);
+
+//SCTLR_EL1 bits
+const SCTLR_MMU: u64 = 1 << 0;
+const SCTLR_DATA_CACHE: u64 = 1 << 2;
+const SCTLR_INSTRUCTION_CACHE: u64 = 1 << 12;
+
+const NORMAL_MEMORY: u64 = DESCRIPTOR_VALID | DESCRIPTOR_ACCESS_FLAG | DESCRIPTOR_INNER_SHAREABLE | DESCRIPTOR_NOT_GLOBAL | (ATTR_INDEX_NORMAL << 2);
+const DEVICE_MEMORY: u64 = DESCRIPTOR_VALID | DESCRIPTOR_ACCESS_FLAG | DESCRIPTOR_NOT_GLOBAL | DESCRIPTOR_PXN | DESCRIPTOR_UXN | (ATTR_INDEX_DEVICE << 2);
+
+/// The kernel's code and constants. EL0 tasks run kernel code, so they can read and execute these, but nobody can write them.
+const SHARED_CODE: u64 = NORMAL_MEMORY | DESCRIPTOR_PAGE | DESCRIPTOR_EL0_ACCESS | DESCRIPTOR_READ_ONLY;
+
+
+#[repr(C, align(4096))]
+pub(super) struct Table(pub(super) [u64; ENTRIES_PER_TABLE]);
+
+/// Every translation table. They never move, and are only changed with the lock held.
+struct TranslationTables{
+    level_1: Table,
+    /// Maps the first gigabyte of DRAM in 2MB blocks, or through level_3 where the kernel is
+    level_2: Table,
+    level_3: [Table; LEVEL_3_TABLE_COUNT]
+}
+
+struct TablesCell(UnsafeCell<TranslationTables>);
+
+// Changed only with TABLE_LOCK held, or before the MMU is enabled.
+unsafe impl Sync for TablesCell{}
+
+static TABLES: TablesCell = TablesCell(UnsafeCell::new(TranslationTables{
+    level_1: Table([0; ENTRIES_PER_TABLE]),
+    level_2: Table([0; ENTRIES_PER_TABLE]),
+    level_3: [const { Table([0; ENTRIES_PER_TABLE]) }; LEVEL_3_TABLE_COUNT]
+}));
+
+/// Held while changing any translation table, the kernel's or a task's.
+pub(super) static TABLE_LOCK: SpinLock<()> = SpinLock::new(());
+
+static MMU_ENABLED: AtomicBool = AtomicBool::new(false);
+
+extern "C" {
+    static __text_start: u8;
+    static __rodata_end: u8;
+    static _end: u8;
+}
+
+/// True once init has turned on translation.
+pub fn is_enabled() -> bool{
+    MMU_ENABLED.load(Ordering::Acquire)
+}
+
+/// The end of the kernel image, including the BSS and the task stacks.
+fn kernel_end() -> usize{
+    unsafe{ &_end as *const u8 as usize }
+}
+
+/// The kernel's code and read only data. The linker page aligns both ends.
+pub(super) fn shared_code() -> core::ops::Range<usize>{
+    unsafe{ &__text_start as *const u8 as usize..&__rodata_end as *const u8 as usize }
+}
+
+/// True if a table is one of the kernel's, rather than one a task's address space owns.
+pub(super) fn is_kernel_table(address: usize) -> bool{
+    let start = TABLES.0.get() as usize;
+    (start..start + core::mem::size_of::<TranslationTables>()).contains(&address)
+}
+
+/// The kernel's level 1 table. Its translation table base has ASID 0.
+pub(super) fn kernel_level_1() -> usize{
+    let tables = TABLES.0.get() as *const TranslationTables;
+    unsafe{ core::ptr::addr_of!((*tables).level_1) as usize }
+}
+
+/// The translation table base for tasks without an address space of their own.
+pub fn kernel_ttbr() -> u64{
+    kernel_level_1() as u64
+}
+
+/// Switch the calling core to another set of translation tables. The kernel is mapped the same
+/// in every set, so this is safe anywhere in the kernel.
+pub fn activate(ttbr: u64){
+    let current: u64;
+    unsafe{
+        asm!("mrs {}, ttbr0_el1", out(reg) current, options(nomem, nostack));
+    }
+
+    if current == ttbr{
+        return;
+    }
+
+    unsafe{
+        asm!("msr ttbr0_el1, {}", "isb", in(reg) ttbr, options(nostack));
+    }
+}
+
+/// Build identity-mapped translation tables and turn on the MMU and caches.
+/// DRAM is normal cacheable memory, and the peripheral window is device memory. The kernel image
+/// is mapped with 4KB pages, so single pages can be unmapped to act as stack guards. Its code and
+/// constants are read only, and the only memory EL0 can reach. Tasks reach more through an AddressSpace.
+pub fn init() -> Result<(), &'static str>{
+    if is_enabled(){
+        return Err("The MMU is already enabled.");
+    }
+
+    let paged_blocks = kernel_end().div_ceil(LEVEL_2_BLOCK_SIZE);
+
+    if paged_blocks > LEVEL_3_TABLE_COUNT{
+        return Err("The kernel image is too large to map with pages.");
+    }
+
+    let tables = unsafe{ &mut *TABLES.0.get() };
+
+    for (index, entry) in tables.level_1.0.iter_mut().enumerate(){
+        let address = index * LEVEL_1_BLOCK_SIZE;
+
+        *entry = if (PERIPHERAL_START..PERIPHERAL_END).contains(&address){
+            address as u64 | DEVICE_MEMORY
+        } else{
+            0
+        };
+    }
+
+    tables.level_1.0[0] = &tables.level_2 as *const Table as u64 | DESCRIPTOR_VALID | DESCRIPTOR_TABLE;
+
+    for (index, entry) in tables.level_2.0.iter_mut().enumerate().take(DRAM_MAPPED_SIZE / LEVEL_2_BLOCK_SIZE){
+        *entry = (index * LEVEL_2_BLOCK_SIZE) as u64 | NORMAL_MEMORY;
+    }
+
+    let shared_code = shared_code();
+
+    for block in 0..paged_blocks{
+        let table = &mut tables.level_3[block];
+
+        for (index, entry) in table.0.iter_mut().enumerate(){
+            let address = block * LEVEL_2_BLOCK_SIZE + index * PAGE_SIZE;
+
+            *entry = if shared_code.contains(&address){
+                address as u64 | SHARED_CODE
+            } else{
+                address as u64 | NORMAL_MEMORY | DESCRIPTOR_PAGE
+            };
+        }
+
+        tables.level_2.0[block] = table as *const Table as u64 | DESCRIPTOR_VALID | DESCRIPTOR_TABLE;
+    }
+
+    enable_translation(&tables.level_1 as *const Table as u64);
+
+    MMU_ENABLED.store(true, Ordering::Release);
+
+    Ok(())
+}
+
+/// Program the calling core's translation registers and turn on the MMU and caches.
+fn enable_translation(level_1: u64){
+    unsafe{
+        asm!(
+            "dsb ish",
+            "tlbi vmalle1",
+            "dsb ish",
+            "msr mair_el1, {mair}",
+            "msr tcr_el1, {tcr}",
+            "msr ttbr0_el1, {ttbr}",
+            "isb",
+            "ic iallu",
+            "mrs {sctlr}, sctlr_el1",
+            "orr {sctlr}, {sctlr}, {enable}",
+            "msr sctlr_el1, {sctlr}",
+            "isb",
+            mair = in(reg) MAIR_VALUE,
+            tcr = in(reg) TCR_VALUE,
+            ttbr = in(reg) level_1,
+            sctlr = out(reg) _,
+            enable = in(reg) SCTLR_MMU | SCTLR_DATA_CACHE | SCTLR_INSTRUCTION_CACHE,
+            options(nostack)
+        );
+    }
+}
+
+/// Turn on the MMU on a secondary core, with the tables the boot core built in init, which
+/// must already have run. Until this returns, the core's data accesses bypass the caches, so
+/// it must only touch memory the boot core has cleaned. That includes MMU_ENABLED, so it isn't checked.
+pub fn init_secondary(){
+    //Only the address is needed. The walker reads the tables coherently once translation is on.
+    enable_translation(kernel_ttbr());
+}
+
+/// The level 3 entry that maps a page of the kernel image.
+fn page_entry(tables: &mut TranslationTables, address: usize) -> Result<&mut u64, &'static str>{
+    if !address.is_multiple_of(PAGE_SIZE){
+        return Err("The address isn't page aligned.");
+    }
+
+    let block = address / LEVEL_2_BLOCK_SIZE;
+
+    if block >= LEVEL_3_TABLE_COUNT || tables.level_2.0[block] & DESCRIPTOR_TABLE == 0{
+        return Err("Only pages within the kernel image can be remapped.");
+    }
+
+    Ok(&mut tables.level_3[block].0[(address % LEVEL_2_BLOCK_SIZE) / PAGE_SIZE])
+}
+
+fn invalidate_page(address: usize){
+    unsafe{
+        asm!(
+            "dsb ishst",
+            "tlbi vaae1is, {}",
+            "dsb ish",
+            "isb",
+            in(reg) address >> 12,
+            options(nostack)
+        );
+    }
+}
+
+/// Unmap one page of the kernel image, so any access to it faults. Used for stack guard pages.
+pub fn unmap_page(address: usize) -> Result<(), &'static str>{
+    if !is_enabled(){
+        return Err("Pages can't be unmapped before the MMU is enabled.");
+    }
+
+    let _lock = TABLE_LOCK.lock();
+    let tables = unsafe{ &mut *TABLES.0.get() };
+    let entry = page_entry(tables, address)?;
+
+    *entry = 0;
+    address_space::update_kernel_page(address, 0);
+    invalidate_page(address);
+
+    Ok(())
+}
+
+/// Map a page of the kernel image back in as normal memory.
+pub fn map_page(address: usize) -> Result<(), &'static str>{
+    if !is_enabled(){
+        return Err("Pages can't be mapped before the MMU is enabled.");
+    }
+
+    let _lock = TABLE_LOCK.lock();
+    let tables = unsafe{ &mut *TABLES.0.get() };
+    let entry = page_entry(tables, address)?;
+
+    *entry = address as u64 | NORMAL_MEMORY | DESCRIPTOR_PAGE;
+    address_space::update_kernel_page(address, *entry);
+    invalidate_page(address);
+
+    Ok(())
+}
+
+/// True if EL0 could read the address, or write it if write is set, without faulting.
+/// Interrupts are off across the lookup, since an interrupt between the AT and the read
+/// could translate something itself and overwrite PAR_EL1.
+pub fn is_user_accessible(address: usize, write: bool) -> bool{
+    let par: u64 = exception::without_interrupts(|| {
+        let par: u64;
+        unsafe{
+            if write{
+                asm!("at s1e0w, {}", "isb", "mrs {}, par_el1", in(reg) address, out(reg) par, options(nostack));
+            } else{
+                asm!("at s1e0r, {}", "isb", "mrs {}, par_el1", in(reg) address, out(reg) par, options(nostack));
+            }
+        }
+        par
+    });
+
+    par & 1 == 0
+}
+
+/// True if a write to the address would translate without faulting. Interrupts are off
+/// across the lookup for the same reason as is_user_accessible.
+pub fn is_mapped(address: usize) -> bool{
+    let par: u64 = exception::without_interrupts(|| {
+        let par: u64;
+        unsafe{
+            asm!("at s1e1w, {}", "isb", "mrs {}, par_el1", in(reg) address, out(reg) par, options(nostack));
+        }
+        par
+    });
+
+    //PAR_EL1.F is set when the translation faulted.
+    par & 1 == 0
+}
@@ -0,0 +1,4 @@
+pub mod cache;
+pub mod mmu;
+pub mod frame_allocator;
+pub mod address_space;
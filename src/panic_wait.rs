@@ -1,7 +1,40 @@
+use core::fmt::Write;
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
+use crate::bsp::raspberry_pi_5::uart;
+
+const NO_CONSOLE: usize = usize::MAX;
+
+/// The UART panics are reported on, once main has set one up.
+static PANIC_CONSOLE: AtomicUsize = AtomicUsize::new(NO_CONSOLE);
+
+/// Report panics on this UART. It must already be set up.
+pub fn set_console(uart_index: usize){
+    PANIC_CONSOLE.store(uart_index, Ordering::Release);
+}
+
+/// Formats straight to the UART's FIFO, since whoever holds the console may never run again.
+struct PanicWriter{
+    uart_index: usize
+}
+
+impl Write for PanicWriter{
+    fn write_str(&mut self, text: &str) -> core::fmt::Result{
+        uart::emergency_write(self.uart_index, text.as_bytes()).map_err(|_| core::fmt::Error)
+    }
+}
 
 #[panic_handler]
-fn panic(_i: &PanicInfo) -> !{
+fn panic(info: &PanicInfo) -> !{
+    //Nothing else should run on a kernel that has panicked, on this core or any other.
+    crate::exception::local_irq_disable();
+    crate::kernel::ipi::stop_other_cpus();
+
+    let uart_index = PANIC_CONSOLE.load(Ordering::Acquire);
+    if uart_index != NO_CONSOLE{
+        let _ = write!(PanicWriter{ uart_index }, "\r\nKernel panic: {}\r\n", info);
+    }
+
     loop{};
 }
\ No newline at end of file
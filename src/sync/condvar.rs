@@ -0,0 +1,64 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::time::Duration;
+
+use crate::kernel::wait_queue::WaitQueue;
+
+use super::mutex::MutexGuard;
+
+/// A condition variable, used with a Mutex to wait for the data it protects to change.
+/// Like any condition variable, a wait can return without a notify, so wait in a loop that re-checks the condition.
+/// notify never blocks, and is safe to call from an interrupt handler.
+pub struct Condvar{
+    /// Bumped on every notify, so a waiter can tell it has been notified since it started waiting.
+    sequence: AtomicU32,
+    waiters: WaitQueue
+}
+
+impl Condvar{
+    pub const fn new() -> Condvar{
+        Condvar{
+            sequence: AtomicU32::new(0),
+            waiters: WaitQueue::new()
+        }
+    }
+
+    /// Release the lock, block until notified, then take the lock again.
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T>{
+        let (guard, _) = self.wait_timeout_inner(guard, None);
+        guard
+    }
+
+    /// As wait, but give up after the timeout. The bool is true if the wait timed out.
+    pub fn wait_timeout<'a, T>(&self, guard: MutexGuard<'a, T>, timeout: Duration) -> (MutexGuard<'a, T>, bool){
+        self.wait_timeout_inner(guard, Some(timeout))
+    }
+
+    fn wait_timeout_inner<'a, T>(&self, guard: MutexGuard<'a, T>, timeout: Option<Duration>) -> (MutexGuard<'a, T>, bool){
+        let mutex = guard.mutex();
+
+        //Read the sequence before unlocking. A notify between the unlock and the wait still changes it.
+        let sequence = self.sequence.load(Ordering::Acquire);
+        drop(guard);
+
+        let notified = self.waiters.wait_until_timeout(|| self.sequence.load(Ordering::Acquire) != sequence, timeout);
+
+        (mutex.lock(), !notified)
+    }
+
+    /// Wake the most urgent waiter.
+    pub fn notify_one(&self){
+        self.sequence.fetch_add(1, Ordering::Release);
+        self.waiters.notify_one();
+    }
+
+    pub fn notify_all(&self){
+        self.sequence.fetch_add(1, Ordering::Release);
+        self.waiters.notify_all();
+    }
+}
+
+impl Default for Condvar{
+    fn default() -> Condvar{
+        Condvar::new()
+    }
+}
@@ -0,0 +1,88 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::time::Duration;
+
+use crate::kernel::wait_queue::WaitQueue;
+
+/// Whether a wait on an EventFlags group is satisfied by any of the requested flags, or needs all of them.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum WaitMode{
+    Any,
+    All
+}
+
+/// A group of 32 event flags. Tasks wait for a combination of flags to be set, and set
+/// wakes every waiter so each can check its own combination. Setting and clearing never
+/// block, so both are safe from an interrupt handler.
+pub struct EventFlags{
+    flags: AtomicU32,
+    waiters: WaitQueue
+}
+
+impl EventFlags{
+    pub const fn new() -> EventFlags{
+        EventFlags{
+            flags: AtomicU32::new(0),
+            waiters: WaitQueue::new()
+        }
+    }
+
+    /// Set flags, waking any task waiting on them. Returns the flags as they were before.
+    pub fn set(&self, flags: u32) -> u32{
+        let previous = self.flags.fetch_or(flags, Ordering::Release);
+        self.waiters.notify_all();
+        previous
+    }
+
+    /// Clear flags. Returns the flags as they were before.
+    pub fn clear(&self, flags: u32) -> u32{
+        self.flags.fetch_and(!flags, Ordering::Release)
+    }
+
+    pub fn get(&self) -> u32{
+        self.flags.load(Ordering::Acquire)
+    }
+
+    /// Check the flags without blocking. On success, returns the flags that were set, and
+    /// clears the requested ones if clear_on_exit is set.
+    pub fn try_wait(&self, mask: u32, mode: WaitMode, clear_on_exit: bool) -> Option<u32>{
+        let satisfied = |flags: u32| match mode{
+            WaitMode::Any => flags & mask != 0,
+            WaitMode::All => flags & mask == mask,
+        };
+
+        //The check and the clear must be one atomic step, or two waiters could both consume the same flags.
+        let result = self.flags.fetch_update(Ordering::Acquire, Ordering::Relaxed, |flags| {
+            if !satisfied(flags){
+                None
+            } else if clear_on_exit{
+                Some(flags & !mask)
+            } else{
+                Some(flags)
+            }
+        });
+
+        result.ok()
+    }
+
+    /// Block until the flags in mask are set, for at most the timeout if one is given.
+    /// Returns the flags as they were when the wait was satisfied.
+    pub fn wait(&self, mask: u32, mode: WaitMode, clear_on_exit: bool, timeout: Option<Duration>) -> Result<u32, &'static str>{
+        let mut result = None;
+
+        self.waiters.wait_until_timeout(|| {
+            result = self.try_wait(mask, mode, clear_on_exit);
+            result.is_some()
+        }, timeout);
+
+        match result{
+            Some(flags) => Ok(flags),
+            None => Err("Timed out waiting for the event flags."),
+        }
+    }
+}
+
+impl Default for EventFlags{
+    fn default() -> EventFlags{
+        EventFlags::new()
+    }
+}
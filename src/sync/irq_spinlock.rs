@@ -0,0 +1,76 @@
+use core::ops::{Deref, DerefMut};
+
+use crate::exception;
+
+use super::spinlock::{SpinLock, SpinLockGuard};
+
+/// A SpinLock that masks IRQs on the current core while it is held.
+/// Use this for data shared with interrupt handlers: without it, an interrupt taken while
+/// the lock is held would spin forever waiting for the code it interrupted.
+pub struct IrqSpinLock<T>{
+    lock: SpinLock<T>
+}
+
+/// Exclusive access to the data behind an IrqSpinLock. Dropping it releases the lock, then restores the IRQ mask.
+pub struct IrqSpinLockGuard<'a, T>{
+    guard: Option<SpinLockGuard<'a, T>>,
+    daif: usize
+}
+
+impl<T> IrqSpinLock<T>{
+    pub const fn new(data: T) -> IrqSpinLock<T>{
+        IrqSpinLock{
+            lock: SpinLock::new(data)
+        }
+    }
+
+    pub fn lock(&self) -> IrqSpinLockGuard<'_, T>{
+        let daif = exception::local_irq_save();
+
+        IrqSpinLockGuard{
+            guard: Some(self.lock.lock()),
+            daif
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<IrqSpinLockGuard<'_, T>>{
+        let daif = exception::local_irq_save();
+
+        match self.lock.try_lock(){
+            Some(guard) => Some(IrqSpinLockGuard{
+                guard: Some(guard),
+                daif
+            }),
+            None => {
+                exception::local_irq_restore(daif);
+                None
+            }
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T{
+        self.lock.get_mut()
+    }
+}
+
+impl<T> Deref for IrqSpinLockGuard<'_, T>{
+    type Target = T;
+
+    fn deref(&self) -> &T{
+        self.guard.as_ref().expect("IrqSpinLockGuard used after release")
+    }
+}
+
+impl<T> DerefMut for IrqSpinLockGuard<'_, T>{
+    fn deref_mut(&mut self) -> &mut T{
+        self.guard.as_mut().expect("IrqSpinLockGuard used after release")
+    }
+}
+
+impl<T> Drop for IrqSpinLockGuard<'_, T>{
+    fn drop(&mut self){
+        //Release the lock before unmasking, or an interrupt could arrive and spin on it.
+        drop(self.guard.take());
+        exception::local_irq_restore(self.daif);
+    }
+}
@@ -0,0 +1,19 @@
+pub mod spinlock;
+pub mod irq_spinlock;
+pub mod rwlock;
+pub mod once;
+pub mod semaphore;
+pub mod event_flags;
+pub mod mutex;
+pub mod condvar;
+pub mod queue;
+
+pub use spinlock::{SpinLock, SpinLockGuard};
+pub use irq_spinlock::{IrqSpinLock, IrqSpinLockGuard};
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use once::{Once, Lazy};
+pub use semaphore::Semaphore;
+pub use event_flags::{EventFlags, WaitMode};
+pub use mutex::{Mutex, MutexGuard};
+pub use condvar::Condvar;
+pub use queue::{MessageQueue, Mailbox};
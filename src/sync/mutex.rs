@@ -0,0 +1,127 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::kernel::scheduler;
+use crate::kernel::task::MAX_TASKS;
+use crate::kernel::wait_queue::WaitQueue;
+
+const UNLOCKED: usize = usize::MAX;
+
+/// The owner recorded for a lock taken before the scheduler starts.
+const BOOT_OWNER: usize = MAX_TASKS;
+
+/// A blocking mutex with priority inheritance. While a task waits for the lock, the owner
+/// runs at the waiter's priority if that is higher, so a middle priority task can't hold up
+/// the waiter indefinitely by preempting the owner. If the owner is itself waiting for another
+/// mutex, that one's owner is raised too. Must not be used from interrupt handlers.
+pub struct Mutex<T>{
+    owner: AtomicUsize,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>
+}
+
+// Access to the data is serialized by the lock.
+unsafe impl<T: Send> Sync for Mutex<T>{}
+unsafe impl<T: Send> Send for Mutex<T>{}
+
+/// Exclusive access to the data behind a Mutex. The lock is released when this is dropped.
+pub struct MutexGuard<'a, T>{
+    mutex: &'a Mutex<T>
+}
+
+impl<T> Mutex<T>{
+    pub const fn new(data: T) -> Mutex<T>{
+        Mutex{
+            owner: AtomicUsize::new(UNLOCKED),
+            waiters: WaitQueue::new(),
+            data: UnsafeCell::new(data)
+        }
+    }
+
+    fn caller() -> usize{
+        scheduler::current().unwrap_or(BOOT_OWNER)
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>>{
+        match self.owner.compare_exchange(UNLOCKED, Self::caller(), Ordering::Acquire, Ordering::Relaxed){
+            Ok(_) => {
+                scheduler::mutex_acquired();
+
+                Some(MutexGuard{
+                    mutex: self
+                })
+            },
+            Err(_) => None,
+        }
+    }
+
+    /// Block until the lock is ours.
+    pub fn lock(&self) -> MutexGuard<'_, T>{
+        if let Some(guard) = self.try_lock(){
+            return guard;
+        }
+
+        let caller = Self::caller();
+        if caller == BOOT_OWNER{
+            panic!("A Mutex can't block before the scheduler has started");
+        }
+
+        self.waiters.wait_until(|| {
+            match self.owner.compare_exchange(UNLOCKED, caller, Ordering::Acquire, Ordering::Relaxed){
+                Ok(_) => true,
+                Err(owner) => {
+                    //Lend our priority to whoever holds the lock, so it gets out of our way.
+                    scheduler::inherit_priority(caller, owner);
+                    false
+                },
+            }
+        });
+
+        scheduler::mutex_acquired();
+
+        MutexGuard{
+            mutex: self
+        }
+    }
+
+    pub fn is_locked(&self) -> bool{
+        self.owner.load(Ordering::Relaxed) != UNLOCKED
+    }
+
+    pub fn get_mut(&mut self) -> &mut T{
+        self.data.get_mut()
+    }
+}
+
+impl<'a, T> MutexGuard<'a, T>{
+    /// The mutex this guard locks. Condvar uses it to re-lock after waiting.
+    pub(super) fn mutex(&self) -> &'a Mutex<T>{
+        self.mutex
+    }
+}
+
+impl<T> Deref for MutexGuard<'_, T>{
+    type Target = T;
+
+    fn deref(&self) -> &T{
+        unsafe{ &*self.mutex.data.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T>{
+    fn deref_mut(&mut self) -> &mut T{
+        unsafe{ &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T>{
+    fn drop(&mut self){
+        self.mutex.owner.store(UNLOCKED, Ordering::Release);
+
+        //Drop any priority we inherited before handing over, so the waiter can preempt us.
+        scheduler::mutex_released();
+        self.mutex.waiters.notify_one();
+        scheduler::preempt_if_needed();
+    }
+}
@@ -0,0 +1,115 @@
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::ops::Deref;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+const INCOMPLETE: u8 = 0;
+const RUNNING: u8 = 1;
+const COMPLETE: u8 = 2;
+
+/// A value that is initialized exactly once, by whichever caller gets there first.
+/// Other callers spin until the initializer has finished.
+pub struct Once<T>{
+    state: AtomicU8,
+    value: UnsafeCell<MaybeUninit<T>>
+}
+
+unsafe impl<T: Send + Sync> Sync for Once<T>{}
+unsafe impl<T: Send> Send for Once<T>{}
+
+impl<T> Once<T>{
+    pub const fn new() -> Once<T>{
+        Once{
+            state: AtomicU8::new(INCOMPLETE),
+            value: UnsafeCell::new(MaybeUninit::uninit())
+        }
+    }
+
+    /// Run the initializer if nobody has yet, and return the value.
+    pub fn call_once<F>(&self, initializer: F) -> &T where F: FnOnce() -> T{
+        if self.state.compare_exchange(INCOMPLETE, RUNNING, Ordering::Acquire, Ordering::Acquire).is_ok(){
+            unsafe{
+                (*self.value.get()).write(initializer());
+            }
+
+            self.state.store(COMPLETE, Ordering::Release);
+        }
+
+        while self.state.load(Ordering::Acquire) != COMPLETE{
+            core::hint::spin_loop();
+        }
+
+        unsafe{ (*self.value.get()).assume_init_ref() }
+    }
+
+    /// The value, if it has been initialized.
+    pub fn get(&self) -> Option<&T>{
+        if self.state.load(Ordering::Acquire) == COMPLETE{
+            Some(unsafe{ (*self.value.get()).assume_init_ref() })
+        } else{
+            None
+        }
+    }
+
+    pub fn is_completed(&self) -> bool{
+        self.state.load(Ordering::Acquire) == COMPLETE
+    }
+}
+
+impl<T> Default for Once<T>{
+    fn default() -> Once<T>{
+        Once::new()
+    }
+}
+
+impl<T> Drop for Once<T>{
+    fn drop(&mut self){
+        if *self.state.get_mut() == COMPLETE{
+            unsafe{
+                self.value.get_mut().assume_init_drop();
+            }
+        }
+    }
+}
+
+/// A static initialized on first use.
+///
+/// ```ignore
+/// static TABLE: Lazy<[u8; 256]> = Lazy::new(build_table);
+/// ```
+pub struct Lazy<T, F = fn() -> T>{
+    once: Once<T>,
+    initializer: UnsafeCell<Option<F>>
+}
+
+// The initializer is only taken by the single caller that wins the Once.
+unsafe impl<T: Send + Sync, F: Send> Sync for Lazy<T, F>{}
+
+impl<T, F> Lazy<T, F> where F: FnOnce() -> T{
+    pub const fn new(initializer: F) -> Lazy<T, F>{
+        Lazy{
+            once: Once::new(),
+            initializer: UnsafeCell::new(Some(initializer))
+        }
+    }
+
+    /// Initialize the value now, if it hasn't been already.
+    pub fn force(this: &Lazy<T, F>) -> &T{
+        this.once.call_once(|| {
+            let initializer = unsafe{ (*this.initializer.get()).take() };
+
+            match initializer{
+                Some(initializer) => initializer(),
+                None => panic!("Lazy initializer already taken"),
+            }
+        })
+    }
+}
+
+impl<T, F> Deref for Lazy<T, F> where F: FnOnce() -> T{
+    type Target = T;
+
+    fn deref(&self) -> &T{
+        Lazy::force(self)
+    }
+}
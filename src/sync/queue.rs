@@ -0,0 +1,321 @@
+use core::mem::MaybeUninit;
+use core::time::Duration;
+
+use crate::kernel::wait_queue::WaitQueue;
+
+use super::irq_spinlock::IrqSpinLock;
+
+/// The ring of messages behind a MessageQueue.
+struct Ring<T, const DEPTH: usize>{
+    messages: [MaybeUninit<T>; DEPTH],
+    /// The index of the oldest message
+    head: usize,
+    len: usize,
+    /// The most messages the queue has ever held at once
+    high_water_mark: usize
+}
+
+impl<T, const DEPTH: usize> Ring<T, DEPTH>{
+    const fn new() -> Ring<T, DEPTH>{
+        Ring{
+            messages: [const { MaybeUninit::uninit() }; DEPTH],
+            head: 0,
+            len: 0,
+            high_water_mark: 0
+        }
+    }
+
+    fn push_back(&mut self, message: T) -> Result<(), T>{
+        if self.len == DEPTH{
+            return Err(message);
+        }
+
+        self.messages[(self.head + self.len) % DEPTH].write(message);
+        self.record_push();
+
+        Ok(())
+    }
+
+    fn push_front(&mut self, message: T) -> Result<(), T>{
+        if self.len == DEPTH{
+            return Err(message);
+        }
+
+        self.head = (self.head + DEPTH - 1) % DEPTH;
+        self.messages[self.head].write(message);
+        self.record_push();
+
+        Ok(())
+    }
+
+    fn record_push(&mut self){
+        self.len += 1;
+
+        if self.len > self.high_water_mark{
+            self.high_water_mark = self.len;
+        }
+    }
+
+    fn pop_front(&mut self) -> Option<T>{
+        if self.len == 0{
+            return None;
+        }
+
+        let message = unsafe{ self.messages[self.head].assume_init_read() };
+        self.head = (self.head + 1) % DEPTH;
+        self.len -= 1;
+
+        Some(message)
+    }
+}
+
+impl<T, const DEPTH: usize> Drop for Ring<T, DEPTH>{
+    fn drop(&mut self){
+        while self.pop_front().is_some(){}
+    }
+}
+
+/// A bounded queue of messages, copied in and out by value, with no heap behind it.
+/// Declare one as a static. Senders block while it is full and receivers while it is empty.
+/// The try_ variants never block, which makes them safe to call from interrupt handlers.
+/// A message that can't be sent is handed back in the Err.
+pub struct MessageQueue<T, const DEPTH: usize>{
+    ring: IrqSpinLock<Ring<T, DEPTH>>,
+    not_empty: WaitQueue,
+    not_full: WaitQueue
+}
+
+impl<T, const DEPTH: usize> MessageQueue<T, DEPTH>{
+    pub const fn new() -> MessageQueue<T, DEPTH>{
+        assert!(DEPTH > 0, "A MessageQueue must hold at least one message");
+
+        MessageQueue{
+            ring: IrqSpinLock::new(Ring::new()),
+            not_empty: WaitQueue::new(),
+            not_full: WaitQueue::new()
+        }
+    }
+
+    fn try_push(&self, message: T, to_front: bool) -> Result<(), T>{
+        let result = if to_front{
+            self.ring.lock().push_front(message)
+        } else{
+            self.ring.lock().push_back(message)
+        };
+
+        if result.is_ok(){
+            self.not_empty.notify_one();
+        }
+
+        result
+    }
+
+    fn push_timeout(&self, message: T, to_front: bool, timeout: Option<Duration>) -> Result<(), T>{
+        let mut pending = Some(message);
+
+        self.not_full.wait_until_timeout(|| {
+            match pending.take(){
+                Some(message) => match self.try_push(message, to_front){
+                    Ok(_) => true,
+                    Err(message) => {
+                        pending = Some(message);
+                        false
+                    },
+                },
+                None => true,
+            }
+        }, timeout);
+
+        match pending{
+            Some(message) => Err(message),
+            None => Ok(()),
+        }
+    }
+
+    /// Add a message to the back of the queue without blocking. Safe to call from an interrupt handler.
+    pub fn try_send(&self, message: T) -> Result<(), T>{
+        self.try_push(message, false)
+    }
+
+    /// Add a message to the back of the queue, blocking until there is room.
+    pub fn send(&self, message: T){
+        //Without a timeout, the wait only ends once the message is in.
+        let _ = self.push_timeout(message, false, None);
+    }
+
+    /// Add a message to the back of the queue, blocking for at most the timeout.
+    pub fn send_timeout(&self, message: T, timeout: Duration) -> Result<(), T>{
+        self.push_timeout(message, false, Some(timeout))
+    }
+
+    /// Add an urgent message to the front of the queue, so it is received next, without blocking.
+    /// Safe to call from an interrupt handler.
+    pub fn try_send_to_front(&self, message: T) -> Result<(), T>{
+        self.try_push(message, true)
+    }
+
+    /// Add an urgent message to the front of the queue, blocking until there is room.
+    pub fn send_to_front(&self, message: T){
+        let _ = self.push_timeout(message, true, None);
+    }
+
+    /// Add an urgent message to the front of the queue, blocking for at most the timeout.
+    pub fn send_to_front_timeout(&self, message: T, timeout: Duration) -> Result<(), T>{
+        self.push_timeout(message, true, Some(timeout))
+    }
+
+    /// Take the oldest message without blocking. Safe to call from an interrupt handler.
+    pub fn try_receive(&self) -> Option<T>{
+        let message = self.ring.lock().pop_front();
+
+        if message.is_some(){
+            self.not_full.notify_one();
+        }
+
+        message
+    }
+
+    fn receive_deadline(&self, timeout: Option<Duration>) -> Option<T>{
+        let mut received = None;
+
+        self.not_empty.wait_until_timeout(|| {
+            received = self.try_receive();
+            received.is_some()
+        }, timeout);
+
+        received
+    }
+
+    /// Take the oldest message, blocking until there is one.
+    pub fn receive(&self) -> T{
+        loop{
+            if let Some(message) = self.receive_deadline(None){
+                return message;
+            }
+        }
+    }
+
+    /// Take the oldest message, blocking for at most the timeout.
+    pub fn receive_timeout(&self, timeout: Duration) -> Result<T, &'static str>{
+        match self.receive_deadline(Some(timeout)){
+            Some(message) => Ok(message),
+            None => Err("Timed out waiting for a message."),
+        }
+    }
+
+    /// The number of messages waiting.
+    pub fn len(&self) -> usize{
+        self.ring.lock().len
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.len() == 0
+    }
+
+    pub fn is_full(&self) -> bool{
+        self.len() == DEPTH
+    }
+
+    pub fn capacity(&self) -> usize{
+        DEPTH
+    }
+
+    /// The most messages the queue has held at once since it was created or the mark was last reset.
+    pub fn high_water_mark(&self) -> usize{
+        self.ring.lock().high_water_mark
+    }
+
+    pub fn reset_high_water_mark(&self){
+        let mut ring = self.ring.lock();
+        ring.high_water_mark = ring.len;
+    }
+}
+
+impl<T, const DEPTH: usize> Default for MessageQueue<T, DEPTH>{
+    fn default() -> MessageQueue<T, DEPTH>{
+        MessageQueue::new()
+    }
+}
+
+/// A single slot holding the latest message. post replaces whatever is there, which suits
+/// values where only the newest matters, like a sensor reading. post never blocks, so it is
+/// safe to call from an interrupt handler.
+pub struct Mailbox<T>{
+    slot: IrqSpinLock<Option<T>>,
+    waiters: WaitQueue
+}
+
+impl<T> Mailbox<T>{
+    pub const fn new() -> Mailbox<T>{
+        Mailbox{
+            slot: IrqSpinLock::new(None),
+            waiters: WaitQueue::new()
+        }
+    }
+
+    /// Put a message in the mailbox, replacing and returning any that hadn't been received.
+    pub fn post(&self, message: T) -> Option<T>{
+        let previous = self.slot.lock().replace(message);
+        self.waiters.notify_one();
+        previous
+    }
+
+    /// Put a message in the mailbox only if it is empty. Hands the message back otherwise.
+    pub fn try_post(&self, message: T) -> Result<(), T>{
+        let mut slot = self.slot.lock();
+
+        if slot.is_some(){
+            return Err(message);
+        }
+
+        *slot = Some(message);
+        drop(slot);
+
+        self.waiters.notify_one();
+
+        Ok(())
+    }
+
+    /// Take the message without blocking.
+    pub fn try_receive(&self) -> Option<T>{
+        self.slot.lock().take()
+    }
+
+    /// Take the message, blocking until there is one.
+    pub fn receive(&self) -> T{
+        loop{
+            if let Ok(message) = self.receive_inner(None){
+                return message;
+            }
+        }
+    }
+
+    /// Take the message, blocking for at most the timeout.
+    pub fn receive_timeout(&self, timeout: Duration) -> Result<T, &'static str>{
+        self.receive_inner(Some(timeout))
+    }
+
+    fn receive_inner(&self, timeout: Option<Duration>) -> Result<T, &'static str>{
+        let mut received = None;
+
+        self.waiters.wait_until_timeout(|| {
+            received = self.try_receive();
+            received.is_some()
+        }, timeout);
+
+        match received{
+            Some(message) => Ok(message),
+            None => Err("Timed out waiting for a message."),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.slot.lock().is_none()
+    }
+}
+
+impl<T> Default for Mailbox<T>{
+    fn default() -> Mailbox<T>{
+        Mailbox::new()
+    }
+}
@@ -0,0 +1,129 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// Set while a writer holds the lock
+const WRITER: u32 = 1 << 31;
+/// Set while a writer is waiting. New readers hold off, so a stream of readers can't starve writers.
+const WRITER_WAITING: u32 = 1 << 30;
+/// The low bits count the readers holding the lock
+const READERS_MASK: u32 = WRITER_WAITING - 1;
+
+/// A spinning reader-writer lock. Any number of readers, or one writer, can hold it.
+/// Like SpinLock, it leaves interrupts alone and must not be taken by interrupt handlers.
+pub struct RwLock<T>{
+    state: AtomicU32,
+    data: UnsafeCell<T>
+}
+
+unsafe impl<T: Send + Sync> Sync for RwLock<T>{}
+unsafe impl<T: Send> Send for RwLock<T>{}
+
+pub struct RwLockReadGuard<'a, T>{
+    lock: &'a RwLock<T>
+}
+
+pub struct RwLockWriteGuard<'a, T>{
+    lock: &'a RwLock<T>
+}
+
+impl<T> RwLock<T>{
+    pub const fn new(data: T) -> RwLock<T>{
+        RwLock{
+            state: AtomicU32::new(0),
+            data: UnsafeCell::new(data)
+        }
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<'_, T>{
+        loop{
+            if let Some(guard) = self.try_read(){
+                return guard;
+            }
+
+            core::hint::spin_loop();
+        }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>>{
+        let state = self.state.load(Ordering::Relaxed);
+
+        if state & (WRITER | WRITER_WAITING) != 0 || state & READERS_MASK == READERS_MASK{
+            return None;
+        }
+
+        match self.state.compare_exchange_weak(state, state + 1, Ordering::Acquire, Ordering::Relaxed){
+            Ok(_) => Some(RwLockReadGuard{
+                lock: self
+            }),
+            Err(_) => None,
+        }
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<'_, T>{
+        loop{
+            //Announce ourselves so no new readers get in, then wait for the current ones to leave.
+            self.state.fetch_or(WRITER_WAITING, Ordering::Relaxed);
+
+            if let Some(guard) = self.try_write(){
+                return guard;
+            }
+
+            core::hint::spin_loop();
+        }
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>>{
+        let state = self.state.load(Ordering::Relaxed);
+
+        if state & (WRITER | READERS_MASK) != 0{
+            return None;
+        }
+
+        match self.state.compare_exchange_weak(state, WRITER, Ordering::Acquire, Ordering::Relaxed){
+            Ok(_) => Some(RwLockWriteGuard{
+                lock: self
+            }),
+            Err(_) => None,
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T{
+        self.data.get_mut()
+    }
+}
+
+impl<T> Deref for RwLockReadGuard<'_, T>{
+    type Target = T;
+
+    fn deref(&self) -> &T{
+        unsafe{ &*self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for RwLockReadGuard<'_, T>{
+    fn drop(&mut self){
+        self.lock.state.fetch_sub(1, Ordering::Release);
+    }
+}
+
+impl<T> Deref for RwLockWriteGuard<'_, T>{
+    type Target = T;
+
+    fn deref(&self) -> &T{
+        unsafe{ &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for RwLockWriteGuard<'_, T>{
+    fn deref_mut(&mut self) -> &mut T{
+        unsafe{ &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for RwLockWriteGuard<'_, T>{
+    fn drop(&mut self){
+        //Another writer may have flagged itself as waiting while we held the lock. Keep its flag.
+        self.lock.state.fetch_and(!WRITER, Ordering::Release);
+    }
+}
@@ -0,0 +1,63 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
+
+use crate::kernel::wait_queue::WaitQueue;
+
+/// A counting semaphore. take blocks the calling task while the count is zero, and give
+/// wakes the most urgent waiter. give never blocks, so it is safe to call from an interrupt handler.
+pub struct Semaphore{
+    count: AtomicUsize,
+    maximum: usize,
+    waiters: WaitQueue
+}
+
+impl Semaphore{
+    pub const fn new(initial: usize, maximum: usize) -> Semaphore{
+        Semaphore{
+            count: AtomicUsize::new(if initial < maximum { initial } else { maximum }),
+            maximum,
+            waiters: WaitQueue::new()
+        }
+    }
+
+    /// A semaphore that counts to one, for signalling a single event.
+    pub const fn new_binary(available: bool) -> Semaphore{
+        Semaphore::new(available as usize, 1)
+    }
+
+    /// Take one count without blocking. Returns false if the count was zero.
+    pub fn try_take(&self) -> bool{
+        self.count.fetch_update(Ordering::Acquire, Ordering::Relaxed, |count| count.checked_sub(1)).is_ok()
+    }
+
+    /// Take one count, blocking until one is available.
+    pub fn take(&self){
+        self.waiters.wait_until(|| self.try_take());
+    }
+
+    /// Take one count, blocking for at most the timeout.
+    pub fn take_timeout(&self, timeout: Duration) -> Result<(), &'static str>{
+        if self.waiters.wait_until_timeout(|| self.try_take(), Some(timeout)){
+            Ok(())
+        } else{
+            Err("Timed out waiting for the semaphore.")
+        }
+    }
+
+    /// Release one count. Fails, without changing anything, if the semaphore is already at its maximum.
+    /// Safe to call from an interrupt handler.
+    pub fn give(&self) -> Result<(), &'static str>{
+        match self.count.fetch_update(Ordering::Release, Ordering::Relaxed, |count| if count < self.maximum { Some(count + 1) } else { None }){
+            Ok(_) => {},
+            Err(_) => return Err("The semaphore is already at its maximum count."),
+        }
+
+        self.waiters.notify_one();
+
+        Ok(())
+    }
+
+    pub fn count(&self) -> usize{
+        self.count.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,105 @@
+use core::arch::asm;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// A ticket spinlock. Cores are served in the order they asked for the lock, so none can starve.
+/// Waiters sleep in WFE on the now_serving word and are woken when the holder releases it.
+/// Interrupts are left alone, so it must not be taken by an interrupt handler. See IrqSpinLock.
+pub struct SpinLock<T>{
+    next_ticket: AtomicU32,
+    now_serving: AtomicU32,
+    data: UnsafeCell<T>
+}
+
+// Access to the data is serialized by the lock.
+unsafe impl<T: Send> Sync for SpinLock<T>{}
+unsafe impl<T: Send> Send for SpinLock<T>{}
+
+/// Exclusive access to the data behind a SpinLock. The lock is released when this is dropped.
+pub struct SpinLockGuard<'a, T>{
+    lock: &'a SpinLock<T>
+}
+
+impl<T> SpinLock<T>{
+    pub const fn new(data: T) -> SpinLock<T>{
+        SpinLock{
+            next_ticket: AtomicU32::new(0),
+            now_serving: AtomicU32::new(0),
+            data: UnsafeCell::new(data)
+        }
+    }
+
+    /// Wait until now_serving reaches our ticket.
+    fn wait_for_turn(&self, ticket: u32){
+        loop{
+            //The exclusive load arms this core's monitor on now_serving. The releasing store clears it,
+            //which raises an event, so a release between the load and the WFE isn't missed.
+            let serving: u32;
+            unsafe{
+                asm!("ldaxr {0:w}, [{1}]", out(reg) serving, in(reg) self.now_serving.as_ptr(), options(nostack));
+            }
+
+            if serving == ticket{
+                return;
+            }
+
+            unsafe{
+                asm!("wfe", options(nostack));
+            }
+        }
+    }
+
+    /// Spin until the lock is ours.
+    pub fn lock(&self) -> SpinLockGuard<'_, T>{
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        self.wait_for_turn(ticket);
+
+        SpinLockGuard{
+            lock: self
+        }
+    }
+
+    /// Take the lock only if nobody holds it or is waiting for it.
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>>{
+        let serving = self.now_serving.load(Ordering::Relaxed);
+
+        match self.next_ticket.compare_exchange(serving, serving.wrapping_add(1), Ordering::Acquire, Ordering::Relaxed){
+            Ok(_) => Some(SpinLockGuard{
+                lock: self
+            }),
+            Err(_) => None,
+        }
+    }
+
+    /// True if the lock is held. Only useful as a hint, it can change at any time.
+    pub fn is_locked(&self) -> bool{
+        self.next_ticket.load(Ordering::Relaxed) != self.now_serving.load(Ordering::Relaxed)
+    }
+
+    /// Access the data without locking. The &mut proves nobody else can hold the lock.
+    pub fn get_mut(&mut self) -> &mut T{
+        self.data.get_mut()
+    }
+}
+
+impl<T> Deref for SpinLockGuard<'_, T>{
+    type Target = T;
+
+    fn deref(&self) -> &T{
+        unsafe{ &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T>{
+    fn deref_mut(&mut self) -> &mut T{
+        unsafe{ &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T>{
+    fn drop(&mut self){
+        //Only the holder writes now_serving, and the store clears every waiter's exclusive monitor.
+        self.lock.now_serving.fetch_add(1, Ordering::Release);
+    }
+}